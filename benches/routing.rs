@@ -0,0 +1,76 @@
+use {
+    criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main},
+    keyspace::{BatchGrouping, KeyspaceBuilder},
+    std::{collections::HashMap, hint::black_box},
+};
+
+fn routing(c: &mut Criterion) {
+    let init_nodes = (0..64).map(|i| format!("node{}", i)).collect::<Vec<_>>();
+    let keyspace = KeyspaceBuilder::new(init_nodes)
+        .build()
+        .expect("Failed to create keyspace");
+
+    let mut group = c.benchmark_group("route");
+    for batch_size in [100, 1_000, 10_000] {
+        let keys = (0..batch_size)
+            .map(|i| format!("key{}", i))
+            .collect::<Vec<_>>();
+        group.throughput(Throughput::Elements(batch_size as u64));
+
+        // Baseline: route each key separately, and group by primary node.
+        group.bench_with_input(BenchmarkId::new("per_key", batch_size), &keys, |b, keys| {
+            b.iter(|| {
+                let mut groups = HashMap::<_, Vec<usize>>::new();
+                for (idx, key) in keys.iter().enumerate() {
+                    let primary = keyspace.replicas(key).next().unwrap();
+                    groups.entry(primary).or_default().push(idx);
+                }
+                black_box(groups)
+            })
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("per_key_replica_set", batch_size),
+            &keys,
+            |b, keys| {
+                b.iter(|| {
+                    let mut groups = HashMap::<_, Vec<usize>>::new();
+                    for (idx, key) in keys.iter().enumerate() {
+                        let replicas = keyspace.replicas(key).collect::<Vec<_>>();
+                        groups.entry(replicas).or_default().push(idx);
+                    }
+                    black_box(groups)
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("batch_primary", batch_size),
+            &keys,
+            |b, keys| b.iter(|| black_box(keyspace.route_batch(keys, BatchGrouping::Primary))),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("batch_replica_set", batch_size),
+            &keys,
+            |b, keys| b.iter(|| black_box(keyspace.route_batch(keys, BatchGrouping::ReplicaSet))),
+        );
+    }
+    group.finish();
+
+    let key = "key0".to_string();
+    let mut group = c.benchmark_group("single_key");
+    group.bench_function("replicas", |b| {
+        b.iter(|| black_box(keyspace.replicas(black_box(&key)).count()))
+    });
+    group.bench_function("replicas_ref", |b| {
+        b.iter(|| black_box(keyspace.replicas_ref(black_box(&key)).count()))
+    });
+    group.bench_function("primary", |b| {
+        b.iter(|| black_box(keyspace.primary(black_box(&key))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, routing);
+criterion_main!(benches);
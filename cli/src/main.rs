@@ -0,0 +1,345 @@
+//! Command-line tool to inspect keyspace topologies, and plan changes to them.
+//!
+//! Keyspaces are built with the same hasher and placement code as by the
+//! library, so routing and migration plans match the ones computed in
+//! production.
+
+mod output;
+mod topology;
+
+use {
+    clap::{Parser, Subcommand},
+    keyspace::{KeyRange, KeyspaceNode, MigrationPlan},
+    output::{Format, Record},
+    serde::Serialize,
+    std::{collections::BTreeSet, error::Error, path::PathBuf, process::ExitCode},
+    topology::{Node, Topology},
+};
+
+/// Inspect keyspace topologies, route keys, and plan changes to them.
+#[derive(Debug, Parser)]
+#[command(name = "keyspace", version)]
+struct Cli {
+    /// Topology file (TOML, or JSON with `.json` extension).
+    #[arg(short, long, global = true, default_value = "topology.toml")]
+    topology: PathBuf,
+
+    /// Output format.
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    format: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Ownership of the keyspace by the nodes.
+    Stats,
+
+    /// Positions, shards and replicas of the keys.
+    Route {
+        /// Keys to route.
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+
+    /// Migration plan for a change of the topology.
+    Plan {
+        #[command(subcommand)]
+        change: Change,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum Change {
+    /// Add a node, or change the capacity of an existing one.
+    Add {
+        /// Node id.
+        id: String,
+
+        /// Node capacity.
+        #[arg(short, long, default_value_t = 1)]
+        capacity: usize,
+
+        /// Topology labels, from the widest failure domain to the narrowest
+        /// one.
+        #[arg(short, long = "label")]
+        labels: Vec<String>,
+    },
+
+    /// Remove a node.
+    Remove {
+        /// Node id.
+        id: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let topology = Topology::load(&cli.topology)?;
+    match cli.command {
+        Command::Stats => output::print(&stats(&topology)?, cli.format),
+        Command::Route { keys } => output::print(&route(&topology, &keys)?, cli.format),
+        Command::Plan { change } => output::print(&plan(&topology, change)?, cli.format),
+    }
+}
+
+/// Ownership of the keyspace by a node.
+#[derive(Debug, Serialize)]
+struct NodeStats {
+    node: String,
+    capacity: usize,
+    /// Share of the total capacity.
+    capacity_share: f64,
+    /// Number of key ranges replicated by the node.
+    ranges: usize,
+    /// Number of key ranges the node is the primary of.
+    primaries: usize,
+    /// Share of all the replicas, weighted by the size of the key ranges.
+    replica_share: f64,
+    /// Share of the keyspace the node is the primary of.
+    primary_share: f64,
+}
+
+impl Record for NodeStats {
+    const HEADER: &'static [&'static str] = &[
+        "node",
+        "capacity",
+        "capacity_share",
+        "ranges",
+        "primaries",
+        "replica_share",
+        "primary_share",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.node.clone(),
+            self.capacity.to_string(),
+            format!("{:.4}", self.capacity_share),
+            self.ranges.to_string(),
+            self.primaries.to_string(),
+            format!("{:.4}", self.replica_share),
+            format!("{:.4}", self.primary_share),
+        ]
+    }
+}
+
+fn stats(topology: &Topology) -> Result<Vec<NodeStats>, Box<dyn Error>> {
+    let keyspace = topology.keyspace()?;
+    let total_capacity = topology.nodes.iter().map(Node::capacity).sum::<usize>();
+    let mut stats = topology
+        .nodes
+        .iter()
+        .map(|node| NodeStats {
+            node: node.id.clone(),
+            capacity: node.capacity,
+            capacity_share: node.capacity as f64 / total_capacity as f64,
+            ranges: 0,
+            primaries: 0,
+            replica_share: 0.0,
+            primary_share: 0.0,
+        })
+        .collect::<Vec<_>>();
+
+    // Replicas of a key range are listed together, primary first.
+    let mut previous = None;
+    let mut replicas = 0.0;
+    for (key_range, node) in keyspace.iter() {
+        let share = range_share(&key_range);
+        let is_primary = previous != Some(key_range);
+        previous = Some(key_range);
+        replicas += share;
+
+        let Some(stats) = stats.iter_mut().find(|stats| &stats.node == node.id()) else {
+            continue;
+        };
+        stats.ranges += 1;
+        stats.replica_share += share;
+        if is_primary {
+            stats.primaries += 1;
+            stats.primary_share += share;
+        }
+    }
+    for stats in &mut stats {
+        stats.replica_share /= replicas;
+    }
+    Ok(stats)
+}
+
+/// Fraction of the keyspace covered by the key range.
+fn range_share(key_range: &KeyRange) -> f64 {
+    let end = key_range.end().map_or(1u128 << 64, u128::from);
+    (end - u128::from(key_range.start())) as f64 / (1u128 << 64) as f64
+}
+
+/// Routing of a key.
+#[derive(Debug, Serialize)]
+struct Route {
+    key: String,
+    position: u64,
+    shard_start: u64,
+    shard_end: Option<u64>,
+    /// Replicas, primary first.
+    replicas: Vec<String>,
+}
+
+impl Record for Route {
+    const HEADER: &'static [&'static str] =
+        &["key", "position", "shard_start", "shard_end", "replicas"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.key.clone(),
+            self.position.to_string(),
+            self.shard_start.to_string(),
+            end_field(self.shard_end),
+            self.replicas.join(" "),
+        ]
+    }
+}
+
+fn route(topology: &Topology, keys: &[String]) -> Result<Vec<Route>, Box<dyn Error>> {
+    let keyspace = topology.keyspace()?;
+    Ok(keys
+        .iter()
+        .map(|key| {
+            let position = keyspace.position_of_key(key.as_str());
+            let shard = keyspace.shard_of(position);
+            Route {
+                key: key.clone(),
+                position,
+                shard_start: shard.start(),
+                shard_end: shard.end(),
+                replicas: keyspace
+                    .replicas_for_position(position)
+                    .map(|node| node.id().clone())
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+/// Step of a migration plan: a node pulling a key range from the sources, or
+/// cleaning it up.
+#[derive(Debug, PartialEq, Serialize)]
+struct Step {
+    node: String,
+    action: Action,
+    start: u64,
+    end: Option<u64>,
+    /// Nodes to pull the data from (any of them), when pulling.
+    sources: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Action {
+    Pull,
+    Cleanup,
+}
+
+impl Record for Step {
+    const HEADER: &'static [&'static str] = &["node", "action", "start", "end", "sources"];
+
+    fn fields(&self) -> Vec<String> {
+        let action = match self.action {
+            Action::Pull => "pull",
+            Action::Cleanup => "cleanup",
+        };
+        vec![
+            self.node.clone(),
+            action.to_owned(),
+            self.start.to_string(),
+            end_field(self.end),
+            self.sources.join(" "),
+        ]
+    }
+}
+
+fn plan(topology: &Topology, change: Change) -> Result<Vec<Step>, Box<dyn Error>> {
+    let mut keyspace = topology.keyspace()?;
+    let mut node_ids = topology
+        .nodes
+        .iter()
+        .map(|node| node.id.clone())
+        .collect::<BTreeSet<_>>();
+    let plan = match change {
+        Change::Add {
+            id,
+            capacity,
+            labels,
+        } => {
+            node_ids.insert(id.clone());
+            keyspace.add_node(Node {
+                id,
+                capacity,
+                topology: labels,
+            })?
+        }
+        Change::Remove { id } => {
+            if !node_ids.contains(&id) {
+                return Err(format!("Unknown node: {id}").into());
+            }
+            keyspace.remove_node(&id)?
+        }
+    };
+    Ok(node_ids
+        .iter()
+        .flat_map(|node_id| steps(&plan, node_id))
+        .collect())
+}
+
+/// Steps of the plan for the given node, with adjacent key ranges sharing
+/// the action and the sources merged.
+fn steps(plan: &MigrationPlan<Node>, node_id: &String) -> Vec<Step> {
+    let pulls = plan.pull_intervals(node_id).map(|interval| {
+        let mut sources = interval
+            .data_nodes()
+            .map(|node| node.id().clone())
+            .collect::<Vec<_>>();
+        sources.sort();
+        (Action::Pull, *interval.key_range(), sources)
+    });
+    let cleanups = plan
+        .cleanup_intervals(node_id)
+        .map(|key_range| (Action::Cleanup, *key_range, Vec::new()));
+    let mut ranges = pulls.chain(cleanups).collect::<Vec<_>>();
+    ranges.sort_by_key(|(action, key_range, _)| (*action, key_range.start()));
+
+    let mut steps = Vec::<Step>::new();
+    for (action, key_range, sources) in ranges {
+        if let Some(last) = steps.last_mut()
+            && last.action == action
+            && last.end == Some(key_range.start())
+            && last.sources == sources
+        {
+            last.end = key_range.end();
+            continue;
+        }
+        steps.push(Step {
+            node: node_id.clone(),
+            action,
+            start: key_range.start(),
+            end: key_range.end(),
+            sources,
+        });
+    }
+    steps
+}
+
+/// End of a key range, empty if the range extends to the end of the keyspace.
+fn end_field(end: Option<u64>) -> String {
+    end.map(|end| end.to_string()).unwrap_or_default()
+}
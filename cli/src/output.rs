@@ -0,0 +1,93 @@
+use {
+    clap::ValueEnum,
+    serde::Serialize,
+    std::{
+        error::Error,
+        io::{self, Write},
+    },
+};
+
+/// Output format of the reports.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+    /// Aligned columns, for humans.
+    #[default]
+    Text,
+
+    /// Array of objects.
+    Json,
+
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+/// Row of a report.
+pub trait Record: Serialize {
+    /// Column names, in text and CSV formats.
+    const HEADER: &'static [&'static str];
+
+    /// Column values, in text and CSV formats.
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Writes the rows in the given format to the standard output.
+///
+/// Output closed by the reader (e.g. piped to `head`) is not an error.
+pub fn print<R: Record>(rows: &[R], format: Format) -> Result<(), Box<dyn Error>> {
+    let mut out = io::stdout().lock();
+    let written = match format {
+        Format::Text => write_text(&mut out, rows),
+        Format::Json => serde_json::to_writer_pretty(&mut out, rows)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(out)),
+        Format::Csv => write_csv(&mut out, rows),
+    };
+    match written.and_then(|()| out.flush()) {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn write_text<R: Record>(out: &mut impl Write, rows: &[R]) -> io::Result<()> {
+    let header = R::HEADER.iter().map(|name| name.to_uppercase()).collect();
+    let rows = [header]
+        .into_iter()
+        .chain(rows.iter().map(Record::fields))
+        .collect::<Vec<Vec<_>>>();
+    let widths = (0..R::HEADER.len())
+        .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(field, width)| format!("{field:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+fn write_csv<R: Record>(out: &mut impl Write, rows: &[R]) -> io::Result<()> {
+    writeln!(out, "{}", R::HEADER.join(","))?;
+    for row in rows {
+        let line = row
+            .fields()
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Quotes the field, if it contains separators or quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
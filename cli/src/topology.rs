@@ -0,0 +1,310 @@
+use {
+    keyspace::{
+        ContextualReplicationStrategy,
+        DYNAMIC_RF,
+        DefaultReplicationStrategy,
+        FailureDomainReplicationStrategy,
+        HashTag,
+        Hrw,
+        JumpHash,
+        Keyspace,
+        KeyspaceNode,
+        KeyspaceRegistry,
+        KeyspaceResult,
+        Maglev,
+        MigrationPlan,
+        ReplicaContext,
+        Ring,
+        TableConfig,
+    },
+    serde::Deserialize,
+    std::{
+        collections::HashSet,
+        error::Error,
+        fs,
+        hash::{Hash, Hasher},
+        ops::Deref,
+        path::Path,
+    },
+};
+
+/// Name of the table holding the keyspace of the topology.
+const TABLE: &str = "topology";
+
+/// Keyspace as configured in production.
+///
+/// Built as the only table of a registry, as salted placements (see
+/// [`TableConfig::with_salt`]) are only available to tables. Unsalted tables
+/// are placed the same way as standalone keyspaces.
+pub struct TopologyKeyspace(KeyspaceRegistry<Node, Strategy>);
+
+impl TopologyKeyspace {
+    /// Add a node to the keyspace, returning the migration plan.
+    pub fn add_node(&mut self, node: Node) -> KeyspaceResult<MigrationPlan<Node>> {
+        Ok(Self::plan(self.0.add_node(node)?))
+    }
+
+    /// Remove a node from the keyspace, returning the migration plan.
+    pub fn remove_node(&mut self, node_id: &String) -> KeyspaceResult<MigrationPlan<Node>> {
+        Ok(Self::plan(self.0.remove_node(node_id)?))
+    }
+
+    fn plan<I>(plans: I) -> MigrationPlan<Node>
+    where
+        I: IntoIterator<Item = (String, MigrationPlan<Node>)>,
+    {
+        plans
+            .into_iter()
+            .find_map(|(table, plan)| (table == TABLE).then_some(plan))
+            .expect("Keyspace table exists")
+    }
+}
+
+impl Deref for TopologyKeyspace {
+    type Target = Keyspace<Node, Strategy, DYNAMIC_RF>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.table(TABLE).expect("Keyspace table exists")
+    }
+}
+
+/// Topology file: nodes, along with the settings of the keyspace.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    #[serde(default = "default_replication_factor")]
+    pub replication_factor: usize,
+
+    #[serde(default)]
+    pub strategy: StrategyKind,
+
+    #[serde(default)]
+    pub placement: PlacementKind,
+
+    /// Size of the lookup table of Maglev placement (see
+    /// [`Maglev::with_table_size`]).
+    pub maglev_table_size: Option<usize>,
+
+    /// Number of virtual nodes per unit of capacity of ring placement (see
+    /// [`Ring::with_vnodes`]).
+    pub ring_vnodes: Option<usize>,
+
+    /// Salt mixed into the placement (see [`TableConfig::with_salt`]).
+    pub salt: Option<u64>,
+
+    /// Whether keys are placed by their hash tags (see [`HashTag`]).
+    #[serde(default)]
+    pub hash_tags: bool,
+
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+}
+
+fn default_replication_factor() -> usize {
+    3
+}
+
+impl Topology {
+    /// Reads the topology from a JSON (`.json` extension) or TOML file.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read {}: {err}", path.display()))?;
+        let topology = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)
+                .map_err(|err| format!("Invalid topology {}: {err}", path.display()))?
+        } else {
+            toml::from_str(&contents)
+                .map_err(|err| format!("Invalid topology {}: {err}", path.display()))?
+        };
+        Self::check(&topology)
+            .map_err(|err| format!("Invalid topology {}: {err}", path.display()))?;
+        Ok(topology)
+    }
+
+    /// Rejects the topologies that cannot be represented as configured.
+    fn check(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        if let Some(node) = self.nodes.iter().find(|node| !ids.insert(&node.id)) {
+            return Err(format!("duplicate node id {:?}", node.id));
+        }
+        let is_maglev = matches!(self.placement, PlacementKind::Maglev);
+        match self.maglev_table_size {
+            Some(_) if !is_maglev => {
+                return Err("maglev_table_size requires maglev placement".into());
+            }
+            Some(0) => return Err("maglev_table_size must be positive".into()),
+            _ => {}
+        }
+        let is_ring = matches!(self.placement, PlacementKind::Ring);
+        match self.ring_vnodes {
+            Some(_) if !is_ring => return Err("ring_vnodes requires ring placement".into()),
+            Some(0) => return Err("ring_vnodes must be positive".into()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Builds the keyspace over the nodes of the topology.
+    pub fn keyspace(&self) -> Result<TopologyKeyspace, Box<dyn Error>> {
+        let config = TableConfig::new(Strategy::from(self.strategy), self.replication_factor);
+        let config = match self.placement {
+            PlacementKind::Hrw => config.with_placement_algorithm(Hrw),
+            PlacementKind::Maglev => config.with_placement_algorithm(
+                self.maglev_table_size
+                    .map_or_else(Maglev::new, Maglev::with_table_size),
+            ),
+            PlacementKind::Jump => config.with_placement_algorithm(JumpHash),
+            PlacementKind::Ring => config.with_placement_algorithm(
+                self.ring_vnodes.map_or_else(Ring::new, Ring::with_vnodes),
+            ),
+        };
+        let config = match self.salt {
+            Some(salt) => config.with_salt(salt),
+            None => config,
+        };
+        let config = if self.hash_tags {
+            config.with_key_extractor(HashTag::new())
+        } else {
+            config
+        };
+
+        let mut registry = KeyspaceRegistry::new(self.nodes.clone());
+        registry.create_table(TABLE, config)?;
+        Ok(TopologyKeyspace(registry))
+    }
+}
+
+/// Node of the topology.
+///
+/// Nodes are identified (compared and hashed) by their ids only, same as
+/// `String` nodes, so that placement matches keyspaces of nodes hashed by
+/// their ids.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Node {
+    pub id: String,
+
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+
+    /// Topology labels, from the widest failure domain to the narrowest one,
+    /// e.g. `["eu-west", "eu-west-1a"]`.
+    #[serde(default)]
+    pub topology: Vec<String>,
+}
+
+fn default_capacity() -> usize {
+    1
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Node {}
+
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl KeyspaceNode for Node {
+    type Id = String;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn topology(&self) -> &[String] {
+        &self.topology
+    }
+}
+
+/// Replication strategy of the topology.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// Any node is eligible (see [`DefaultReplicationStrategy`]).
+    #[default]
+    Default,
+
+    /// Replicas are spread across failure domains (see
+    /// [`FailureDomainReplicationStrategy`]).
+    FailureDomain,
+}
+
+/// Placement algorithm of the topology.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlacementKind {
+    #[default]
+    Hrw,
+    Maglev,
+    Jump,
+    Ring,
+}
+
+/// Replication strategy selected by the topology file.
+#[derive(Debug, Clone)]
+pub enum Strategy {
+    Default(DefaultReplicationStrategy),
+    FailureDomain(FailureDomainReplicationStrategy),
+}
+
+impl From<StrategyKind> for Strategy {
+    fn from(kind: StrategyKind) -> Self {
+        match kind {
+            StrategyKind::Default => Self::Default(DefaultReplicationStrategy::new()),
+            StrategyKind::FailureDomain => {
+                Self::FailureDomain(FailureDomainReplicationStrategy::new())
+            }
+        }
+    }
+}
+
+impl ContextualReplicationStrategy<Node> for Strategy {
+    fn is_eligible(&mut self, node: &Node, ctx: &ReplicaContext<'_, Node>) -> bool {
+        match self {
+            Self::Default(strategy) => strategy.is_eligible(node, ctx),
+            Self::FailureDomain(strategy) => strategy.is_eligible(node, ctx),
+        }
+    }
+
+    fn relaxed(&self) -> Option<Self> {
+        match self {
+            Self::Default(_) => None,
+            Self::FailureDomain(strategy) => {
+                ContextualReplicationStrategy::<Node>::relaxed(strategy).map(Self::FailureDomain)
+            }
+        }
+    }
+
+    fn prepare(&self, nodes: &[&Node], replication_factor: usize) -> Self {
+        match self {
+            Self::Default(strategy) => Self::Default(*strategy),
+            Self::FailureDomain(strategy) => {
+                Self::FailureDomain(ContextualReplicationStrategy::<Node>::prepare(
+                    strategy,
+                    nodes,
+                    replication_factor,
+                ))
+            }
+        }
+    }
+
+    fn forced_relaxations(&self) -> usize {
+        match self {
+            Self::Default(_) => 0,
+            Self::FailureDomain(strategy) => {
+                ContextualReplicationStrategy::<Node>::forced_relaxations(strategy)
+            }
+        }
+    }
+}
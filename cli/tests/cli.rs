@@ -0,0 +1,219 @@
+use {
+    keyspace::{
+        DefaultReplicationStrategy,
+        KeyspaceBuilder,
+        KeyspaceNode,
+        KeyspaceRegistry,
+        Ring,
+        TableConfig,
+    },
+    std::{env, fs, path::PathBuf, process::Command},
+};
+
+const TOPOLOGY: &str = r#"
+replication_factor = 2
+
+[[nodes]]
+id = "node1"
+topology = ["eu-west", "eu-west-1a"]
+
+[[nodes]]
+id = "node2"
+topology = ["eu-west", "eu-west-1b"]
+
+[[nodes]]
+id = "node3"
+topology = ["us-east", "us-east-1a"]
+"#;
+
+fn topology_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("keyspace-cli-{}-{name}", std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn keyspace(topology: &PathBuf, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_keyspace"))
+        .arg("--topology")
+        .arg(topology)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn cli() {
+    let topology = topology_file("topology.toml", TOPOLOGY);
+
+    // Routing matches the one of the library, for nodes identified by strings.
+    let nodes = vec!["node1".to_string(), "node2".into(), "node3".into()];
+    let expected = KeyspaceBuilder::new(nodes)
+        .with_replication_factor::<2>()
+        .build()
+        .unwrap();
+    let routes = keyspace(&topology, &["--format", "csv", "route", "key1", "key2"]);
+    let mut lines = routes.lines();
+    assert_eq!(
+        lines.next(),
+        Some("key,position,shard_start,shard_end,replicas")
+    );
+    for (line, key) in lines.zip(["key1", "key2"]) {
+        let fields = line.split(',').collect::<Vec<_>>();
+        let position = expected.position_of_key(key);
+        let replicas = expected
+            .replicas_for_key(key)
+            .map(|node| node.id().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(fields[0], key);
+        assert_eq!(fields[1], position.to_string());
+        assert_eq!(fields[2], expected.shard_of(position).start().to_string());
+        assert_eq!(fields[4], replicas.join(" "));
+    }
+
+    // Equal capacities, equal shares.
+    let stats = keyspace(&topology, &["stats"]);
+    assert_eq!(stats.lines().count(), 4);
+    assert!(stats.lines().skip(1).all(|line| line.contains("0.3333")));
+
+    // Migration plan matches the one of the library.
+    let plan = keyspace(&topology, &["--format", "json", "plan", "add", "node4"]);
+    let steps: serde_json::Value = serde_json::from_str(&plan).unwrap();
+    let steps = steps.as_array().unwrap();
+    let mut expected = expected;
+    let expected = expected.add_node("node4".to_string()).unwrap();
+    let pulls = steps
+        .iter()
+        .filter(|step| step["node"] == "node4" && step["action"] == "pull")
+        .map(|step| step["start"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    let mut expected_pulls = expected
+        .pull_intervals(&"node4".to_string())
+        .map(|interval| interval.key_range().start())
+        .collect::<Vec<_>>();
+    expected_pulls.sort();
+    assert!(!pulls.is_empty());
+    assert!(pulls.iter().all(|start| expected_pulls.contains(start)));
+    assert!(
+        steps
+            .iter()
+            .all(|step| step["node"] != "node4" || step["action"] == "pull")
+    );
+
+    // Same topology, as JSON.
+    let json = topology_file(
+        "topology.json",
+        r#"{"replication_factor": 2, "nodes": [{"id": "node1"}, {"id": "node2"}, {"id": "node3"}]}"#,
+    );
+    assert_eq!(
+        keyspace(&json, &["route", "key1", "key2"]),
+        keyspace(&topology, &["route", "key1", "key2"])
+    );
+
+    // Unknown nodes cannot be removed.
+    let output = Command::new(env!("CARGO_BIN_EXE_keyspace"))
+        .arg("--topology")
+        .arg(&topology)
+        .args(["plan", "remove", "node9"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    fs::remove_file(topology).unwrap();
+    fs::remove_file(json).unwrap();
+}
+
+#[test]
+fn placement_parameters() {
+    let nodes = vec!["node1".to_string(), "node2".into(), "node3".into()];
+    let replicas = |routes: &str| {
+        routes
+            .lines()
+            .skip(1)
+            .map(|line| line.rsplit(',').next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let keys = (0..20).map(|i| format!("key{i}")).collect::<Vec<_>>();
+    let args = ["--format", "csv", "route"]
+        .into_iter()
+        .chain(keys.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+
+    // Salted placement matches the one of a salted table.
+    let salted = topology_file("salted.toml", &format!("salt = 42\n{TOPOLOGY}"));
+    let mut registry = KeyspaceRegistry::new(nodes.clone());
+    let table = registry
+        .create_table(
+            "table",
+            TableConfig::new(DefaultReplicationStrategy::new(), 2).with_salt(42),
+        )
+        .unwrap();
+    let expected = keys
+        .iter()
+        .map(|key| {
+            let replicas = table
+                .replicas_for_key(key.as_str())
+                .map(|node| node.id().clone())
+                .collect::<Vec<_>>();
+            replicas.join(" ")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(replicas(&keyspace(&salted, &args)), expected);
+    let unsalted = topology_file("unsalted.toml", TOPOLOGY);
+    assert_ne!(keyspace(&salted, &args), keyspace(&unsalted, &args));
+    fs::remove_file(unsalted).unwrap();
+
+    // Non-default parameters of the placement algorithm.
+    let ring = topology_file(
+        "ring.toml",
+        &format!("placement = \"ring\"\nring_vnodes = 16\n{TOPOLOGY}"),
+    );
+    let expected = KeyspaceBuilder::new(nodes)
+        .with_replication_factor::<2>()
+        .with_placement_algorithm(Ring::with_vnodes(16))
+        .build()
+        .unwrap();
+    let expected = keys
+        .iter()
+        .map(|key| {
+            let replicas = expected
+                .replicas_for_key(key.as_str())
+                .map(|node| node.id().clone())
+                .collect::<Vec<_>>();
+            replicas.join(" ")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(replicas(&keyspace(&ring, &args)), expected);
+
+    // Topologies that cannot be represented are rejected.
+    let invalid = [
+        ("vnodes.toml", format!("ring_vnodes = 16\n{TOPOLOGY}")),
+        (
+            "table.toml",
+            format!("placement = \"ring\"\nmaglev_table_size = 101\n{TOPOLOGY}"),
+        ),
+        (
+            "duplicate.toml",
+            format!("{TOPOLOGY}\n[[nodes]]\nid = \"node1\"\n"),
+        ),
+    ];
+    for (name, contents) in invalid {
+        let topology = topology_file(name, &contents);
+        let output = Command::new(env!("CARGO_BIN_EXE_keyspace"))
+            .arg("--topology")
+            .arg(&topology)
+            .arg("stats")
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{name}");
+        fs::remove_file(topology).unwrap();
+    }
+
+    fs::remove_file(salted).unwrap();
+    fs::remove_file(ring).unwrap();
+}
@@ -0,0 +1,52 @@
+//! Simulates churn of a cluster, measuring data movement against the
+//! theoretical minimum, fairness, constraint satisfaction and plan sizes
+//! after every step.
+//!
+//! Run with `cargo run --release --features simulation --example churn`.
+
+use keyspace::{
+    FailureDomainReplicationStrategy,
+    KeyspaceBuilder,
+    KeyspaceResult,
+    simulation::{SimNode, Simulation},
+};
+
+fn main() -> KeyspaceResult<()> {
+    let nodes = (0..12).map(|id| SimNode::new(id, 1 + id as usize % 3).with_zone(id as usize % 3));
+    let keyspace = KeyspaceBuilder::new(nodes)
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()?;
+    let mut simulation = Simulation::new(keyspace)
+        .with_keys(100_000)
+        .with_capacities(1, 3)
+        .with_zones(3)
+        .with_node_limits(6, 24)
+        .with_seed(7);
+
+    println!(
+        "{:<28} {:>8} {:>8} {:>9} {:>9} {:>9} {:>10} {:>10}",
+        "event", "moved", "minimal", "ratio", "fairness", "degraded", "invariants", "plan size"
+    );
+    for report in simulation.run(30)? {
+        // Ratio is undefined, when nothing had to move.
+        let ratio = report
+            .movement_ratio()
+            .map_or_else(|| "-".to_string(), |ratio| format!("{ratio:.2}x"));
+        let invariants = match report.invariant_violation() {
+            Some(_) => "violated",
+            None => "ok",
+        };
+        println!(
+            "{:<28} {:>7.2}% {:>7.2}% {:>9} {:>8.2}% {:>9} {:>10} {:>10}",
+            format!("{:?}", report.event()),
+            report.moved() * 100.0,
+            report.minimal() * 100.0,
+            ratio,
+            report.fairness() * 100.0,
+            report.degraded_shards(),
+            invariants,
+            report.plan_size()
+        );
+    }
+    Ok(())
+}
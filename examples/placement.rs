@@ -0,0 +1,160 @@
+//! Compares placement algorithms by fairness and movement.
+//!
+//! Fairness is measured as the largest deviation of a node's share of primary
+//! shards from its share of the total capacity. Movement is measured as the
+//! portion of replicas migrated on a node change, relative to the minimal
+//! possible portion (capacity share of the added or removed node).
+//!
+//! Run with `cargo run --release --example placement`.
+
+use {
+    keyspace::{
+        Hrw,
+        JumpHash,
+        KeyspaceBuilder,
+        KeyspaceNode,
+        KeyspaceResult,
+        Maglev,
+        MigrationPlan,
+        PlacementAlgorithm,
+        Ring,
+    },
+    std::collections::HashMap,
+};
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+struct Node {
+    id: String,
+    capacity: usize,
+}
+
+impl Node {
+    fn new(idx: usize, capacity: usize) -> Self {
+        Self {
+            id: format!("node{idx}"),
+            capacity,
+        }
+    }
+}
+
+impl KeyspaceNode for Node {
+    type Id = String;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+const RF: usize = 3;
+
+/// Measurements of a single algorithm.
+struct Report {
+    /// Largest relative deviation of the primary share from capacity share.
+    fairness: f64,
+
+    /// Movement on node addition, relative to the minimal one.
+    add_movement: f64,
+
+    /// Movement on removal of the first added node, relative to the minimal
+    /// one.
+    remove_movement: f64,
+}
+
+fn measure<P>(placement: P, nodes: &[Node]) -> KeyspaceResult<Report>
+where
+    P: PlacementAlgorithm<Node> + 'static,
+{
+    let total_capacity = nodes.iter().map(|node| node.capacity).sum::<usize>();
+    let mut keyspace = KeyspaceBuilder::new(nodes.to_vec())
+        .with_placement_algorithm(placement)
+        .build()?;
+
+    // Primary node of each shard is the first one of its replica set.
+    let mut primaries = HashMap::new();
+    let mut shards = 0;
+    let mut last_range = None;
+    for (key_range, node) in keyspace.iter() {
+        if last_range != Some(key_range) {
+            last_range = Some(key_range);
+            shards += 1;
+            *primaries.entry(node.id().clone()).or_insert(0usize) += 1;
+        }
+    }
+    let fairness = nodes
+        .iter()
+        .map(|node| {
+            let share = primaries.get(node.id()).copied().unwrap_or(0) as f64 / shards as f64;
+            let expected = node.capacity as f64 / total_capacity as f64;
+            (share / expected - 1.0).abs()
+        })
+        .fold(0.0, f64::max);
+
+    let moved = |plan: &MigrationPlan<Node>, ids: &[String]| {
+        ids.iter()
+            .map(|id| plan.pull_intervals(id).count())
+            .sum::<usize>() as f64
+            / (shards * RF) as f64
+    };
+    let mut ids = nodes
+        .iter()
+        .map(|node| node.id().clone())
+        .collect::<Vec<_>>();
+
+    let added = Node::new(nodes.len(), 1);
+    let minimal = added.capacity as f64 / (total_capacity + added.capacity) as f64;
+    ids.push(added.id().clone());
+    let plan = keyspace.add_node(added)?;
+    let add_movement = moved(&plan, &ids) / minimal;
+
+    let removed = &nodes[0];
+    let minimal = removed.capacity as f64 / (total_capacity + 1) as f64;
+    let plan = keyspace.remove_node(removed.id())?;
+    let remove_movement = moved(&plan, &ids) / minimal;
+
+    Ok(Report {
+        fairness,
+        add_movement,
+        remove_movement,
+    })
+}
+
+fn compare(title: &str, nodes: &[Node]) -> KeyspaceResult<()> {
+    println!("{title}");
+    println!(
+        "{:<10} {:>10} {:>14} {:>17}",
+        "algorithm", "fairness", "add movement", "remove movement"
+    );
+    let reports = [
+        ("hrw", measure(Hrw, nodes)?),
+        ("maglev", measure(Maglev::new(), nodes)?),
+        ("jump", measure(JumpHash, nodes)?),
+        ("ring", measure(Ring::new(), nodes)?),
+    ];
+    for (name, report) in reports {
+        println!(
+            "{:<10} {:>9.2}% {:>13.2}x {:>16.2}x",
+            name,
+            report.fairness * 100.0,
+            report.add_movement,
+            report.remove_movement
+        );
+    }
+    println!();
+    Ok(())
+}
+
+fn main() -> KeyspaceResult<()> {
+    let uniform = (0..10).map(|idx| Node::new(idx, 1)).collect::<Vec<_>>();
+    compare("10 nodes of equal capacity", &uniform)?;
+
+    let weighted = (0..10)
+        .map(|idx| Node::new(idx, 1 + idx % 3))
+        .collect::<Vec<_>>();
+    compare("10 nodes of capacities 1-3", &weighted)?;
+
+    Ok(())
+}
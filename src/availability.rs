@@ -0,0 +1,133 @@
+use {
+    super::{
+        Interval,
+        KeyPosition,
+        KeyRange,
+        KeyspaceNode,
+        NodeRef,
+        ReplicaRole,
+        ReplicaRoles,
+        replication::{Substitutes, role_of},
+        sharding::Shards,
+    },
+    std::collections::{BTreeMap, HashMap},
+};
+
+/// Hints kept for a node that is down: key ranges, along with the nodes
+/// substituting it (and the role they substitute it in).
+type Hints<N> = HashMap<KeyRange, Vec<(NodeRef<N>, ReplicaRole)>>;
+
+/// Nodes of a keyspace that are temporarily down, along with the nodes
+/// substituting them in the replica sets.
+///
+/// Marking nodes down does not change the placement: replica sets keep their
+/// members, while requests are routed to the substitutes (hinted handoff
+/// targets), which keep the hints to replay once the nodes are up again.
+pub(crate) struct Availability<N: KeyspaceNode> {
+    /// Nodes that are down, along with the hints kept for them.
+    down: HashMap<N::Id, Hints<N>>,
+
+    /// Substitutes of the replicas that are down, keyed by the start of the key
+    /// range of the shard (or sub-shard).
+    substitutes: BTreeMap<KeyPosition, Substitutes<N>>,
+}
+
+impl<N: KeyspaceNode> Default for Availability<N> {
+    fn default() -> Self {
+        Self {
+            down: HashMap::new(),
+            substitutes: BTreeMap::new(),
+        }
+    }
+}
+
+impl<N: KeyspaceNode> Availability<N> {
+    /// Availability with the same nodes down, and no hints kept so far.
+    pub fn down_only(&self) -> Self {
+        Self {
+            down: self
+                .down
+                .keys()
+                .map(|id| (id.clone(), HashMap::new()))
+                .collect(),
+            substitutes: BTreeMap::new(),
+        }
+    }
+
+    /// Whether any node is down.
+    pub fn has_down(&self) -> bool {
+        !self.down.is_empty()
+    }
+
+    /// Whether the node is down.
+    pub fn is_down(&self, node_id: &N::Id) -> bool {
+        self.down.contains_key(node_id)
+    }
+
+    /// Marks the node down. Returns `false` if it was down already.
+    pub fn mark_down(&mut self, node_id: &N::Id) -> bool {
+        if self.is_down(node_id) {
+            return false;
+        }
+        self.down.insert(node_id.clone(), HashMap::new());
+        true
+    }
+
+    /// Marks the node up, returning the hints kept for it, if it was down.
+    pub fn mark_up(&mut self, node_id: &N::Id) -> Option<Vec<Interval<N>>> {
+        self.down.remove(node_id).map(Self::intervals)
+    }
+
+    /// Hints kept for the node, if it is down.
+    pub fn hints(&self, node_id: &N::Id) -> Option<Vec<Interval<N>>> {
+        self.down.get(node_id).cloned().map(Self::intervals)
+    }
+
+    /// Forgets the nodes that are down, but no longer satisfy the predicate
+    /// (e.g. were removed from the keyspace), along with their hints.
+    pub fn retain<F: Fn(&N::Id) -> bool>(&mut self, keep: F) {
+        self.down.retain(|id, _| keep(id));
+    }
+
+    /// Substitutes of the replicas of the shard with the given key range.
+    pub fn substitutes(&self, key_range: KeyRange) -> &[(usize, NodeRef<N>)] {
+        self.substitutes
+            .get(&key_range.start())
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Replaces the substitutes with the given ones (see
+    /// [`Shards::substitutes`]), recording the hints each of them keeps.
+    pub fn update<const RF: usize>(
+        &mut self,
+        shards: &Shards<N, RF>,
+        substitutes: Vec<(KeyRange, Substitutes<N>)>,
+        roles: Option<ReplicaRoles>,
+    ) {
+        self.substitutes.clear();
+        for (key_range, substitutes) in substitutes {
+            let replica_set = shards.replica_set(key_range.start());
+            for (rank, node) in &substitutes {
+                let Some(hints) = self.down.get_mut(replica_set[*rank].id()) else {
+                    continue;
+                };
+                let hints = hints.entry(key_range).or_default();
+                if !hints.iter().any(|(hinted, _)| hinted.id() == node.id()) {
+                    hints.push((node.clone(), role_of(roles, *rank)));
+                }
+            }
+            self.substitutes.insert(key_range.start(), substitutes);
+        }
+    }
+
+    /// Hints as intervals, ordered by key ranges.
+    fn intervals(hints: Hints<N>) -> Vec<Interval<N>> {
+        let mut intervals = hints
+            .into_iter()
+            .map(|(key_range, nodes)| Interval::new(key_range, nodes))
+            .collect::<Vec<_>>();
+        intervals
+            .sort_by_key(|interval| (interval.key_range().start(), interval.key_range().end()));
+        intervals
+    }
+}
@@ -0,0 +1,175 @@
+use {
+    super::{KeyPosition, KeyspaceNode, NodeRef, replication::DYNAMIC_RF, sharding::Shards},
+    rapidhash::fast::RandomState,
+    std::collections::HashMap,
+};
+
+/// Determines how keys of a batch are grouped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BatchGrouping {
+    /// Keys are grouped by their primary node.
+    #[default]
+    Primary,
+
+    /// Keys are grouped by their full replica set.
+    ///
+    /// Replica sets are compared including the order of nodes, so keys in a
+    /// group share the same primary node as well.
+    ReplicaSet,
+}
+
+/// Batch of keys grouped by nodes responsible for them.
+///
+/// Keys are referred to by their index in the original batch. Indexes of all
+/// the groups are stored in a single buffer (and so are the nodes), so the
+/// number of allocations does not depend on the size of the batch, and each
+/// node reference is cloned once per group, not once per key.
+#[derive(Debug)]
+pub struct RoutedBatch<N: KeyspaceNode> {
+    /// Nodes of all groups.
+    nodes: Vec<NodeRef<N>>,
+
+    /// Nodes of the `i`-th group are stored at
+    /// `nodes[node_offsets[i]..node_offsets[i + 1]]`.
+    node_offsets: Vec<usize>,
+
+    /// Keys of the `i`-th group are stored at `keys[offsets[i]..offsets[i +
+    /// 1]]`.
+    offsets: Vec<usize>,
+
+    /// Indexes of keys, ordered by group.
+    keys: Vec<usize>,
+}
+
+impl<N: KeyspaceNode> RoutedBatch<N> {
+    /// Routes positions of the keys of a batch.
+    pub(crate) fn new<const RF: usize, I>(
+        shards: &Shards<N, RF>,
+        positions: I,
+        grouping: BatchGrouping,
+    ) -> Self
+    where
+        I: IntoIterator<Item = KeyPosition>,
+    {
+        // Replica sets of different ranges might be of different sizes, if
+        // replication factor is set at runtime.
+        let members = |pos| {
+            let replica_set = &shards.replica_set(pos)[..];
+            match grouping {
+                BatchGrouping::Primary => &replica_set[..1],
+                BatchGrouping::ReplicaSet => replica_set,
+            }
+        };
+
+        // Assign each key to a group. Nodes are matched by their addresses, so
+        // that nodes themselves are never hashed.
+        let positions = positions.into_iter();
+        let mut key_groups = Vec::with_capacity(positions.size_hint().0);
+        let mut nodes = Vec::new();
+        let mut node_offsets = vec![0];
+        let mut add_group = |members: &[NodeRef<N>]| {
+            nodes.extend_from_slice(members);
+            node_offsets.push(nodes.len());
+            node_offsets.len() - 2
+        };
+        if RF == DYNAMIC_RF {
+            // Addresses are collected into a reusable buffer, and only copied
+            // once a new group is found.
+            let mut groups = HashMap::<Box<[usize]>, _, _>::with_hasher(RandomState::default());
+            let mut addrs = Vec::new();
+            for pos in positions {
+                let members = members(pos);
+                addrs.clear();
+                addrs.extend(members.iter().map(NodeRef::addr));
+                let group = match groups.get(addrs.as_slice()) {
+                    Some(group) => *group,
+                    None => {
+                        let group = add_group(members);
+                        groups.insert(addrs.as_slice().into(), group);
+                        group
+                    }
+                };
+                key_groups.push(group);
+            }
+        } else {
+            let mut groups = HashMap::with_hasher(RandomState::default());
+            for pos in positions {
+                let members = members(pos);
+                let mut addrs = [0; RF];
+                for (addr, node) in addrs.iter_mut().zip(members) {
+                    *addr = node.addr();
+                }
+                let group = *groups.entry(addrs).or_insert_with(|| add_group(members));
+                key_groups.push(group);
+            }
+        }
+
+        // Counting sort of keys by their groups.
+        let num_groups = node_offsets.len() - 1;
+        let mut offsets = vec![0; num_groups + 1];
+        for &group in &key_groups {
+            offsets[group + 1] += 1;
+        }
+        for group in 0..num_groups {
+            offsets[group + 1] += offsets[group];
+        }
+        let mut cursors = offsets[..num_groups].to_vec();
+        let mut keys = vec![0; key_groups.len()];
+        for (key_idx, &group) in key_groups.iter().enumerate() {
+            keys[cursors[group]] = key_idx;
+            cursors[group] += 1;
+        }
+
+        Self {
+            nodes,
+            node_offsets,
+            offsets,
+            keys,
+        }
+    }
+
+    /// Number of groups in the batch.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Checks if the batch has no groups, i.e. no keys were routed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterator over the groups of the batch.
+    pub fn iter(&self) -> impl Iterator<Item = BatchGroup<'_, N>> {
+        (0..self.len()).map(|group| BatchGroup {
+            nodes: &self.nodes[self.node_offsets[group]..self.node_offsets[group + 1]],
+            keys: &self.keys[self.offsets[group]..self.offsets[group + 1]],
+        })
+    }
+}
+
+/// Group of keys routed to the same nodes.
+#[derive(Debug)]
+pub struct BatchGroup<'a, N: KeyspaceNode> {
+    nodes: &'a [NodeRef<N>],
+    keys: &'a [usize],
+}
+
+impl<'a, N: KeyspaceNode> BatchGroup<'a, N> {
+    /// Nodes responsible for the keys of the group.
+    ///
+    /// When grouped by primary node, only the primary node is returned,
+    /// otherwise the whole replica set is returned (primary node first).
+    pub fn nodes(&self) -> &'a [NodeRef<N>] {
+        self.nodes
+    }
+
+    /// Primary node of the group.
+    pub fn primary(&self) -> &'a NodeRef<N> {
+        &self.nodes[0]
+    }
+
+    /// Indexes of the keys of the group, in the original batch.
+    pub fn keys(&self) -> &'a [usize] {
+        self.keys
+    }
+}
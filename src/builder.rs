@@ -1,19 +1,137 @@
 use {
     super::{
+        ContextualReplicationStrategy,
         DefaultHasher,
         DefaultReplicationStrategy,
+        ErasureCoding,
+        KeyExtractor,
         Keyspace,
+        KeyspaceError,
         KeyspaceNode,
         KeyspaceResult,
-        ReplicationStrategy,
+        NodeRef,
+        PlacementAlgorithm,
+        RangeKeyspace,
+        ReplicaRoles,
+        placement::Hrw,
+        replication::{DYNAMIC_RF, Replication},
+        validation,
+    },
+    std::{
+        collections::BTreeMap,
+        hash::{BuildHasher, BuildHasherDefault},
+        sync::Arc,
     },
-    std::hash::{BuildHasher, BuildHasherDefault},
 };
 
+/// Keyspace settings that do not affect the type of the keyspace.
+pub(crate) struct Options<N: KeyspaceNode> {
+    /// Extracts the portion of the key that determines its position, if not
+    /// the whole key.
+    pub key_extractor: Option<Arc<dyn KeyExtractor>>,
+
+    /// Assigns nodes to portions of the keyspace.
+    pub placement: Arc<dyn PlacementAlgorithm<N>>,
+
+    /// Replication factor, used if it is not set at compile time (see
+    /// [`DYNAMIC_RF`]).
+    pub replication_factor: usize,
+
+    /// Erasure coding scheme, if shards are erasure coded instead of
+    /// replicated.
+    pub erasure_coding: Option<ErasureCoding>,
+
+    /// Number of replicas of each role, if replicas are not all voters.
+    pub replica_roles: Option<ReplicaRoles>,
+
+    /// Whether primaries are balanced across the nodes.
+    pub primary_balancing: bool,
+}
+
+impl<N: KeyspaceNode> Options<N> {
+    /// Replication factor of a keyspace with `RF` compile time replication
+    /// factor.
+    pub fn replication_factor<const RF: usize>(&self) -> KeyspaceResult<usize> {
+        match RF {
+            DYNAMIC_RF if self.replication_factor == 0 => {
+                Err(KeyspaceError::InvalidReplicationFactor(0))
+            }
+            DYNAMIC_RF => Ok(self.replication_factor),
+            _ => Ok(RF),
+        }
+    }
+
+    /// Checks whether the keyspace can be built from the given nodes.
+    ///
+    /// See [`Keyspace::validate`].
+    fn validate<R, const RF: usize>(
+        &self,
+        nodes: &[N],
+        replication_strategy: &R,
+    ) -> KeyspaceResult<()>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+        R: ContextualReplicationStrategy<N>,
+    {
+        validation::validate::<N, R, RF>(
+            nodes.iter().cloned().map(NodeRef::new).collect(),
+            &*self.placement,
+            &Replication::new(
+                replication_strategy.clone(),
+                self.replication_factor::<RF>()?,
+            ),
+            &BTreeMap::new(),
+        )
+    }
+
+    /// Finds the spare nodes that need to be added, for the keyspace to be
+    /// built from the given nodes.
+    ///
+    /// See [`Keyspace::validate_with_spares`].
+    fn validate_with_spares<R, I, const RF: usize>(
+        &self,
+        nodes: &[N],
+        replication_strategy: &R,
+        spares: I,
+    ) -> KeyspaceResult<Vec<N::Id>>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+        R: ContextualReplicationStrategy<N>,
+        I: IntoIterator<Item = N>,
+    {
+        validation::minimal_spares::<N, R, RF>(
+            nodes.iter().cloned().map(NodeRef::new).collect(),
+            spares.into_iter().map(NodeRef::new).collect(),
+            &*self.placement,
+            &Replication::new(
+                replication_strategy.clone(),
+                self.replication_factor::<RF>()?,
+            ),
+            &BTreeMap::new(),
+        )
+    }
+}
+
+impl<N: KeyspaceNode> Default for Options<N> {
+    fn default() -> Self {
+        Self {
+            key_extractor: None,
+            placement: Arc::new(Hrw),
+            replication_factor: 3,
+            erasure_coding: None,
+            replica_roles: None,
+            primary_balancing: false,
+        }
+    }
+}
+
 /// Keyspace builder.
 pub struct KeyspaceBuilder<N: KeyspaceNode, H: BuildHasher = BuildHasherDefault<DefaultHasher>>(
     Vec<N>,
     H,
+    Options<N>,
 );
 
 impl<N: KeyspaceNode> KeyspaceBuilder<N> {
@@ -29,55 +147,286 @@ impl<N: KeyspaceNode, H: BuildHasher> KeyspaceBuilder<N, H> {
     where
         I: IntoIterator<Item = N>,
     {
-        Self(init_nodes.into_iter().collect(), build_hasher)
+        Self(
+            init_nodes.into_iter().collect(),
+            build_hasher,
+            Options::default(),
+        )
+    }
+
+    /// Set the key extractor, used to determine which portion of a key defines
+    /// its position in the keyspace.
+    ///
+    /// See [`Keyspace::replicas_for_key`]. Only applies to hash partitioned
+    /// keyspaces: building a range partitioned keyspace fails with
+    /// [`KeyspaceError::UnsupportedKeyExtractor`].
+    pub fn with_key_extractor<E: KeyExtractor + 'static>(mut self, key_extractor: E) -> Self {
+        self.2.key_extractor = Some(Arc::new(key_extractor));
+        self
+    }
+
+    /// Set the algorithm used to assign nodes to portions of the keyspace.
+    ///
+    /// Defaults to [`Hrw`](crate::Hrw).
+    pub fn with_placement_algorithm<P: PlacementAlgorithm<N> + 'static>(
+        mut self,
+        placement: P,
+    ) -> Self {
+        self.2.placement = Arc::new(placement);
+        self
+    }
+
+    /// Set the number of replicas of each role (voters, learners, and
+    /// witnesses) in replica sets.
+    ///
+    /// Total number of replicas must match the replication factor. By
+    /// default, all the replicas are voters.
+    pub fn with_replica_roles(mut self, replica_roles: ReplicaRoles) -> Self {
+        self.2.replica_roles = Some(replica_roles);
+        self
+    }
+
+    /// Reorder replica sets, so that primaries (the first nodes of replica
+    /// sets) are spread over the nodes in proportion to their capacities.
+    ///
+    /// Members of replica sets stay the same, only the primaries change, and
+    /// changes of primaries are reported as role changes of migration plans
+    /// (see [`MigrationPlan::role_changes`](crate::MigrationPlan::role_changes)).
+    /// Membership changes only reassign primaries of the replica sets they
+    /// change, and nodes with no capacity never become primaries.
+    /// With replica roles, only voters become primaries. Has no effect on
+    /// erasure coded keyspaces, where ranks of nodes are fragment indices.
+    /// Building a range partitioned keyspace with primary balancing fails
+    /// with [`KeyspaceError::UnsupportedPrimaryBalancing`].
+    pub fn with_primary_balancing(mut self) -> Self {
+        self.2.primary_balancing = true;
+        self
     }
 
     /// Transform the builder into one with a different replication factor.
     pub fn with_replication_factor<const RF: usize>(
         self,
     ) -> KeyspaceBuilderWithReplicationFactor<N, DefaultReplicationStrategy, RF, H> {
-        KeyspaceBuilderWithReplicationFactor(self.0, DefaultReplicationStrategy::new(), self.1)
+        KeyspaceBuilderWithReplicationFactor(
+            self.0,
+            DefaultReplicationStrategy::new(),
+            self.1,
+            self.2,
+        )
+    }
+
+    /// Transform the builder into one with the replication factor set at
+    /// runtime.
+    ///
+    /// Replica sets are stored on the heap, and the replication factor can be
+    /// changed later on (see [`Keyspace::set_replication_factor`]).
+    pub fn with_runtime_replication_factor(
+        mut self,
+        replication_factor: usize,
+    ) -> KeyspaceBuilderWithReplicationFactor<N, DefaultReplicationStrategy, DYNAMIC_RF, H> {
+        self.2.replication_factor = replication_factor;
+        self.with_replication_factor::<DYNAMIC_RF>()
+    }
+
+    /// Transform the builder into one with erasure coded shards.
+    ///
+    /// Each shard is placed on `k + m` distinct nodes (see [`ErasureCoding`]),
+    /// i.e. the replication factor is set at runtime to the number of
+    /// fragments, and cannot be changed later on. To keep at most `m`
+    /// fragments in a failure domain, use
+    /// [`ErasureCoding::failure_domains`] as the replication strategy.
+    ///
+    /// Only applies to hash partitioned keyspaces: building a range partitioned
+    /// keyspace fails with [`KeyspaceError::UnsupportedErasureCoding`].
+    pub fn with_erasure_coding(
+        mut self,
+        erasure_coding: ErasureCoding,
+    ) -> KeyspaceBuilderWithReplicationFactor<N, DefaultReplicationStrategy, DYNAMIC_RF, H> {
+        self.2.erasure_coding = Some(erasure_coding);
+        self.with_runtime_replication_factor(erasure_coding.fragments())
     }
 
     /// Transform the builder into one with a different replication strategy.
-    pub fn with_replication_strategy<R: ReplicationStrategy<N>>(
+    pub fn with_replication_strategy<R: ContextualReplicationStrategy<N>>(
         self,
         replication_strategy: R,
     ) -> KeyspaceBuilderWithReplicationStrategy<N, R, 3, H> {
-        KeyspaceBuilderWithReplicationStrategy(self.0, replication_strategy, self.1)
+        KeyspaceBuilderWithReplicationStrategy(self.0, replication_strategy, self.1, self.2)
+    }
+
+    /// Check whether the keyspace can be built, reporting all the shards
+    /// that cannot be filled.
+    ///
+    /// See [`Keyspace::validate`].
+    pub fn validate(&self) -> KeyspaceResult<()>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+    {
+        self.2
+            .validate::<_, 3>(&self.0, &DefaultReplicationStrategy::new())
+    }
+
+    /// Find the minimal subset of spare nodes that need to be added, for the
+    /// keyspace to be built.
+    ///
+    /// See [`Keyspace::validate_with_spares`].
+    pub fn validate_with_spares<I>(&self, spares: I) -> KeyspaceResult<Vec<N::Id>>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+        I: IntoIterator<Item = N>,
+    {
+        self.2
+            .validate_with_spares::<_, _, 3>(&self.0, &DefaultReplicationStrategy::new(), spares)
     }
 
     /// Build the keyspace.
     pub fn build(self) -> KeyspaceResult<Keyspace<N, DefaultReplicationStrategy, 3, H>> {
-        Keyspace::with_build_hasher(self.1, self.0, DefaultReplicationStrategy::new())
+        Keyspace::with_build_hasher(self.1, self.0, DefaultReplicationStrategy::new(), self.2)
+    }
+
+    /// Build range partitioned keyspace, where keys are mapped to positions
+    /// using the given order-preserving encoder.
+    pub fn build_range_partitioned<E>(
+        self,
+        encoder: E,
+    ) -> KeyspaceResult<RangeKeyspace<N, DefaultReplicationStrategy, 3, E>> {
+        RangeKeyspace::with_encoder(encoder, self.0, DefaultReplicationStrategy::new(), self.2)
     }
 }
 
 /// Keyspace builder with custom replication strategy.
-pub struct KeyspaceBuilderWithReplicationStrategy<N, R, const RF: usize, H>(Vec<N>, R, H);
+pub struct KeyspaceBuilderWithReplicationStrategy<N, R, const RF: usize, H>(
+    Vec<N>,
+    R,
+    H,
+    Options<N>,
+)
+where
+    N: KeyspaceNode;
 
 impl<N, R, const RF: usize, H> KeyspaceBuilderWithReplicationStrategy<N, R, RF, H>
 where
     N: KeyspaceNode,
-    R: ReplicationStrategy<N>,
+    R: ContextualReplicationStrategy<N>,
     H: BuildHasher,
 {
     /// Transform the builder into one with a different replication factor.
     pub fn with_replication_factor<const CUSTOM_RF: usize>(
         self,
     ) -> KeyspaceBuilderWithReplicationFactor<N, R, CUSTOM_RF, H> {
-        KeyspaceBuilderWithReplicationFactor(self.0, self.1, self.2)
+        KeyspaceBuilderWithReplicationFactor(self.0, self.1, self.2, self.3)
+    }
+
+    /// Transform the builder into one with the replication factor set at
+    /// runtime.
+    ///
+    /// See [`KeyspaceBuilder::with_runtime_replication_factor`].
+    pub fn with_runtime_replication_factor(
+        mut self,
+        replication_factor: usize,
+    ) -> KeyspaceBuilderWithReplicationStrategy<N, R, DYNAMIC_RF, H> {
+        self.3.replication_factor = replication_factor;
+        KeyspaceBuilderWithReplicationStrategy(self.0, self.1, self.2, self.3)
+    }
+
+    /// Transform the builder into one with erasure coded shards.
+    ///
+    /// See [`KeyspaceBuilder::with_erasure_coding`].
+    pub fn with_erasure_coding(
+        mut self,
+        erasure_coding: ErasureCoding,
+    ) -> KeyspaceBuilderWithReplicationStrategy<N, R, DYNAMIC_RF, H> {
+        self.3.erasure_coding = Some(erasure_coding);
+        self.with_runtime_replication_factor(erasure_coding.fragments())
+    }
+
+    /// Set the key extractor, used to determine which portion of a key defines
+    /// its position in the keyspace.
+    ///
+    /// See [`Keyspace::replicas_for_key`]. Only applies to hash partitioned
+    /// keyspaces: building a range partitioned keyspace fails with
+    /// [`KeyspaceError::UnsupportedKeyExtractor`].
+    pub fn with_key_extractor<E: KeyExtractor + 'static>(mut self, key_extractor: E) -> Self {
+        self.3.key_extractor = Some(Arc::new(key_extractor));
+        self
+    }
+
+    /// Set the algorithm used to assign nodes to portions of the keyspace.
+    ///
+    /// Defaults to [`Hrw`](crate::Hrw).
+    pub fn with_placement_algorithm<P: PlacementAlgorithm<N> + 'static>(
+        mut self,
+        placement: P,
+    ) -> Self {
+        self.3.placement = Arc::new(placement);
+        self
+    }
+
+    /// Set the number of replicas of each role in replica sets.
+    ///
+    /// See [`KeyspaceBuilder::with_replica_roles`].
+    pub fn with_replica_roles(mut self, replica_roles: ReplicaRoles) -> Self {
+        self.3.replica_roles = Some(replica_roles);
+        self
+    }
+
+    /// Reorder replica sets, so that primaries are spread over the nodes in
+    /// proportion to their capacities.
+    ///
+    /// See [`KeyspaceBuilder::with_primary_balancing`].
+    pub fn with_primary_balancing(mut self) -> Self {
+        self.3.primary_balancing = true;
+        self
+    }
+
+    /// Check whether the keyspace can be built, reporting all the shards
+    /// that cannot be filled.
+    ///
+    /// See [`Keyspace::validate`].
+    pub fn validate(&self) -> KeyspaceResult<()>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+    {
+        self.3.validate::<_, RF>(&self.0, &self.1)
+    }
+
+    /// Find the minimal subset of spare nodes that need to be added, for the
+    /// keyspace to be built.
+    ///
+    /// See [`Keyspace::validate_with_spares`].
+    pub fn validate_with_spares<I>(&self, spares: I) -> KeyspaceResult<Vec<N::Id>>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+        I: IntoIterator<Item = N>,
+    {
+        self.3
+            .validate_with_spares::<_, _, RF>(&self.0, &self.1, spares)
     }
 
     /// Build the keyspace with the given replication strategy and default
     /// replication factor.
     pub fn build(self) -> KeyspaceResult<Keyspace<N, R, RF, H>> {
-        Keyspace::with_build_hasher(self.2, self.0, self.1)
+        Keyspace::with_build_hasher(self.2, self.0, self.1, self.3)
+    }
+
+    /// Build range partitioned keyspace, where keys are mapped to positions
+    /// using the given order-preserving encoder.
+    pub fn build_range_partitioned<E>(
+        self,
+        encoder: E,
+    ) -> KeyspaceResult<RangeKeyspace<N, R, RF, E>> {
+        RangeKeyspace::with_encoder(encoder, self.0, self.1, self.3)
     }
 }
 
 /// Keyspace builder with custom replication factor.
-pub struct KeyspaceBuilderWithReplicationFactor<N, R, const RF: usize, H>(Vec<N>, R, H);
+pub struct KeyspaceBuilderWithReplicationFactor<N, R, const RF: usize, H>(Vec<N>, R, H, Options<N>)
+where
+    N: KeyspaceNode;
 
 impl<N, R, const RF: usize, H> KeyspaceBuilderWithReplicationFactor<N, R, RF, H>
 where
@@ -85,16 +434,114 @@ where
     H: BuildHasher,
 {
     /// Transform the builder into one with a different replication strategy.
-    pub fn with_replication_strategy<CustomR: ReplicationStrategy<N>>(
+    pub fn with_replication_strategy<CustomR: ContextualReplicationStrategy<N>>(
         self,
         replication_strategy: CustomR,
     ) -> KeyspaceBuilderWithReplicationStrategy<N, CustomR, RF, H> {
-        KeyspaceBuilderWithReplicationStrategy(self.0, replication_strategy, self.2)
+        KeyspaceBuilderWithReplicationStrategy(self.0, replication_strategy, self.2, self.3)
+    }
+
+    /// Transform the builder into one with the replication factor set at
+    /// runtime.
+    ///
+    /// See [`KeyspaceBuilder::with_runtime_replication_factor`].
+    pub fn with_runtime_replication_factor(
+        mut self,
+        replication_factor: usize,
+    ) -> KeyspaceBuilderWithReplicationFactor<N, R, DYNAMIC_RF, H> {
+        self.3.replication_factor = replication_factor;
+        KeyspaceBuilderWithReplicationFactor(self.0, self.1, self.2, self.3)
+    }
+
+    /// Transform the builder into one with erasure coded shards.
+    ///
+    /// See [`KeyspaceBuilder::with_erasure_coding`].
+    pub fn with_erasure_coding(
+        mut self,
+        erasure_coding: ErasureCoding,
+    ) -> KeyspaceBuilderWithReplicationFactor<N, R, DYNAMIC_RF, H> {
+        self.3.erasure_coding = Some(erasure_coding);
+        self.with_runtime_replication_factor(erasure_coding.fragments())
+    }
+
+    /// Set the key extractor, used to determine which portion of a key defines
+    /// its position in the keyspace.
+    ///
+    /// See [`Keyspace::replicas_for_key`]. Only applies to hash partitioned
+    /// keyspaces: building a range partitioned keyspace fails with
+    /// [`KeyspaceError::UnsupportedKeyExtractor`].
+    pub fn with_key_extractor<E: KeyExtractor + 'static>(mut self, key_extractor: E) -> Self {
+        self.3.key_extractor = Some(Arc::new(key_extractor));
+        self
+    }
+
+    /// Set the algorithm used to assign nodes to portions of the keyspace.
+    ///
+    /// Defaults to [`Hrw`](crate::Hrw).
+    pub fn with_placement_algorithm<P: PlacementAlgorithm<N> + 'static>(
+        mut self,
+        placement: P,
+    ) -> Self {
+        self.3.placement = Arc::new(placement);
+        self
+    }
+
+    /// Set the number of replicas of each role in replica sets.
+    ///
+    /// See [`KeyspaceBuilder::with_replica_roles`].
+    pub fn with_replica_roles(mut self, replica_roles: ReplicaRoles) -> Self {
+        self.3.replica_roles = Some(replica_roles);
+        self
+    }
+
+    /// Reorder replica sets, so that primaries are spread over the nodes in
+    /// proportion to their capacities.
+    ///
+    /// See [`KeyspaceBuilder::with_primary_balancing`].
+    pub fn with_primary_balancing(mut self) -> Self {
+        self.3.primary_balancing = true;
+        self
+    }
+
+    /// Check whether the keyspace can be built, reporting all the shards
+    /// that cannot be filled.
+    ///
+    /// See [`Keyspace::validate`].
+    pub fn validate(&self) -> KeyspaceResult<()>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+    {
+        self.3
+            .validate::<_, RF>(&self.0, &DefaultReplicationStrategy::new())
+    }
+
+    /// Find the minimal subset of spare nodes that need to be added, for the
+    /// keyspace to be built.
+    ///
+    /// See [`Keyspace::validate_with_spares`].
+    pub fn validate_with_spares<I>(&self, spares: I) -> KeyspaceResult<Vec<N::Id>>
+    where
+        N: Clone,
+        N::Id: Send + Sync + 'static,
+        I: IntoIterator<Item = N>,
+    {
+        self.3
+            .validate_with_spares::<_, _, RF>(&self.0, &DefaultReplicationStrategy::new(), spares)
     }
 
     /// Build the keyspace with the given replication factor and default
     /// replication strategy.
     pub fn build(self) -> KeyspaceResult<Keyspace<N, DefaultReplicationStrategy, RF, H>> {
-        Keyspace::with_build_hasher(self.2, self.0, DefaultReplicationStrategy::new())
+        Keyspace::with_build_hasher(self.2, self.0, DefaultReplicationStrategy::new(), self.3)
+    }
+
+    /// Build range partitioned keyspace, where keys are mapped to positions
+    /// using the given order-preserving encoder.
+    pub fn build_range_partitioned<E>(
+        self,
+        encoder: E,
+    ) -> KeyspaceResult<RangeKeyspace<N, DefaultReplicationStrategy, RF, E>> {
+        RangeKeyspace::with_encoder(encoder, self.0, DefaultReplicationStrategy::new(), self.3)
     }
 }
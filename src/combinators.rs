@@ -0,0 +1,376 @@
+//! Replication strategy combinators.
+//!
+//! Combinators compose replication strategies, so that constraints like
+//! "distinct zones, excluding nodes under maintenance" do not require writing
+//! a new strategy by hand:
+//!
+//! ```
+//! use keyspace::{
+//!     FailureDomainReplicationStrategy,
+//!     KeyspaceBuilder,
+//!     KeyspaceNode,
+//!     combinators::{And, Filter, MaxPerGroup},
+//! };
+//!
+//! #[derive(Debug, Hash, PartialEq, Eq, Clone)]
+//! struct MyNode {
+//!     id: String,
+//!     topology: Vec<String>,
+//!     maintenance: bool,
+//! }
+//!
+//! impl KeyspaceNode for MyNode {
+//!     type Id = String;
+//!
+//!     fn id(&self) -> &Self::Id {
+//!         &self.id
+//!     }
+//!
+//!     fn topology(&self) -> &[String] {
+//!         &self.topology
+//!     }
+//! }
+//!
+//! let strategy = And::new(
+//!     FailureDomainReplicationStrategy::new(),
+//!     Filter::new(|node: &MyNode| !node.maintenance),
+//! );
+//! let strategy = And::new(
+//!     strategy,
+//!     MaxPerGroup::new(|node: &MyNode| node.topology[0].clone(), 2),
+//! );
+//!
+//! let nodes = (0..6).map(|i| MyNode {
+//!     id: format!("node{i}"),
+//!     topology: vec![format!("zone{}", i % 3)],
+//!     maintenance: i == 0,
+//! });
+//! let ks = KeyspaceBuilder::new(nodes)
+//!     .with_replication_strategy(strategy)
+//!     .build()
+//!     .expect("Failed to create keyspace");
+//! assert!(ks.replicas(&"key").all(|node| !node.maintenance));
+//! ```
+//!
+//! Strategies are stateful, and record the nodes they accept. To keep their
+//! state consistent, operands of [`And`], [`Or`] and [`Not`] are never shown
+//! the nodes that the combinator as a whole rejects: each candidate is checked
+//! by a fresh copy of an operand, which is shown the nodes accepted so far
+//! first. As with any other strategy, each replica set gets a fresh copy of
+//! the combinator.
+//!
+//! Hence cloning a combinator, or [`MaxPerGroup`], does not copy its state:
+//! the clone starts over, as if none of the nodes had been checked yet.
+
+use super::ReplicationStrategy;
+
+/// Strategy composed into a combinator.
+#[derive(Debug)]
+struct Operand<S> {
+    /// Pristine copy, used to check candidates.
+    template: S,
+
+    /// Copy that has been shown all the candidates, used to get a fallback.
+    seen: S,
+}
+
+/// Clones the template, without the candidates shown so far.
+impl<S: Clone> Clone for Operand<S> {
+    fn clone(&self) -> Self {
+        Self::new(self.template.clone())
+    }
+}
+
+impl<S: Clone> Operand<S> {
+    fn new(strategy: S) -> Self {
+        Self {
+            seen: strategy.clone(),
+            template: strategy,
+        }
+    }
+
+    /// Checks the node with a fresh copy of the strategy, that has been shown
+    /// the accepted nodes first.
+    fn check<N>(&mut self, accepted: &[N], node: &N) -> bool
+    where
+        S: ReplicationStrategy<N>,
+    {
+        self.seen.is_eligible_replica(node);
+
+        let mut strategy = self.template.clone();
+        for accepted in accepted {
+            strategy.is_eligible_replica(accepted);
+        }
+        strategy.is_eligible_replica(node)
+    }
+
+    /// Fallback tiers forced by the topology of the nodes, for an operand
+    /// that has fallbacks (see [`ReplicationStrategy::forced_fallbacks`]).
+    fn forced_fallbacks<N>(&self) -> Option<usize>
+    where
+        S: ReplicationStrategy<N>,
+    {
+        self.template
+            .fallback()
+            .map(|_| self.template.forced_fallbacks())
+    }
+
+    /// Fallback tiers of the combinator of two operands forced by the topology
+    /// of the nodes: fallbacks relax both the operands, so only the tiers
+    /// forced for each of the operands that have fallbacks are.
+    fn forced_fallbacks_of<N, T>(a: &Self, b: &Operand<T>) -> usize
+    where
+        S: ReplicationStrategy<N>,
+        T: ReplicationStrategy<N>,
+    {
+        match (a.forced_fallbacks(), b.forced_fallbacks()) {
+            (Some(fa), Some(fb)) => fa.min(fb),
+            (forced, None) | (None, forced) => forced.unwrap_or(0),
+        }
+    }
+
+    /// Fallbacks of two operands, where operand without a fallback is kept
+    /// as is.
+    ///
+    /// Returns `None` if neither of the operands has a fallback.
+    fn fallback<N, T>(a: &Self, b: &Operand<T>) -> Option<(S, T)>
+    where
+        S: ReplicationStrategy<N>,
+        T: ReplicationStrategy<N>,
+    {
+        match (a.seen.fallback(), b.seen.fallback()) {
+            (None, None) => None,
+            (fa, fb) => Some((
+                fa.unwrap_or_else(|| a.template.clone()),
+                fb.unwrap_or_else(|| b.template.clone()),
+            )),
+        }
+    }
+}
+
+/// Node is eligible if it is eligible for both strategies.
+///
+/// Fallback relaxes both the strategies (whichever have fallbacks).
+#[derive(Debug)]
+pub struct And<N, A, B> {
+    a: Operand<A>,
+    b: Operand<B>,
+    accepted: Vec<N>,
+}
+
+/// Clones the operands as they were created, without the accepted nodes.
+impl<N, A: Clone, B: Clone> Clone for And<N, A, B> {
+    fn clone(&self) -> Self {
+        Self::new(self.a.template.clone(), self.b.template.clone())
+    }
+}
+
+impl<N, A: Clone, B: Clone> And<N, A, B> {
+    /// Creates a conjunction of two strategies.
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a: Operand::new(a),
+            b: Operand::new(b),
+            accepted: Vec::new(),
+        }
+    }
+}
+
+impl<N, A, B> ReplicationStrategy<N> for And<N, A, B>
+where
+    N: Clone,
+    A: ReplicationStrategy<N>,
+    B: ReplicationStrategy<N>,
+{
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        let a = self.a.check(&self.accepted, node);
+        let b = self.b.check(&self.accepted, node);
+        if a && b {
+            self.accepted.push(node.clone());
+        }
+        a && b
+    }
+
+    fn fallback(&self) -> Option<Self> {
+        Operand::fallback(&self.a, &self.b).map(|(a, b)| Self::new(a, b))
+    }
+
+    fn adapt(&self, nodes: &[&N], replication_factor: usize) -> Self {
+        Self::new(
+            self.a.template.adapt(nodes, replication_factor),
+            self.b.template.adapt(nodes, replication_factor),
+        )
+    }
+
+    fn forced_fallbacks(&self) -> usize {
+        Operand::forced_fallbacks_of(&self.a, &self.b)
+    }
+}
+
+/// Node is eligible if it is eligible for either of the strategies.
+///
+/// Fallback relaxes both the strategies (whichever have fallbacks).
+#[derive(Debug)]
+pub struct Or<N, A, B> {
+    a: Operand<A>,
+    b: Operand<B>,
+    accepted: Vec<N>,
+}
+
+/// Clones the operands as they were created, without the accepted nodes.
+impl<N, A: Clone, B: Clone> Clone for Or<N, A, B> {
+    fn clone(&self) -> Self {
+        Self::new(self.a.template.clone(), self.b.template.clone())
+    }
+}
+
+impl<N, A: Clone, B: Clone> Or<N, A, B> {
+    /// Creates a disjunction of two strategies.
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a: Operand::new(a),
+            b: Operand::new(b),
+            accepted: Vec::new(),
+        }
+    }
+}
+
+impl<N, A, B> ReplicationStrategy<N> for Or<N, A, B>
+where
+    N: Clone,
+    A: ReplicationStrategy<N>,
+    B: ReplicationStrategy<N>,
+{
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        let a = self.a.check(&self.accepted, node);
+        let b = self.b.check(&self.accepted, node);
+        if a || b {
+            self.accepted.push(node.clone());
+        }
+        a || b
+    }
+
+    fn fallback(&self) -> Option<Self> {
+        Operand::fallback(&self.a, &self.b).map(|(a, b)| Self::new(a, b))
+    }
+
+    fn adapt(&self, nodes: &[&N], replication_factor: usize) -> Self {
+        Self::new(
+            self.a.template.adapt(nodes, replication_factor),
+            self.b.template.adapt(nodes, replication_factor),
+        )
+    }
+
+    fn forced_fallbacks(&self) -> usize {
+        Operand::forced_fallbacks_of(&self.a, &self.b)
+    }
+}
+
+/// Node is eligible if it is not eligible for the strategy.
+///
+/// Has no fallback, as relaxing the strategy would make the negation stricter.
+#[derive(Debug)]
+pub struct Not<N, S> {
+    strategy: Operand<S>,
+    accepted: Vec<N>,
+}
+
+/// Clones the negated strategy as it was created, without the accepted nodes.
+impl<N, S: Clone> Clone for Not<N, S> {
+    fn clone(&self) -> Self {
+        Self::new(self.strategy.template.clone())
+    }
+}
+
+impl<N, S: Clone> Not<N, S> {
+    /// Creates a negation of the strategy.
+    pub fn new(strategy: S) -> Self {
+        Self {
+            strategy: Operand::new(strategy),
+            accepted: Vec::new(),
+        }
+    }
+}
+
+impl<N, S> ReplicationStrategy<N> for Not<N, S>
+where
+    N: Clone,
+    S: ReplicationStrategy<N>,
+{
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        let eligible = !self.strategy.check(&self.accepted, node);
+        if eligible {
+            self.accepted.push(node.clone());
+        }
+        eligible
+    }
+
+    fn adapt(&self, nodes: &[&N], replication_factor: usize) -> Self {
+        Self::new(self.strategy.template.adapt(nodes, replication_factor))
+    }
+}
+
+/// Node is eligible if it satisfies the predicate.
+#[derive(Debug, Clone)]
+pub struct Filter<F>(F);
+
+impl<F> Filter<F> {
+    /// Creates a strategy accepting nodes that satisfy the predicate.
+    pub fn new(predicate: F) -> Self {
+        Self(predicate)
+    }
+}
+
+impl<N, F> ReplicationStrategy<N> for Filter<F>
+where
+    F: Fn(&N) -> bool + Clone,
+{
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        (self.0)(node)
+    }
+}
+
+/// Node is eligible if there are less than `max` replicas in its group.
+///
+/// Groups are determined by the key function, e.g. `|node| node.zone()` for
+/// "at most `max` replicas per zone".
+#[derive(Debug)]
+pub struct MaxPerGroup<K, F> {
+    key_fn: F,
+    max: usize,
+    groups: Vec<K>,
+}
+
+/// Clones the key function and the limit, without the groups of the accepted
+/// nodes.
+impl<K, F: Clone> Clone for MaxPerGroup<K, F> {
+    fn clone(&self) -> Self {
+        Self::new(self.key_fn.clone(), self.max)
+    }
+}
+
+impl<K, F> MaxPerGroup<K, F> {
+    /// Creates a strategy accepting at most `max` replicas per group.
+    pub fn new(key_fn: F, max: usize) -> Self {
+        Self {
+            key_fn,
+            max,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl<N, K, F> ReplicationStrategy<N> for MaxPerGroup<K, F>
+where
+    K: PartialEq,
+    F: Fn(&N) -> K + Clone,
+{
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        let group = (self.key_fn)(node);
+        let eligible = self.groups.iter().filter(|g| **g == group).count() < self.max;
+        if eligible {
+            self.groups.push(group);
+        }
+        eligible
+    }
+}
@@ -0,0 +1,244 @@
+use {
+    super::{KeyspaceError, KeyspaceNode, KeyspaceResult, NodeRef, ReplicaRole},
+    std::collections::HashSet,
+};
+
+/// Number of replicas that must acknowledge a request.
+///
+/// Only the replicas that vote count towards the level (see
+/// [`ReplicaRole::votes`]), i.e. all of them, unless replica roles are
+/// configured. Reads are only served by the replicas storing the data, so
+/// witnesses do not count towards the level of reads (see
+/// [`ReplicaRole::stores_data`]), while learners do count towards reads at
+/// [`ConsistencyLevel::One`], as any replica storing the data may serve
+/// them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyLevel {
+    /// A single replica.
+    One,
+
+    /// Majority of the replicas.
+    Quorum,
+
+    /// All the replicas.
+    All,
+
+    /// Majority of the replicas in the local failure domain, identified by
+    /// its topology labels, from the widest one (see
+    /// [`KeyspaceNode::topology`]). E.g. `["eu-west"]` for a region, or
+    /// `["eu-west", "eu-west-1a"]` for a zone in it.
+    LocalQuorum(Vec<String>),
+}
+
+impl ConsistencyLevel {
+    /// Majority of the replicas in the failure domain with the given topology
+    /// labels (see [`ConsistencyLevel::LocalQuorum`]).
+    pub fn local_quorum<I, S>(domain: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::LocalQuorum(domain.into_iter().map(Into::into).collect())
+    }
+
+    /// Number of responses required, when the given number of replicas
+    /// count towards the level.
+    pub fn required(&self, replicas: usize) -> usize {
+        match self {
+            Self::One => 1,
+            Self::Quorum | Self::LocalQuorum(_) => replicas / 2 + 1,
+            Self::All => replicas,
+        }
+    }
+
+    /// Checks whether the replica counts towards the level, of reads or of
+    /// writes.
+    fn counts<N: KeyspaceNode>(&self, node: &N, role: ReplicaRole, reads: bool) -> bool {
+        let counts = match self {
+            Self::One if reads => role.stores_data(),
+            _ => role.votes() && (role.stores_data() || !reads),
+        };
+        match self {
+            Self::LocalQuorum(domain) => counts && node.topology().starts_with(domain),
+            _ => counts,
+        }
+    }
+}
+
+/// Tracks responses of the replicas to a request, until its consistency
+/// level is satisfied.
+///
+/// Lists the replicas to send the request to: the minimal subset of the
+/// replicas that are up, which satisfies the level if all of them respond
+/// (see [`ResponseTracker::contact`]), and the rest of them, to retry with
+/// when some fail (see [`ResponseTracker::spares`]).
+#[derive(Debug)]
+pub struct ResponseTracker<N: KeyspaceNode> {
+    level: ConsistencyLevel,
+    required: usize,
+    contact: Vec<NodeRef<N>>,
+    spares: Vec<NodeRef<N>>,
+    responded: HashSet<N::Id>,
+    failed: HashSet<N::Id>,
+}
+
+impl<N: KeyspaceNode> ResponseTracker<N> {
+    /// Tracker of a write request to the given replicas, along with their
+    /// roles, in the order of preference.
+    ///
+    /// Replicas that are down (according to `is_down`) are never contacted.
+    /// If there are not enough replicas up to satisfy the level,
+    /// [`KeyspaceError::InsufficientReplicas`] is returned.
+    pub fn new<I, F>(replicas: I, level: ConsistencyLevel, is_down: F) -> KeyspaceResult<Self>
+    where
+        I: IntoIterator<Item = (NodeRef<N>, ReplicaRole)>,
+        F: Fn(&N::Id) -> bool,
+    {
+        let replicas = replicas.into_iter().map(|(node, role)| (node, role, None));
+        Self::with_kind(replicas, level, false, is_down)
+    }
+
+    /// Tracker of a write request to the given replicas, along with their
+    /// roles and substitutes, in the order of preference.
+    ///
+    /// Same as [`ResponseTracker::new`], except that a replica that is down
+    /// is substituted (hinted handoff), and responses of its substitute count
+    /// for it.
+    pub(crate) fn with_substitutes<I, F>(
+        replicas: I,
+        level: ConsistencyLevel,
+        is_down: F,
+    ) -> KeyspaceResult<Self>
+    where
+        I: IntoIterator<Item = (NodeRef<N>, ReplicaRole, Option<NodeRef<N>>)>,
+        F: Fn(&N::Id) -> bool,
+    {
+        Self::with_kind(replicas, level, false, is_down)
+    }
+
+    /// Tracker of a read request to the given replicas, along with their
+    /// roles, in the order of preference.
+    ///
+    /// Same as [`ResponseTracker::new`], except that the replicas storing no
+    /// data (witnesses) are neither contacted, nor count towards the level,
+    /// and that learners count towards [`ConsistencyLevel::One`].
+    pub fn for_reads<I, F>(replicas: I, level: ConsistencyLevel, is_down: F) -> KeyspaceResult<Self>
+    where
+        I: IntoIterator<Item = (NodeRef<N>, ReplicaRole)>,
+        F: Fn(&N::Id) -> bool,
+    {
+        let replicas = replicas.into_iter().map(|(node, role)| (node, role, None));
+        Self::with_kind(replicas, level, true, is_down)
+    }
+
+    fn with_kind<I, F>(
+        replicas: I,
+        level: ConsistencyLevel,
+        reads: bool,
+        is_down: F,
+    ) -> KeyspaceResult<Self>
+    where
+        I: IntoIterator<Item = (NodeRef<N>, ReplicaRole, Option<NodeRef<N>>)>,
+        F: Fn(&N::Id) -> bool,
+    {
+        let counted = replicas
+            .into_iter()
+            .filter(|(node, role, _)| level.counts(node.inner(), *role, reads))
+            .collect::<Vec<_>>();
+        // Majority is of all the replicas, including the ones that are down,
+        // which are contacted through their substitutes, if any.
+        let required = level.required(counted.len());
+        let mut contact = counted
+            .into_iter()
+            .filter_map(|(node, _, substitute)| {
+                if is_down(node.id()) {
+                    substitute
+                } else {
+                    Some(node)
+                }
+            })
+            .collect::<Vec<_>>();
+        if contact.len() < required {
+            return Err(KeyspaceError::InsufficientReplicas(required, contact.len()));
+        }
+        let spares = contact.split_off(required);
+
+        Ok(Self {
+            level,
+            required,
+            contact,
+            spares,
+            responded: HashSet::new(),
+            failed: HashSet::new(),
+        })
+    }
+
+    /// Consistency level of the request.
+    pub fn level(&self) -> &ConsistencyLevel {
+        &self.level
+    }
+
+    /// Number of responses required to satisfy the level.
+    pub fn required(&self) -> usize {
+        self.required
+    }
+
+    /// Minimal set of replicas to contact, the first ones in the order of
+    /// preference that are up.
+    pub fn contact(&self) -> &[NodeRef<N>] {
+        &self.contact
+    }
+
+    /// Rest of the replicas that are up, and count towards the level, in the
+    /// order of preference, to contact when some of the replicas fail.
+    pub fn spares(&self) -> &[NodeRef<N>] {
+        &self.spares
+    }
+
+    /// Records a successful response of the node, returning whether the level
+    /// is satisfied.
+    ///
+    /// Responses of the nodes not counting towards the level (e.g. learners
+    /// for writes, witnesses for reads, or replicas outside of the local
+    /// domain) are ignored, as are repeated ones.
+    pub fn record_response(&mut self, node_id: &N::Id) -> bool {
+        if self.is_tracked(node_id) && !self.failed.contains(node_id) {
+            self.responded.insert(node_id.clone());
+        }
+        self.is_satisfied()
+    }
+
+    /// Records a failure of the node (e.g. a timeout), returning whether the
+    /// level can still be satisfied.
+    pub fn record_failure(&mut self, node_id: &N::Id) -> bool {
+        if self.is_tracked(node_id) && !self.responded.contains(node_id) {
+            self.failed.insert(node_id.clone());
+        }
+        !self.is_failed()
+    }
+
+    /// Number of successful responses counting towards the level.
+    pub fn responses(&self) -> usize {
+        self.responded.len()
+    }
+
+    /// Checks whether enough replicas responded.
+    pub fn is_satisfied(&self) -> bool {
+        self.responded.len() >= self.required
+    }
+
+    /// Checks whether the level can no longer be satisfied, as too many
+    /// replicas failed.
+    pub fn is_failed(&self) -> bool {
+        let alive = self.contact.len() + self.spares.len() - self.failed.len();
+        alive < self.required
+    }
+
+    /// Checks whether responses of the node count towards the level.
+    fn is_tracked(&self, node_id: &N::Id) -> bool {
+        self.contact
+            .iter()
+            .chain(&self.spares)
+            .any(|node| node.id() == node_id)
+    }
+}
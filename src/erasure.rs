@@ -0,0 +1,117 @@
+use super::{
+    FailureDomainReplicationStrategy,
+    KeyRange,
+    KeyspaceError,
+    KeyspaceNode,
+    KeyspaceResult,
+    NodeRef,
+};
+
+/// Erasure coding scheme, where data of a shard is encoded into `k` data and
+/// `m` parity fragments, any `k` of which are enough to restore the data.
+///
+/// Erasure coded keyspace (see
+/// [`KeyspaceBuilder::with_erasure_coding`](crate::KeyspaceBuilder::with_erasure_coding))
+/// places each shard on `k + m` distinct nodes, where the node at position `i`
+/// of a replica set stores the `i`-th fragment. Positions are stable: when
+/// replica set changes, remaining nodes keep their fragments, and only the
+/// fragments of the nodes that left are rebuilt (see
+/// [`MigrationPlan::reconstructions`](crate::MigrationPlan::reconstructions)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureCoding {
+    data: usize,
+    parity: usize,
+}
+
+impl ErasureCoding {
+    /// Scheme with `data` data fragments and `parity` parity fragments.
+    ///
+    /// At least one data fragment is required.
+    pub fn new(data: usize, parity: usize) -> KeyspaceResult<Self> {
+        if data == 0 {
+            return Err(KeyspaceError::InvalidErasureCoding(data, parity));
+        }
+        Ok(Self { data, parity })
+    }
+
+    /// Number of data fragments (`k`), i.e. the number of fragments needed
+    /// to restore the data.
+    pub fn data_fragments(&self) -> usize {
+        self.data
+    }
+
+    /// Number of parity fragments (`m`), i.e. the number of fragments that
+    /// can be lost without losing the data.
+    pub fn parity_fragments(&self) -> usize {
+        self.parity
+    }
+
+    /// Total number of fragments (`k + m`).
+    pub fn fragments(&self) -> usize {
+        self.data + self.parity
+    }
+
+    /// Failure domain replication strategy, that places at most `m`
+    /// fragments of a shard into a single failure domain at the given
+    /// topology level, so that the data survives the loss of a whole domain.
+    ///
+    /// See [`FailureDomainReplicationStrategy::with_cap`].
+    pub fn failure_domains(&self, level: usize) -> FailureDomainReplicationStrategy {
+        FailureDomainReplicationStrategy::new().with_cap(level, self.parity)
+    }
+}
+
+/// Fragment of an erasure coded shard, that needs to be rebuilt on a node.
+#[derive(Debug, Clone)]
+pub struct Reconstruction<N: KeyspaceNode> {
+    key_range: KeyRange,
+    fragment: usize,
+    sources: Vec<(usize, NodeRef<N>)>,
+}
+
+impl<N: KeyspaceNode> Reconstruction<N> {
+    pub(crate) fn new(
+        key_range: KeyRange,
+        fragment: usize,
+        sources: Vec<(usize, NodeRef<N>)>,
+    ) -> Self {
+        Self {
+            key_range,
+            fragment,
+            sources,
+        }
+    }
+
+    /// Range of keys of the fragment.
+    pub fn key_range(&self) -> KeyRange {
+        self.key_range
+    }
+
+    /// Index of the fragment to rebuild.
+    pub fn fragment(&self) -> usize {
+        self.fragment
+    }
+
+    /// Fragments to read, as `(fragment index, node)` tuples.
+    ///
+    /// If the node that stored the fragment so far is still in the keyspace,
+    /// it is the only source, and the fragment is copied as is (see
+    /// [`Reconstruction::is_copy`]). Otherwise, `k` fragments of the nodes
+    /// remaining from the old replica set are listed, data fragments first,
+    /// and the fragment is decoded from them. Empty, if fewer than `k`
+    /// fragments remain, i.e. the data is lost.
+    pub fn sources(&self) -> &[(usize, NodeRef<N>)] {
+        &self.sources
+    }
+
+    /// Whether the fragment is copied from the node that stored it so far,
+    /// instead of being decoded.
+    pub fn is_copy(&self) -> bool {
+        matches!(&self.sources[..], [(fragment, _)] if *fragment == self.fragment)
+    }
+
+    /// Whether there are enough fragments left to rebuild the fragment.
+    pub fn is_recoverable(&self) -> bool {
+        !self.sources.is_empty()
+    }
+}
@@ -1,4 +1,14 @@
+use {
+    crate::KeyRange,
+    std::{any::Any, fmt, sync::Arc},
+};
+
+/// Error of a keyspace operation.
+///
+/// Variants may be added in future releases, so matches on it need a wildcard
+/// arm.
 #[derive(Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
 pub enum KeyspaceError {
     /// Not enough nodes for replication factor
     #[error("Not enough nodes for a given replication factor: {0}")]
@@ -23,6 +33,210 @@ pub enum KeyspaceError {
     /// Number of shards in new and old keyspace do not match
     #[error("Number of shards in new and old keyspace do not match")]
     ShardCountMismatch,
+
+    /// Shard cannot be split by the given number of bits
+    #[error("Invalid number of bits to split a shard by: {0}")]
+    InvalidSplitBits(u8),
+
+    /// Shard is not split into sub-shards
+    #[error("Shard is not split")]
+    ShardNotSplit,
+
+    /// Invalid tablet boundary in range partitioned keyspace
+    #[error("Invalid tablet boundary: {0}")]
+    InvalidBoundary(u64),
+
+    /// Range partitioned keyspaces cannot be erasure coded
+    #[error("Erasure coding is not supported by range partitioned keyspaces")]
+    UnsupportedErasureCoding,
+
+    /// Range partitioned keyspaces place keys by their encoding, not by
+    /// extracted portions of them
+    #[error("Key extractors are not supported by range partitioned keyspaces")]
+    UnsupportedKeyExtractor,
+
+    /// Range partitioned keyspaces cannot balance primaries
+    #[error("Primary balancing is not supported by range partitioned keyspaces")]
+    UnsupportedPrimaryBalancing,
+
+    /// Table with the given name already exists
+    #[error("Table already exists: {0}")]
+    TableExists(String),
+
+    /// Table with the given name does not exist
+    #[error("Table not found: {0}")]
+    TableNotFound(String),
+
+    /// Replication factor must be positive
+    #[error("Invalid replication factor: {0}")]
+    InvalidReplicationFactor(usize),
+
+    /// Erasure coding requires at least one data fragment
+    #[error("Invalid erasure coding: {0} data and {1} parity fragments")]
+    InvalidErasureCoding(usize, usize),
+
+    /// Replica sets require at least one voter
+    #[error("Invalid replica roles: {0} voters, {1} learners and {2} witnesses")]
+    InvalidReplicaRoles(usize, usize, usize),
+
+    /// Not enough replicas are up to satisfy the consistency level
+    #[error("Not enough replicas available: {0} required, {1} alive")]
+    InsufficientReplicas(usize, usize),
+
+    /// Keyspace state is inconsistent
+    #[error("Invariant violated: {0}")]
+    InvariantViolation(String),
+
+    /// Replica sets of some shards cannot be filled
+    #[error(
+        "Infeasible placement: {} shards cannot be filled, at least {} more nodes needed (lower \
+         bound)",
+        .0.unfilled().len(),
+        .0.min_nodes_to_add()
+    )]
+    InfeasiblePlacement(Box<PlacementReport>),
+}
+
+/// Outcome of a placement validation.
+///
+/// Node ids are type-erased (see [`ReportedId`]), so that the report does not
+/// depend on the node type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacementReport {
+    unfilled: Vec<UnfilledShard>,
+}
+
+impl PlacementReport {
+    pub(crate) fn new(unfilled: Vec<UnfilledShard>) -> Self {
+        Self { unfilled }
+    }
+
+    /// Shards (or sub-shards) whose replica sets cannot be filled, in the
+    /// order of their key ranges.
+    pub fn unfilled(&self) -> &[UnfilledShard] {
+        &self.unfilled
+    }
+
+    /// Lower bound of the number of nodes that need to be added to make the
+    /// placement feasible, i.e. the largest number of replicas missing from a
+    /// single replica set.
+    ///
+    /// More nodes may be needed, e.g. when the strategy requires new nodes to
+    /// be in distinct failure domains; use
+    /// [`Keyspace::validate_with_spares`](crate::Keyspace::validate_with_spares)
+    /// to find out which nodes would be enough.
+    pub fn min_nodes_to_add(&self) -> usize {
+        self.unfilled
+            .iter()
+            .map(|shard| shard.replication_factor - shard.selected.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Shard whose replica set cannot be filled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnfilledShard {
+    key_range: KeyRange,
+    replication_factor: usize,
+    selected: Vec<ReportedId>,
+    rejected: Vec<Vec<ReportedId>>,
+}
+
+impl UnfilledShard {
+    pub(crate) fn new(
+        key_range: KeyRange,
+        replication_factor: usize,
+        selected: Vec<ReportedId>,
+        rejected: Vec<Vec<ReportedId>>,
+    ) -> Self {
+        Self {
+            key_range,
+            replication_factor,
+            selected,
+            rejected,
+        }
+    }
+
+    /// Range of keys of the shard.
+    pub fn key_range(&self) -> KeyRange {
+        self.key_range
+    }
+
+    /// Number of replicas the shard needs.
+    pub fn replication_factor(&self) -> usize {
+        self.replication_factor
+    }
+
+    /// Nodes selected before the strategy (and all its fallbacks) ran out of
+    /// eligible candidates.
+    pub fn selected(&self) -> &[ReportedId] {
+        &self.selected
+    }
+
+    /// Nodes rejected by the replication strategy, indexed by fallback tier:
+    /// nodes rejected by the strategy itself come first, followed by the
+    /// nodes rejected by each of its fallbacks.
+    ///
+    /// Nodes already selected are not checked by the fallbacks again.
+    pub fn rejected(&self) -> &[Vec<ReportedId>] {
+        &self.rejected
+    }
+}
+
+/// Id of a node in a [`PlacementReport`].
+///
+/// Wraps the id of the node (see
+/// [`KeyspaceNode::Id`](crate::KeyspaceNode::Id)), which can be recovered with
+/// [`ReportedId::downcast_ref`]. Ids are compared and formatted as the wrapped
+/// ids are.
+#[derive(Clone)]
+pub struct ReportedId(Arc<dyn ErasedId>);
+
+impl ReportedId {
+    pub(crate) fn new<T>(id: T) -> Self
+    where
+        T: fmt::Debug + PartialEq + Send + Sync + 'static,
+    {
+        Self(Arc::new(id))
+    }
+
+    /// The wrapped id, if it is of type `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.as_any().downcast_ref()
+    }
+}
+
+impl fmt::Debug for ReportedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq for ReportedId {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_id(&*other.0)
+    }
+}
+
+/// Node id, with its type erased.
+trait ErasedId: fmt::Debug + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    fn eq_id(&self, other: &dyn ErasedId) -> bool;
+}
+
+impl<T> ErasedId for T
+where
+    T: fmt::Debug + PartialEq + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn eq_id(&self, other: &dyn ErasedId) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
 }
 
 pub type KeyspaceResult<T> = Result<T, KeyspaceError>;
@@ -1,4 +1,16 @@
-use {rapidhash::v3::rapidhash_v3, std::hash::Hasher};
+use {
+    rapidhash::v3::{DEFAULT_RAPID_SECRETS, rapidhash_v3},
+    std::hash::Hasher,
+};
+
+/// Number of input bytes mixed at once, for long inputs.
+const BLOCK_LEN: usize = 112;
+
+/// Number of trailing bytes that are re-read when hash is finalized.
+const TAIL_LEN: usize = 16;
+
+/// Capacity of the input buffer.
+const BUF_LEN: usize = 2 * BLOCK_LEN + TAIL_LEN;
 
 /// Default hasher for the keyspace.
 ///
@@ -6,19 +18,121 @@ use {rapidhash::v3::rapidhash_v3, std::hash::Hasher};
 /// For C++ compatibility, relies on the default seed and secrets.
 ///
 /// The output is portable across platforms and major releases.
-#[derive(Default)]
-pub struct DefaultHasher(Vec<u8>);
+///
+/// Input is hashed in a streaming fashion: bytes are accumulated in a fixed
+/// size buffer, and mixed block by block, as soon as it is known that the
+/// block is not at the end of the input. So, no heap allocations are made,
+/// while the output is exactly the same as that of `rapidhash_v3` applied to
+/// all the written bytes at once.
+pub struct DefaultHasher {
+    /// Input bytes that are not yet mixed.
+    buf: [u8; BUF_LEN],
+
+    /// Number of bytes in the buffer.
+    len: usize,
+
+    /// State of the mixing lanes, available once the first block is mixed.
+    lanes: Option<[u64; 7]>,
+}
+
+impl Default for DefaultHasher {
+    fn default() -> Self {
+        Self {
+            buf: [0; BUF_LEN],
+            len: 0,
+            lanes: None,
+        }
+    }
+}
 
 impl Hasher for DefaultHasher {
-    fn write(&mut self, bytes: &[u8]) {
-        self.0.extend_from_slice(bytes);
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(BUF_LEN - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+
+            // Trailing bytes of the input are needed for finalization, so only
+            // mix the block when there's enough bytes after it.
+            while self.len > BLOCK_LEN + TAIL_LEN {
+                let lanes = self.lanes.get_or_insert([DEFAULT_RAPID_SECRETS.seed; 7]);
+                mix_block(lanes, &self.buf[..BLOCK_LEN]);
+                self.buf.copy_within(BLOCK_LEN..self.len, 0);
+                self.len -= BLOCK_LEN;
+            }
+        }
     }
 
     fn finish(&self) -> u64 {
-        rapidhash_v3(&self.0)
+        let data = &self.buf[..self.len];
+        let Some(mut lanes) = self.lanes else {
+            // The whole input is still in the buffer.
+            return rapidhash_v3(data);
+        };
+
+        // Input is longer than a block, and at least `TAIL_LEN + 1` bytes
+        // are buffered.
+        let secrets = &DEFAULT_RAPID_SECRETS.secrets;
+        let mut tail = data;
+        if tail.len() > BLOCK_LEN {
+            mix_block(&mut lanes, &tail[..BLOCK_LEN]);
+            tail = &tail[BLOCK_LEN..];
+        }
+
+        let [mut seed, see1, mut see2, see3, mut see4, see5, see6] = lanes;
+        seed ^= see1;
+        see2 ^= see3;
+        see4 ^= see5;
+        seed ^= see6;
+        see2 ^= see4;
+        seed ^= see2;
+
+        for (i, secret) in [2, 2, 1, 1, 2, 1].into_iter().enumerate() {
+            if tail.len() <= TAIL_LEN * (i + 1) {
+                break;
+            }
+            seed = mix(
+                read_u64(tail, 16 * i) ^ secrets[secret],
+                read_u64(tail, 16 * i + 8) ^ seed,
+            );
+        }
+
+        let remainder = tail.len() as u64;
+        let a = read_u64(data, data.len() - 16) ^ remainder ^ secrets[1];
+        let b = read_u64(data, data.len() - 8) ^ seed;
+        let (a, b) = mum(a, b);
+        mix(a ^ 0xaaaaaaaaaaaaaaaa, b ^ secrets[1] ^ remainder)
+    }
+}
+
+/// Mixes a block of input into the lanes.
+fn mix_block(lanes: &mut [u64; 7], block: &[u8]) {
+    let secrets = &DEFAULT_RAPID_SECRETS.secrets;
+    for (i, lane) in lanes.iter_mut().enumerate() {
+        *lane = mix(
+            read_u64(block, 16 * i) ^ secrets[i],
+            read_u64(block, 16 * i + 8) ^ *lane,
+        );
     }
 }
 
+fn mum(a: u64, b: u64) -> (u64, u64) {
+    let r = (a as u128).wrapping_mul(b as u128);
+    (r as u64, (r >> 64) as u64)
+}
+
+fn mix(a: u64, b: u64) -> u64 {
+    let (lo, hi) = mum(a, b);
+    lo ^ hi
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
 #[cfg(test)]
 mod tests {
     use {
@@ -30,11 +144,11 @@ mod tests {
     fn sanity_checks() {
         // Ensure that the hasher produces consistent results.
         let data = b"hello world";
-        let mut hasher1 = DefaultHasher(Vec::new());
+        let mut hasher1 = DefaultHasher::default();
         hasher1.write(data);
         let hash1 = hasher1.finish();
 
-        let mut hasher2 = DefaultHasher(Vec::new());
+        let mut hasher2 = DefaultHasher::default();
         hasher2.write(data);
         let hash2 = hasher2.finish();
         assert_eq!(hash1, hash2, "Hashes should be equal for the same input");
@@ -55,4 +169,29 @@ mod tests {
         };
         assert_eq!(builder.hash_one(my_struct), 17347315807818014607);
     }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let data = (0..1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<_>>();
+
+        for len in 0..data.len() {
+            let expected = rapidhash_v3(&data[..len]);
+
+            // Single write.
+            let mut hasher = DefaultHasher::default();
+            hasher.write(&data[..len]);
+            assert_eq!(hasher.finish(), expected, "len: {len}");
+
+            // Writes of various chunk sizes.
+            for chunk_len in [1, 3, 7, 16, 17, 111, 112, 113, 240] {
+                let mut hasher = DefaultHasher::default();
+                for chunk in data[..len].chunks(chunk_len) {
+                    hasher.write(chunk);
+                }
+                assert_eq!(hasher.finish(), expected, "len: {len}, chunk: {chunk_len}");
+            }
+        }
+    }
 }
@@ -1,7 +1,7 @@
-use super::{KeyPosition, KeyspaceNode, NodeRef};
+use super::{KeyPosition, KeyspaceNode, NodeRef, ReplicaRole};
 
 /// A range of keys in the keyspace.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum KeyRange {
     Bounded(KeyPosition, KeyPosition),
     Unbounded(KeyPosition),
@@ -16,6 +16,23 @@ impl KeyRange {
         }
     }
 
+    /// Start position of the range (inclusive).
+    pub fn start(&self) -> KeyPosition {
+        match self {
+            KeyRange::Bounded(start, _) | KeyRange::Unbounded(start) => *start,
+        }
+    }
+
+    /// End position of the range (exclusive).
+    ///
+    /// Unbounded range extends to the end of the keyspace, and has no end.
+    pub fn end(&self) -> Option<KeyPosition> {
+        match self {
+            KeyRange::Bounded(_, end) => Some(*end),
+            KeyRange::Unbounded(_) => None,
+        }
+    }
+
     /// Check if the given key is in the range.
     ///
     /// Note not the key itself, but the hash of the key provides the position
@@ -36,6 +53,7 @@ impl KeyRange {
 pub struct Interval<N: KeyspaceNode> {
     key_range: KeyRange,
     nodes: Vec<NodeRef<N>>,
+    roles: Vec<ReplicaRole>,
 }
 
 impl<N: KeyspaceNode> Clone for Interval<N> {
@@ -43,15 +61,24 @@ impl<N: KeyspaceNode> Clone for Interval<N> {
         Self {
             key_range: self.key_range,
             nodes: self.nodes.clone(),
+            roles: self.roles.clone(),
         }
     }
 }
 
 impl<N: KeyspaceNode> Interval<N> {
-    /// Creates a new interval with the given key range and nodes.
-    pub(crate) fn new<I: IntoIterator<Item = NodeRef<N>>>(key_range: KeyRange, nodes: I) -> Self {
-        let nodes = nodes.into_iter().collect::<Vec<_>>();
-        Self { key_range, nodes }
+    /// Creates a new interval with the given key range and nodes, along with
+    /// their roles.
+    pub(crate) fn new<I>(key_range: KeyRange, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = (NodeRef<N>, ReplicaRole)>,
+    {
+        let (nodes, roles) = nodes.into_iter().unzip();
+        Self {
+            key_range,
+            nodes,
+            roles,
+        }
     }
 
     /// Returns the key range of the interval.
@@ -63,4 +90,20 @@ impl<N: KeyspaceNode> Interval<N> {
     pub fn nodes(&self) -> &Vec<NodeRef<N>> {
         &self.nodes
     }
+
+    /// Returns the roles of the nodes, in the same order as
+    /// [`Interval::nodes`].
+    pub fn roles(&self) -> &[ReplicaRole] {
+        &self.roles
+    }
+
+    /// Returns the nodes storing the data of the interval, i.e. all the
+    /// nodes but witnesses.
+    pub fn data_nodes(&self) -> impl Iterator<Item = &NodeRef<N>> {
+        self.nodes
+            .iter()
+            .zip(&self.roles)
+            .filter(|(_, role)| role.stores_data())
+            .map(|(node, _)| node)
+    }
 }
@@ -0,0 +1,320 @@
+use {
+    super::{
+        ContextualReplicationStrategy,
+        KeyPosition,
+        KeyRange,
+        KeyspaceError,
+        KeyspaceNode,
+        KeyspaceResult,
+        MigrationPlan,
+        NodeRef,
+        ReplicaRoles,
+        node::Nodes,
+        replication::{self, Replication},
+        sharding::Shards,
+    },
+    std::{
+        cell::Cell,
+        collections::{BTreeMap, HashMap, HashSet},
+        hash::Hash,
+    },
+};
+
+fn violation(key_range: KeyRange, message: impl AsRef<str>) -> KeyspaceError {
+    KeyspaceError::InvariantViolation(format!("{key_range:?}: {}", message.as_ref()))
+}
+
+/// Checks that the shards tile the keyspace, and that every replica set
+/// consists of the required number of distinct nodes of the keyspace, filled
+/// with a tier the replication strategy has.
+///
+/// Eligibility of the nodes is not replayed, as it depends on the context
+/// the replica set was selected in (placement counts, and the order of
+/// selection), which is not kept: sub-shards are placed with the counts of
+/// all the other shards, and members are reordered when primaries are
+/// balanced or fragments are aligned.
+pub(crate) fn check_shards<N, R, const RF: usize>(
+    shards: &Shards<N, RF>,
+    nodes: &Nodes<N>,
+    replication: &Replication<R>,
+) -> KeyspaceResult<()>
+where
+    N: KeyspaceNode,
+    R: ContextualReplicationStrategy<N>,
+{
+    check_tiling(shards.iter().map(|shard| shard.key_range()))?;
+
+    let replication = replication.prepare(&nodes.values());
+    for shard in shards.iter() {
+        let key_range = shard.key_range();
+        let replica_set = shard.replica_set();
+        let (strategy, factor) = replication.resolve(key_range);
+        if replica_set.len() != factor {
+            return Err(violation(
+                key_range,
+                format!("{} replicas, {factor} required", replica_set.len()),
+            ));
+        }
+        for (rank, node) in replica_set.iter().enumerate() {
+            if node.addr() == 0 {
+                return Err(violation(key_range, format!("empty replica at {rank}")));
+            }
+            if !nodes.contains(node.id()) {
+                return Err(violation(
+                    key_range,
+                    format!("unknown node {:?}", node.id()),
+                ));
+            }
+            if replica_set[..rank].contains(node) {
+                return Err(violation(
+                    key_range,
+                    format!("duplicate node {:?}", node.id()),
+                ));
+            }
+        }
+
+        // Tier the replica set was filled with must exist.
+        let mut strategy = strategy.clone();
+        for tier in 1..=replica_set.fallback_tier() {
+            strategy = strategy
+                .relaxed()
+                .ok_or_else(|| violation(key_range, format!("no fallback tier {tier}")))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the key ranges, in order, cover the whole keyspace, with no
+/// gaps or overlaps.
+fn check_tiling<I: IntoIterator<Item = KeyRange>>(key_ranges: I) -> KeyspaceResult<()> {
+    let mut next = Some(0);
+    let mut last = KeyRange::new(0, Some(0));
+    for key_range in key_ranges {
+        if next != Some(key_range.start()) {
+            return Err(violation(
+                key_range,
+                format!("expected to start at {next:?}"),
+            ));
+        }
+        if key_range.end().is_some_and(|end| end <= key_range.start()) {
+            return Err(violation(key_range, "empty key range"));
+        }
+        next = key_range.end();
+        last = key_range;
+    }
+    match next {
+        None => Ok(()),
+        Some(_) => Err(violation(last, "keyspace is not covered up to its end")),
+    }
+}
+
+/// Checks that applying the migration plan to the previous layout of the
+/// keyspace, as `(key range, node)` tuples (see
+/// [`Keyspace::iter`](crate::Keyspace::iter)), yields the current shards.
+///
+/// Without erasure coding, nodes storing the data of each key range (as per
+/// their roles) must end up being the nodes pulling it, and the nodes that
+/// stored it before, except for the ones cleaning it up. Data is pulled from
+/// the nodes that stored it. With erasure coding, every fragment that changes
+/// hands must be rebuilt on its new node.
+pub(crate) fn check_migration<N, const RF: usize>(
+    previous: Vec<(KeyRange, NodeRef<N>)>,
+    shards: &Shards<N, RF>,
+    plan: &MigrationPlan<N>,
+    roles: Option<ReplicaRoles>,
+    erasure_coded: bool,
+) -> KeyspaceResult<()>
+where
+    N: KeyspaceNode,
+{
+    // Replicas of a key range are listed together, in order.
+    let mut old = Vec::<(KeyRange, Vec<NodeRef<N>>)>::new();
+    for (key_range, node) in previous {
+        match old.last_mut() {
+            Some((last, nodes)) if *last == key_range => nodes.push(node),
+            _ => old.push((key_range, vec![node])),
+        }
+    }
+    check_tiling(old.iter().map(|(key_range, _)| *key_range))?;
+    let new = shards
+        .iter()
+        .map(|shard| (shard.key_range(), shard.replica_set()))
+        .collect::<Vec<_>>();
+
+    let node_ids = old
+        .iter()
+        .flat_map(|(_, nodes)| nodes.iter())
+        .chain(new.iter().flat_map(|(_, replica_set)| replica_set.iter()))
+        .map(|node| node.id().clone())
+        .chain(plan.keys().cloned())
+        .collect::<HashSet<_>>();
+
+    let mut pulls = HashMap::new();
+    let mut cleanups = HashMap::new();
+    let mut reconstructions = HashMap::new();
+    for id in &node_ids {
+        pulls.insert(
+            id.clone(),
+            index(
+                plan.pull_intervals(id)
+                    .map(|interval| (*interval.key_range(), interval)),
+            ),
+        );
+        cleanups.insert(
+            id.clone(),
+            index(plan.cleanup_intervals(id).map(|key_range| (*key_range, ()))),
+        );
+        reconstructions.insert(
+            id.clone(),
+            index(
+                plan.reconstructions(id)
+                    .map(|r| (r.key_range(), r.fragment())),
+            ),
+        );
+    }
+
+    let stored = |nodes: &[NodeRef<N>]| {
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(rank, _)| replication::role_of(roles, *rank).stores_data())
+            .map(|(_, node)| node.id().clone())
+            .collect::<Vec<_>>()
+    };
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        let (old_range, old_nodes) = &old[i];
+        let (new_range, new_nodes) = &new[j];
+        let end = match (old_range.end(), new_range.end()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let segment = KeyRange::new(old_range.start().max(new_range.start()), end);
+
+        if old_range.end() == end {
+            i += 1;
+        }
+        if new_range.end() == end {
+            j += 1;
+        }
+
+        // Unchanged replicas need no entries, and any entry is left unmatched.
+        if old_nodes[..] == new_nodes[..] {
+            continue;
+        }
+        if erasure_coded {
+            for (fragment, node) in new_nodes.iter().enumerate() {
+                if old_nodes.get(fragment) == Some(node) {
+                    continue;
+                }
+                if covering(&reconstructions, node.id(), segment) != Some(&fragment) {
+                    return Err(violation(
+                        segment,
+                        format!("fragment {fragment} is not rebuilt on {:?}", node.id()),
+                    ));
+                }
+            }
+            for node in old_nodes {
+                if !new_nodes.contains(node) && covering(&cleanups, node.id(), segment).is_none() {
+                    return Err(violation(
+                        segment,
+                        format!("node {:?} does not clean up", node.id()),
+                    ));
+                }
+            }
+        } else {
+            let old_data = stored(old_nodes);
+            let new_data = stored(new_nodes);
+            let mut data = old_data
+                .iter()
+                .filter(|id| covering(&cleanups, id, segment).is_none())
+                .cloned()
+                .collect::<Vec<_>>();
+            // Pulls by other nodes are left unmatched.
+            for id in new_nodes.iter().map(|node| node.id()) {
+                let Some(interval) = covering(&pulls, id, segment) else {
+                    continue;
+                };
+                if data.contains(id) {
+                    return Err(violation(segment, format!("node {id:?} pulls stored data")));
+                }
+                if let Some(source) = interval
+                    .data_nodes()
+                    .find(|source| !old_data.contains(source.id()))
+                {
+                    return Err(violation(
+                        segment,
+                        format!("node {id:?} pulls from {:?}", source.id()),
+                    ));
+                }
+                data.push(id.clone());
+            }
+            if data.len() != new_data.len() || !new_data.iter().all(|id| data.contains(id)) {
+                return Err(violation(
+                    segment,
+                    format!("plan yields {data:?}, instead of {new_data:?}"),
+                ));
+            }
+        }
+    }
+
+    // Entries not matching any change of the layout.
+    for (entries, kind) in [
+        (unused(&pulls), "pull"),
+        (unused(&cleanups), "cleanup"),
+        (unused(&reconstructions), "reconstruction"),
+    ] {
+        if let Some((id, key_range)) = entries {
+            return Err(violation(key_range, format!("unexpected {kind} by {id:?}")));
+        }
+    }
+    Ok(())
+}
+
+/// Entries of a migration plan for each node, by the starts of their key
+/// ranges, flagged once they match a change of the layout.
+type Index<K, T> = HashMap<K, BTreeMap<KeyPosition, (KeyRange, T, Cell<bool>)>>;
+
+/// Entries of a migration plan for a node, by the starts of their key
+/// ranges.
+fn index<T, I>(entries: I) -> BTreeMap<KeyPosition, (KeyRange, T, Cell<bool>)>
+where
+    I: IntoIterator<Item = (KeyRange, T)>,
+{
+    entries
+        .into_iter()
+        .map(|(key_range, entry)| (key_range.start(), (key_range, entry, Cell::new(false))))
+        .collect()
+}
+
+/// Finds the entry of the node, which covers the whole segment, flagging it
+/// as matched.
+fn covering<'a, K, T>(entries: &'a Index<K, T>, node_id: &K, segment: KeyRange) -> Option<&'a T>
+where
+    K: Hash + Eq,
+{
+    let (_, (key_range, entry, matched)) = entries
+        .get(node_id)?
+        .range(..=segment.start())
+        .next_back()?;
+    let covers = match (key_range.end(), segment.end()) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(end), Some(segment_end)) => end >= segment_end,
+    };
+    covers.then(|| {
+        matched.set(true);
+        entry
+    })
+}
+
+/// Finds an entry that did not match any change of the layout.
+fn unused<K: Clone, T>(entries: &Index<K, T>) -> Option<(K, KeyRange)> {
+    entries.iter().find_map(|(id, entries)| {
+        entries
+            .values()
+            .find(|(_, _, matched)| !matched.get())
+            .map(|(key_range, _, _)| (id.clone(), *key_range))
+    })
+}
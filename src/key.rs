@@ -0,0 +1,159 @@
+use {
+    auto_impl::auto_impl,
+    std::{
+        borrow::Cow,
+        hash::{Hash, Hasher},
+    },
+};
+
+/// Key that can be routed by its bytes.
+///
+/// Such keys are subject to key extraction (see [`KeyExtractor`]), that is only
+/// a portion of the key may determine its position in the keyspace.
+#[auto_impl(&, Box)]
+pub trait KeyspaceKey {
+    /// Returns the bytes of the key.
+    fn key_bytes(&self) -> &[u8];
+}
+
+impl KeyspaceKey for str {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl KeyspaceKey for String {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl KeyspaceKey for [u8] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl KeyspaceKey for Vec<u8> {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl KeyspaceKey for Cow<'_, str> {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Extracts the portion of the key that determines its position in the
+/// keyspace.
+///
+/// Keys with the same extracted portion are placed on the same replica set,
+/// which allows to co-locate related keys.
+pub trait KeyExtractor: Send + Sync {
+    /// Returns the portion of the key that should be hashed.
+    fn extract<'a>(&self, key: &'a [u8]) -> &'a [u8];
+}
+
+/// Key extractor that uses the whole key.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WholeKey;
+
+impl KeyExtractor for WholeKey {
+    fn extract<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        key
+    }
+}
+
+/// Hash tag key extractor.
+///
+/// Works the same way as hash tags in Redis Cluster: if the key contains an
+/// opening delimiter, followed by a closing delimiter, and there's at least
+/// one byte between them, only the bytes between the first opening delimiter
+/// and the first closing delimiter after it are hashed. Otherwise, the whole
+/// key is hashed.
+///
+/// For example, with the default `{` and `}` delimiters, `{user:42}:profile`
+/// and `{user:42}:cart` are both placed by `user:42`.
+#[derive(Debug, Clone, Copy)]
+pub struct HashTag {
+    open: u8,
+    close: u8,
+}
+
+impl Default for HashTag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashTag {
+    /// Creates a hash tag extractor with `{` and `}` delimiters.
+    pub fn new() -> Self {
+        Self::with_delimiters(b'{', b'}')
+    }
+
+    /// Creates a hash tag extractor with custom delimiters.
+    pub fn with_delimiters(open: u8, close: u8) -> Self {
+        Self { open, close }
+    }
+}
+
+impl KeyExtractor for HashTag {
+    fn extract<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        let Some(start) = key.iter().position(|&b| b == self.open) else {
+            return key;
+        };
+        let tag = &key[start + 1..];
+        match tag.iter().position(|&b| b == self.close) {
+            Some(end) if end > 0 => &tag[..end],
+            _ => key,
+        }
+    }
+}
+
+/// Bytes of a [`KeyspaceKey`], hashed the same way as a string is, so that
+/// string keys are placed the same way, whether routed as keys or as
+/// hashable values.
+pub(crate) struct StrBytes<'a>(pub &'a [u8]);
+
+impl Hash for StrBytes<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.0);
+        state.write_u8(0xff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_tag_extraction() {
+        let tag = HashTag::new();
+        let cases: [(&str, &str); 8] = [
+            ("{user:42}:profile", "user:42"),
+            ("prefix{user:42}suffix", "user:42"),
+            ("{a}{b}", "a"),
+            ("{{a}}", "{a"),
+            ("no tag", "no tag"),
+            ("{}empty", "{}empty"),
+            ("{unterminated", "{unterminated"),
+            ("}{a}", "a"),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(
+                tag.extract(key.key_bytes()),
+                expected.as_bytes(),
+                "key: {key}"
+            );
+        }
+
+        let tag = HashTag::with_delimiters(b'[', b']');
+        assert_eq!(tag.extract(b"[x]:1"), b"x");
+        assert_eq!(tag.extract(b"{x}:1"), b"{x}:1");
+
+        assert_eq!(WholeKey.extract(b"{x}:1"), b"{x}:1");
+    }
+}
@@ -1,31 +1,70 @@
 #![forbid(unsafe_code)]
 
+mod availability;
+mod batch;
 mod builder;
+pub mod combinators;
+mod consistency;
+mod erasure;
 pub mod error;
 mod hash;
 mod interval;
+mod invariants;
+mod key;
+mod locality;
 mod migration;
 mod node;
+mod placement;
+mod range;
+mod registry;
 mod replication;
 mod sharding;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+mod validation;
 
-pub use {
-    builder::KeyspaceBuilder,
-    error::*,
-    hash::DefaultHasher,
-    interval::{Interval, KeyRange},
-    migration::MigrationPlan,
-    node::{KeyspaceNode, NodeRef},
-    replication::{DefaultReplicationStrategy, ReplicationStrategy},
-};
 use {
+    availability::Availability,
+    builder::Options,
+    key::StrBytes,
     node::Nodes,
+    replication::{RangeReplication, Replication},
     sharding::{ShardIdx, Shards},
     std::{
+        collections::HashMap,
         hash::{BuildHasher, BuildHasherDefault, Hash},
+        iter,
         sync::Arc,
     },
 };
+pub use {
+    batch::{BatchGroup, BatchGrouping, RoutedBatch},
+    builder::KeyspaceBuilder,
+    consistency::{ConsistencyLevel, ResponseTracker},
+    erasure::{ErasureCoding, Reconstruction},
+    error::*,
+    hash::DefaultHasher,
+    interval::{Interval, KeyRange},
+    key::{HashTag, KeyExtractor, KeyspaceKey, WholeKey},
+    locality::{Locality, Proximity},
+    migration::{MigrationPlan, RoleChange},
+    node::{KeyspaceNode, NodeRef},
+    placement::{Hrw, JumpHash, Maglev, Placement, PlacementAlgorithm, Ring},
+    range::{IntegerEncoder, KeyEncoder, PrefixEncoder, RangeKeyspace},
+    registry::{KeyspaceRegistry, TableConfig},
+    replication::{
+        ContextualReplicationStrategy,
+        DYNAMIC_RF,
+        DefaultReplicationStrategy,
+        FailureDomainReplicationStrategy,
+        PlacementCounts,
+        ReplicaContext,
+        ReplicaRole,
+        ReplicaRoles,
+        ReplicationStrategy,
+    },
+    sharding::MAX_SPLIT_BITS,
+};
 
 /// Position of a key in the keyspace.
 pub type KeyPosition = u64;
@@ -54,20 +93,26 @@ pub struct Keyspace<
     H = BuildHasherDefault<DefaultHasher>,
 > where
     N: KeyspaceNode,
-    R: ReplicationStrategy<N>,
+    R: ContextualReplicationStrategy<N>,
     H: BuildHasher,
 {
     nodes: Arc<Nodes<N>>,
     shards: Shards<N, RF>,
-    replication_strategy: R,
+    replication: Replication<R>,
     build_hasher: H,
+    key_extractor: Option<Arc<dyn KeyExtractor>>,
+    placement: Arc<dyn PlacementAlgorithm<N>>,
+    erasure_coding: Option<ErasureCoding>,
+    replica_roles: Option<ReplicaRoles>,
+    primary_balancing: bool,
+    availability: Availability<N>,
     version: u64,
 }
 
 impl<N, R, const RF: usize, H> Keyspace<N, R, RF, H>
 where
     N: KeyspaceNode,
-    R: ReplicationStrategy<N>,
+    R: ContextualReplicationStrategy<N>,
     H: BuildHasher,
 {
     /// Create new keyspace.
@@ -79,30 +124,304 @@ where
         build_hasher: H,
         init_nodes: I,
         replication_strategy: R,
+        options: Options<N>,
+    ) -> KeyspaceResult<Self> {
+        let replication =
+            Replication::new(replication_strategy, options.replication_factor::<RF>()?);
+        Self::with_nodes(
+            Nodes::from_iter(init_nodes),
+            build_hasher,
+            replication,
+            options,
+        )
+    }
+
+    /// Create new keyspace over the given nodes, which might be shared with
+    /// other keyspaces.
+    fn with_nodes(
+        nodes: Nodes<N>,
+        build_hasher: H,
+        replication: Replication<R>,
+        options: Options<N>,
     ) -> KeyspaceResult<Self> {
-        let nodes = Nodes::from_iter(init_nodes);
-        let shards = Shards::new(&nodes, replication_strategy.clone())?;
+        Self::check_replication_factor(
+            replication.factor,
+            options.erasure_coding,
+            options.replica_roles,
+        )?;
+
+        let mut shards = Shards::new(&nodes, &*options.placement, &replication)?;
+        if options.primary_balancing && options.erasure_coding.is_none() {
+            shards.balance_primaries(&nodes, options.replica_roles, None);
+        }
         Ok(Self {
             nodes: Arc::new(nodes),
             shards,
-            replication_strategy,
+            replication,
             build_hasher,
+            key_extractor: options.key_extractor,
+            placement: options.placement,
+            erasure_coding: options.erasure_coding,
+            replica_roles: options.replica_roles,
+            primary_balancing: options.primary_balancing,
+            availability: Availability::default(),
             version: 0,
         })
     }
 
     /// Add a node to the keyspace.
     ///
-    /// The node will claim one or more intervals of the keyspace.
+    /// The node will claim one or more intervals of the keyspace. If the
+    /// replica sets cannot be filled, the keyspace is left intact.
     pub fn add_node(&mut self, node: N) -> KeyspaceResult<MigrationPlan<N>> {
-        self.nodes.insert(node);
-        self.migration_plan()
+        self.update_nodes(|nodes| {
+            nodes.insert(node);
+        })
     }
 
     /// Remove a node from the keyspace.
+    ///
+    /// If the replica sets cannot be filled without the node, the keyspace is
+    /// left intact.
     pub fn remove_node(&mut self, node_id: &N::Id) -> KeyspaceResult<MigrationPlan<N>> {
-        self.nodes.remove(node_id);
-        self.migration_plan()
+        self.update_nodes(|nodes| {
+            nodes.remove(node_id);
+        })
+    }
+
+    /// Change the replication strategy of the keyspace.
+    ///
+    /// Placement is recomputed under the new strategy, and the returned plan
+    /// moves the data accordingly. If the new strategy cannot fill the
+    /// replica sets, the keyspace is left intact.
+    pub fn set_replication_strategy(
+        &mut self,
+        replication_strategy: R,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        self.replan(Replication {
+            strategy: replication_strategy,
+            ..self.replication.clone()
+        })
+    }
+
+    /// Copy of the keyspace, with a replication strategy of a different type
+    /// (e.g. moving from any node to zone-aware placement).
+    ///
+    /// Returns the new keyspace, along with the plan of migrating the data
+    /// from this keyspace to it. The new keyspace is one version ahead, and
+    /// has its own copy of the nodes. This keyspace is not modified, so that
+    /// it can keep serving requests until the migration is complete.
+    ///
+    /// Replication factors assigned to ranges of the keyspace are kept, while
+    /// the strategies assigned to them are dropped, in favour of the new one.
+    pub fn with_replication_strategy<S>(
+        &self,
+        replication_strategy: S,
+    ) -> KeyspaceResult<(Keyspace<N, S, RF, H>, MigrationPlan<N>)>
+    where
+        S: ContextualReplicationStrategy<N>,
+        H: Clone,
+    {
+        let replication = Replication {
+            strategy: replication_strategy,
+            factor: self.replication.factor,
+            ranges: self
+                .replication
+                .ranges
+                .iter()
+                .map(|range| RangeReplication {
+                    key_range: range.key_range,
+                    factor: range.factor,
+                    strategy: None,
+                })
+                .collect(),
+        };
+        let nodes = self.nodes.snapshot();
+        let mut shards = Shards::with_splits(
+            &nodes,
+            &*self.placement,
+            &replication,
+            &self.shards.splits(),
+        )?;
+        self.arrange(&mut shards, &nodes);
+        let version = self.version + 1;
+        let plan = self.plan(version, &self.shards, &shards, &nodes)?;
+        let mut keyspace = Keyspace {
+            nodes: Arc::new(nodes),
+            shards,
+            replication,
+            build_hasher: self.build_hasher.clone(),
+            key_extractor: self.key_extractor.clone(),
+            placement: self.placement.clone(),
+            erasure_coding: self.erasure_coding,
+            replica_roles: self.replica_roles,
+            primary_balancing: self.primary_balancing,
+            availability: self.availability.down_only(),
+            version,
+        };
+        keyspace.refresh_availability();
+        Ok((keyspace, plan))
+    }
+
+    /// Mark the node as down, e.g. while it restarts.
+    ///
+    /// Unlike [`Keyspace::remove_node`], the placement (and the version) of
+    /// the keyspace is not changed, so no data is migrated. Instead, requests
+    /// routed with [`Keyspace::available_replicas`] skip the node, and are
+    /// served by its substitutes, which keep hints to replay to the node once
+    /// it is up again (see [`Keyspace::mark_up`]).
+    ///
+    /// Returns `false` if there is no such node, or it is already down.
+    pub fn mark_down(&mut self, node_id: &N::Id) -> bool {
+        if !self.nodes.contains(node_id) || !self.availability.mark_down(node_id) {
+            return false;
+        }
+        self.refresh_availability();
+        true
+    }
+
+    /// Mark the node, that was marked down, as up again.
+    ///
+    /// Returns the hints to replay to the node (see [`Keyspace::hints`]), or
+    /// `None` if the node was not down.
+    pub fn mark_up(&mut self, node_id: &N::Id) -> Option<Vec<Interval<N>>> {
+        let hints = self.availability.mark_up(node_id)?;
+        self.refresh_availability();
+        Some(hints)
+    }
+
+    /// Checks whether the node is marked down.
+    pub fn is_down(&self, node_id: &N::Id) -> bool {
+        self.availability.is_down(node_id)
+    }
+
+    /// Hints kept for the node while it is down, or `None` if it is up.
+    ///
+    /// Each interval is a key range of a shard the node is a replica of,
+    /// along with the nodes that substituted it there (with the role of the
+    /// node), and so may have received writes destined to it. Substitutes
+    /// of a shard change when other nodes go down or up, or the placement
+    /// changes, in which case all of them are listed.
+    pub fn hints(&self, node_id: &N::Id) -> Option<Vec<Interval<N>>> {
+        self.availability.hints(node_id)
+    }
+
+    /// Returns the nodes responsible for the given key, skipping the ones
+    /// that are down, in favour of their substitutes (see
+    /// [`Keyspace::mark_down`]).
+    ///
+    /// Nodes are returned in the same order as by [`Keyspace::replicas`], as
+    /// `(node, substituted)` tuples, where `substituted` is the replica that
+    /// is down, if the node stands in for it. Substitute is the next
+    /// candidate of the placement algorithm, accepted by the replication
+    /// strategy along with the rest of the replica set. Replicas that are
+    /// down, and cannot be substituted, are omitted.
+    pub fn available_replicas<K: Hash>(
+        &self,
+        key: &K,
+    ) -> impl Iterator<Item = (NodeRef<N>, Option<NodeRef<N>>)> {
+        self.available_replicas_for_position(self.position_of(key))
+    }
+
+    /// Returns the nodes responsible for the given key position, skipping
+    /// the ones that are down, in favour of their substitutes.
+    ///
+    /// See [`Keyspace::available_replicas`].
+    pub fn available_replicas_for_position(
+        &self,
+        pos: KeyPosition,
+    ) -> impl Iterator<Item = (NodeRef<N>, Option<NodeRef<N>>)> {
+        let shard = self.shards.shard(pos);
+        let substitutes = self.availability.substitutes(shard.key_range());
+        shard
+            .replica_set()
+            .iter()
+            .enumerate()
+            .filter_map(move |(rank, node)| {
+                if !self.availability.is_down(node.id()) {
+                    return Some((node.clone(), None));
+                }
+                substitutes
+                    .iter()
+                    .find(|(substituted, _)| *substituted == rank)
+                    .map(|(_, substitute)| (substitute.clone(), Some(node.clone())))
+            })
+    }
+
+    /// Tracker of a write request for the given key, at the given
+    /// consistency level.
+    ///
+    /// Replicas are contacted in the same order as returned by
+    /// [`Keyspace::replicas_with_roles`]. Replicas marked down (see
+    /// [`Keyspace::mark_down`]) are contacted through their substitutes
+    /// instead, whose responses count for them, as the substitutes keep the
+    /// writes for the replicas (see [`Keyspace::available_replicas`]). If too
+    /// many of them are down to satisfy the level,
+    /// [`KeyspaceError::InsufficientReplicas`] is returned. To contact the
+    /// replicas in a different order, e.g. nearest first, see
+    /// [`ResponseTracker::new`].
+    pub fn consistency<K: Hash>(
+        &self,
+        key: &K,
+        level: ConsistencyLevel,
+    ) -> KeyspaceResult<ResponseTracker<N>> {
+        self.consistency_for_position(self.position_of(key), level)
+    }
+
+    /// Tracker of a request for the given key position, at the given
+    /// consistency level.
+    ///
+    /// See [`Keyspace::consistency`].
+    pub fn consistency_for_position(
+        &self,
+        pos: KeyPosition,
+        level: ConsistencyLevel,
+    ) -> KeyspaceResult<ResponseTracker<N>> {
+        let shard = self.shards.shard(pos);
+        let substitutes = self.availability.substitutes(shard.key_range());
+        let replicas = shard
+            .replica_set()
+            .with_roles(self.replica_roles)
+            .enumerate()
+            .map(|(rank, (node, role))| {
+                let substitute = substitutes
+                    .iter()
+                    .find(|(substituted, _)| *substituted == rank)
+                    .map(|(_, substitute)| substitute.clone());
+                (node, role, substitute)
+            });
+        ResponseTracker::with_substitutes(replicas, level, |id| self.availability.is_down(id))
+    }
+
+    /// Tracker of a read request for the given key, at the given consistency
+    /// level.
+    ///
+    /// Same as [`Keyspace::consistency`], except that witnesses (see
+    /// [`ReplicaRole::Witness`]) store no data, so they are neither
+    /// contacted, nor count towards the level, while learners count towards
+    /// [`ConsistencyLevel::One`] (see [`ResponseTracker::for_reads`]).
+    /// Substitutes of the replicas that are down only keep the writes the
+    /// replicas missed, not their data, so they are not contacted.
+    pub fn read_consistency<K: Hash>(
+        &self,
+        key: &K,
+        level: ConsistencyLevel,
+    ) -> KeyspaceResult<ResponseTracker<N>> {
+        self.read_consistency_for_position(self.position_of(key), level)
+    }
+
+    /// Tracker of a read request for the given key position, at the given
+    /// consistency level.
+    ///
+    /// See [`Keyspace::read_consistency`].
+    pub fn read_consistency_for_position(
+        &self,
+        pos: KeyPosition,
+        level: ConsistencyLevel,
+    ) -> KeyspaceResult<ResponseTracker<N>> {
+        ResponseTracker::for_reads(self.replicas_with_roles_for_position(pos), level, |id| {
+            self.availability.is_down(id)
+        })
     }
 
     /// Returns replication factor (`RF`) number of nodes responsible for the
@@ -110,12 +429,413 @@ where
     ///
     /// The first node is assumed to be the primary node.
     pub fn replicas<K: Hash>(&self, key: &K) -> impl Iterator<Item = NodeRef<N>> {
-        let key_position = self.build_hasher.hash_one(key);
-        let shard_idx = ShardIdx::from_position(key_position);
-        let replica_set = self.shards.replica_set(shard_idx);
+        self.replicas_for_position(self.position_of(key))
+    }
+
+    /// Returns the primary node responsible for the given key.
+    ///
+    /// Unlike [`Keyspace::replicas`], the node is borrowed from the keyspace,
+    /// so no reference counts are updated on the hot path.
+    pub fn primary<K: Hash>(&self, key: &K) -> &N {
+        self.shards.replica_set(self.position_of(key))[0].inner()
+    }
+
+    /// Returns replication factor (`RF`) number of nodes responsible for the
+    /// given key, borrowed from the keyspace.
+    ///
+    /// The first node is assumed to be the primary node.
+    pub fn replicas_ref<K: Hash>(&self, key: &K) -> impl Iterator<Item = &N> {
+        self.shards
+            .replica_set(self.position_of(key))
+            .iter()
+            .map(NodeRef::inner)
+    }
+
+    /// Routes a batch of keys, grouping them by nodes responsible for them.
+    ///
+    /// This is more efficient than calling [`Keyspace::replicas`] for each
+    /// key, as node references are cloned once per group, not once per key.
+    /// Groups refer to the keys by their index in the batch.
+    pub fn route_batch<K: Hash>(&self, keys: &[K], grouping: BatchGrouping) -> RoutedBatch<N> {
+        RoutedBatch::new(
+            &self.shards,
+            keys.iter().map(|key| self.position_of(key)),
+            grouping,
+        )
+    }
+
+    /// Returns position of the given key in the keyspace.
+    ///
+    /// Position is obtained by hashing the key with the keyspace's build
+    /// hasher, so it can be stored alongside the key, and used for routing
+    /// later on (see [`Keyspace::replicas_for_position`]).
+    ///
+    ///
+    /// Key extractor (see [`KeyspaceBuilder::with_key_extractor`]) is not
+    /// applied, as it only makes sense for keys routed by their bytes (see
+    /// [`Keyspace::position_of_key`]).
+    pub fn position_of<K: Hash + ?Sized>(&self, key: &K) -> KeyPosition {
+        self.build_hasher.hash_one(key)
+    }
+
+    /// Returns replication factor (`RF`) number of nodes responsible for the
+    /// given key, taking key extraction into account.
+    ///
+    /// Only the portion of the key returned by the keyspace's key extractor
+    /// (see [`KeyspaceBuilder::with_key_extractor`]) determines the position
+    /// of the key. So, with [`HashTag`] extractor, `{user:42}:profile` and
+    /// `{user:42}:cart` keys are placed on the same replica set.
+    ///
+    /// String keys are routed to the same nodes as by [`Keyspace::replicas`].
+    pub fn replicas_for_key<K>(&self, key: &K) -> impl Iterator<Item = NodeRef<N>>
+    where
+        K: KeyspaceKey + ?Sized,
+    {
+        self.replicas_for_position(self.position_of_key(key))
+    }
+
+    /// Returns position of the given key in the keyspace, taking key
+    /// extraction into account.
+    ///
+    /// See [`Keyspace::replicas_for_key`].
+    pub fn position_of_key<K>(&self, key: &K) -> KeyPosition
+    where
+        K: KeyspaceKey + ?Sized,
+    {
+        let bytes = key.key_bytes();
+        let bytes = match &self.key_extractor {
+            Some(key_extractor) => key_extractor.extract(bytes),
+            None => bytes,
+        };
+        // Key bytes are hashed the same way as strings are, so that routing of
+        // string keys does not depend on the API used.
+        self.position_of(&StrBytes(bytes))
+    }
+
+    /// Returns the primary node responsible for the given key, taking key
+    /// extraction into account.
+    ///
+    /// See [`Keyspace::primary`] and [`Keyspace::replicas_for_key`].
+    pub fn primary_for_key<K>(&self, key: &K) -> &N
+    where
+        K: KeyspaceKey + ?Sized,
+    {
+        self.shards.replica_set(self.position_of_key(key))[0].inner()
+    }
+
+    /// Routes a batch of keys, taking key extraction into account.
+    ///
+    /// See [`Keyspace::route_batch`] and [`Keyspace::replicas_for_key`].
+    pub fn route_key_batch<K>(&self, keys: &[K], grouping: BatchGrouping) -> RoutedBatch<N>
+    where
+        K: KeyspaceKey,
+    {
+        RoutedBatch::new(
+            &self.shards,
+            keys.iter().map(|key| self.position_of_key(key)),
+            grouping,
+        )
+    }
+
+    /// Returns replication factor (`RF`) number of nodes responsible for the
+    /// given key position.
+    ///
+    /// The first node is assumed to be the primary node.
+    pub fn replicas_for_position(&self, pos: KeyPosition) -> impl Iterator<Item = NodeRef<N>> {
+        let replica_set = self.shards.replica_set(pos);
         replica_set.iter().map(Clone::clone)
     }
 
+    /// Returns the range of keys of the shard containing the given key
+    /// position.
+    ///
+    /// If the shard is split, the range of the sub-shard is returned.
+    pub fn shard_of(&self, pos: KeyPosition) -> KeyRange {
+        self.shards.shard(pos).key_range()
+    }
+
+    /// Intervals of the keyspace overlapping the given key range.
+    ///
+    /// Each interval is a segment of a shard that falls within the key range,
+    /// along with the replica set controlling it. Intervals are returned in
+    /// the order of their key ranges, and together cover the key range
+    /// exactly, so that a range scan can be fanned out to responsible nodes.
+    pub fn replicas_for_range(&self, key_range: KeyRange) -> impl Iterator<Item = Interval<N>> {
+        let mut next = Some(key_range.start());
+        iter::from_fn(move || {
+            let pos = next.filter(|pos| key_range.contains(*pos))?;
+            let shard = self.shards.shard(pos);
+            next = shard.key_range().end();
+
+            let end = match (next, key_range.end()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            Some(Interval::new(
+                KeyRange::new(pos, end),
+                shard.replica_set().with_roles(self.replica_roles),
+            ))
+        })
+    }
+
+    /// Split the shard containing the given key position into `2^bits`
+    /// sub-shards.
+    ///
+    /// Each sub-shard gets its own replica set, so that the load of a hot
+    /// shard is spread over more nodes. Splitting an already split shard
+    /// changes its granularity. At most [`MAX_SPLIT_BITS`] bits are allowed.
+    pub fn split_shard(&mut self, pos: KeyPosition, bits: u8) -> KeyspaceResult<MigrationPlan<N>> {
+        if bits == 0 {
+            return Err(KeyspaceError::InvalidSplitBits(bits));
+        }
+        self.resplit(ShardIdx::from_position(pos), bits)
+    }
+
+    /// Merge the sub-shards of the shard containing the given key position
+    /// back into a single shard.
+    pub fn merge_shard(&mut self, pos: KeyPosition) -> KeyspaceResult<MigrationPlan<N>> {
+        let idx = ShardIdx::from_position(pos);
+        if !self.shards.splits().contains_key(&idx) {
+            return Err(KeyspaceError::ShardNotSplit);
+        }
+        self.resplit(idx, 0)
+    }
+
+    /// Shards that are split into sub-shards.
+    ///
+    /// Returned as `(key range, bits)` tuples, where key range is the range of
+    /// the whole shard, and the shard is split into `2^bits` sub-shards.
+    pub fn splits(&self) -> impl Iterator<Item = (KeyRange, u8)> {
+        self.shards
+            .splits()
+            .into_iter()
+            .map(|(idx, bits)| (idx.key_range(), bits))
+    }
+
+    /// Shards (or sub-shards) running degraded, i.e. with replica sets that
+    /// do not satisfy the replication strategy, and were filled using its
+    /// fallback tiers (see [`ReplicationStrategy::fallback`]).
+    ///
+    /// Fallbacks forced by the topology of the nodes (see
+    /// [`ReplicationStrategy::forced_fallbacks`]) do not degrade the shard.
+    ///
+    /// Returned as `(key range, tier)` tuples, where tier is the number of
+    /// fallbacks that were needed to fill the replica set.
+    pub fn degraded_shards(&self) -> impl Iterator<Item = (KeyRange, usize)> {
+        self.shards.iter().filter_map(|shard| {
+            let replica_set = shard.replica_set();
+            replica_set
+                .is_degraded()
+                .then_some((shard.key_range(), replica_set.fallback_tier()))
+        })
+    }
+
+    /// Checks whether replica sets of all the shards can be filled from the
+    /// current nodes.
+    ///
+    /// Unlike [`Keyspace::add_node`] and friends, which stop at the first
+    /// shard that cannot be filled, all the shards are checked, and
+    /// [`KeyspaceError::InfeasiblePlacement`] error reports every unfilled
+    /// shard, along with the nodes rejected by the replication strategy.
+    pub fn validate(&self) -> KeyspaceResult<()>
+    where
+        N::Id: Send + Sync + 'static,
+    {
+        validation::validate::<N, R, RF>(
+            self.nodes.values(),
+            &*self.placement,
+            &self.replication,
+            &self.shards.splits(),
+        )
+    }
+
+    /// Finds the spare nodes that need to be added to the keyspace, for
+    /// replica sets of all the shards to be filled.
+    ///
+    /// Returns ids of a minimal subset of the spares (no spare can be dropped
+    /// from it), which is empty if the placement is feasible as is. If even
+    /// all the spares together are not enough,
+    /// [`KeyspaceError::InfeasiblePlacement`] is returned, reporting the
+    /// placement with all of them added.
+    ///
+    /// The keyspace itself is not modified.
+    pub fn validate_with_spares<I>(&self, spares: I) -> KeyspaceResult<Vec<N::Id>>
+    where
+        N::Id: Send + Sync + 'static,
+        I: IntoIterator<Item = N>,
+    {
+        validation::minimal_spares::<N, R, RF>(
+            self.nodes.values(),
+            spares.into_iter().map(NodeRef::new).collect(),
+            &*self.placement,
+            &self.replication,
+            &self.shards.splits(),
+        )
+    }
+
+    /// Checks the consistency of the keyspace state, e.g. for health checks.
+    ///
+    /// Verifies that the shards tile the whole keyspace, with no gaps or
+    /// overlaps, and that every replica set consists of the required number
+    /// of distinct nodes, all of which are in the keyspace, filled with a
+    /// fallback tier the replication strategy has (see
+    /// [`Keyspace::degraded_shards`]). Eligibility of the nodes is not
+    /// re-checked, as it depends on the context replica sets were selected
+    /// in (see [`ReplicaContext`]). The first violation found is returned as
+    /// [`KeyspaceError::InvariantViolation`].
+    pub fn check_invariants(&self) -> KeyspaceResult<()> {
+        invariants::check_shards(&self.shards, &self.nodes, &self.replication)
+    }
+
+    /// Checks that the migration plan of the latest change of the keyspace
+    /// moves the data from the previous layout of the keyspace to the current
+    /// one.
+    ///
+    /// Previous layout is the one returned by [`Keyspace::iter`] before the
+    /// change. Applying the plan to it (pulling the intervals, rebuilding the
+    /// fragments, and cleaning up) must yield exactly the current replicas
+    /// storing the data, otherwise [`KeyspaceError::InvariantViolation`] is
+    /// returned.
+    pub fn check_migration<I>(&self, previous: I, plan: &MigrationPlan<N>) -> KeyspaceResult<()>
+    where
+        I: IntoIterator<Item = (KeyRange, NodeRef<N>)>,
+    {
+        if plan.version() != self.version {
+            return Err(KeyspaceError::InvariantViolation(format!(
+                "plan version {} does not match keyspace version {}",
+                plan.version(),
+                self.version
+            )));
+        }
+        invariants::check_migration(
+            previous.into_iter().collect(),
+            &self.shards,
+            plan,
+            self.replica_roles,
+            self.erasure_coding.is_some(),
+        )
+    }
+
+    /// Replication factor, i.e. the number of nodes in each replica set.
+    ///
+    /// Equals to `RF`, unless the replication factor is set at runtime (see
+    /// [`DYNAMIC_RF`]). Ranges of the keyspace may use a different replication
+    /// factor (see [`Keyspace::set_range_replication`]).
+    pub fn replication_factor(&self) -> usize {
+        self.replication.factor
+    }
+
+    /// Replication factor of the shard containing the given key position.
+    pub fn replication_factor_of(&self, pos: KeyPosition) -> usize {
+        self.shards.replica_set(pos).len()
+    }
+
+    /// Erasure coding scheme of the keyspace, if its shards are erasure coded
+    /// (see [`KeyspaceBuilder::with_erasure_coding`]).
+    pub fn erasure_coding(&self) -> Option<ErasureCoding> {
+        self.erasure_coding
+    }
+
+    /// Number of replicas of each role, if replica roles are configured (see
+    /// [`KeyspaceBuilder::with_replica_roles`]).
+    pub fn replica_roles(&self) -> Option<ReplicaRoles> {
+        self.replica_roles
+    }
+
+    /// Returns the nodes responsible for the given key, along with their
+    /// roles.
+    ///
+    /// Nodes are returned in the same order as by [`Keyspace::replicas`], and
+    /// are all voters, unless replica roles are configured.
+    pub fn replicas_with_roles<K: Hash>(
+        &self,
+        key: &K,
+    ) -> impl Iterator<Item = (NodeRef<N>, ReplicaRole)> {
+        self.replicas_with_roles_for_position(self.position_of(key))
+    }
+
+    /// Returns the nodes responsible for the given key position, along with
+    /// their roles.
+    ///
+    /// See [`Keyspace::replicas_with_roles`].
+    pub fn replicas_with_roles_for_position(
+        &self,
+        pos: KeyPosition,
+    ) -> impl Iterator<Item = (NodeRef<N>, ReplicaRole)> {
+        self.shards.replica_set(pos).with_roles(self.replica_roles)
+    }
+
+    /// Returns the nodes storing the data of the given key, ordered for reads
+    /// by the caller: nearest first.
+    ///
+    /// Ties between equally near nodes are broken deterministically, by the
+    /// caller's seed (see [`Proximity::seed`]) and the shard of the key, so
+    /// that reads of different callers and shards are spread over the nodes.
+    /// Witnesses (see [`ReplicaRole::Witness`]) store no data, and are
+    /// omitted.
+    pub fn read_replicas<K: Hash, P: Proximity<N>>(
+        &self,
+        key: &K,
+        caller: &P,
+    ) -> impl Iterator<Item = NodeRef<N>> {
+        self.read_replicas_for_position(self.position_of(key), caller)
+    }
+
+    /// Returns the nodes storing the data of the given key position, ordered
+    /// for reads by the caller.
+    ///
+    /// See [`Keyspace::read_replicas`].
+    pub fn read_replicas_for_position<P: Proximity<N>>(
+        &self,
+        pos: KeyPosition,
+        caller: &P,
+    ) -> impl Iterator<Item = NodeRef<N>> {
+        let start = self.shards.shard(pos).key_range().start();
+        locality::order(self.replicas_with_roles_for_position(pos), caller, start).into_iter()
+    }
+
+    /// Returns the nodes storing fragments of the given key position, as
+    /// `(fragment index, node)` tuples, in erasure coded keyspaces.
+    ///
+    /// Fragment index is the position of the node in the replica set, so
+    /// for replicated keyspaces, these are the replicas along with their
+    /// ranks.
+    pub fn fragments_for_position(
+        &self,
+        pos: KeyPosition,
+    ) -> impl Iterator<Item = (usize, NodeRef<N>)> {
+        self.replicas_for_position(pos).enumerate()
+    }
+
+    /// Imbalance of primaries, i.e. the largest ratio of the number of
+    /// replica sets a node is primary of, to its fair share (proportional to
+    /// its capacity) among the nodes of the keyspace.
+    ///
+    /// Equals to `1.0` when primaries are perfectly balanced, and e.g. `1.5`
+    /// when some node is primary of 50% more replica sets than it should be.
+    /// See [`KeyspaceBuilder::with_primary_balancing`].
+    pub fn primary_imbalance(&self) -> f64 {
+        let nodes = self.nodes.values();
+        let total_capacity = nodes.iter().map(|node| node.capacity()).sum::<usize>();
+        let mut primaries = HashMap::new();
+        let mut replica_sets = 0;
+        for shard in self.shards.iter() {
+            *primaries
+                .entry(shard.replica_set()[0].id().clone())
+                .or_insert(0usize) += 1;
+            replica_sets += 1;
+        }
+
+        nodes
+            .iter()
+            .filter(|node| node.capacity() > 0)
+            .map(|node| {
+                let share = replica_sets as f64 * node.capacity() as f64 / total_capacity as f64;
+                primaries.get(node.id()).copied().unwrap_or(0) as f64 / share
+            })
+            .fold(0.0, f64::max)
+    }
+
     /// Keyspace version.
     ///
     /// Version is incremented each time the keyspace is modified.
@@ -152,16 +872,222 @@ where
         })
     }
 
-    fn migration_plan(&mut self) -> KeyspaceResult<MigrationPlan<N>> {
-        // Recalculate the shards.
-        let old_shards = self.shards.clone();
-        self.shards = Shards::new(&self.nodes, self.replication_strategy.clone())?;
+    /// Applies the update to a copy of the nodes, and recalculates the shards
+    /// over it. Nodes are only updated if the shards can be filled, so that a
+    /// failed update leaves the keyspace intact.
+    fn update_nodes<F>(&mut self, update: F) -> KeyspaceResult<MigrationPlan<N>>
+    where
+        F: FnOnce(&Nodes<N>),
+    {
+        let nodes = self.nodes.snapshot();
+        update(&nodes);
+        let shards = Shards::with_splits(
+            &nodes,
+            &*self.placement,
+            &self.replication,
+            &self.shards.splits(),
+        )?;
+        self.nodes.assign(&nodes);
+        self.update_shards(shards)
+    }
+
+    /// Recalculates the shards with the given replication settings, which
+    /// are only stored if the shards can be filled.
+    fn replan(&mut self, replication: Replication<R>) -> KeyspaceResult<MigrationPlan<N>> {
+        let shards = Shards::with_splits(
+            &self.nodes,
+            &*self.placement,
+            &replication,
+            &self.shards.splits(),
+        )?;
+        self.replication = replication;
+        self.update_shards(shards)
+    }
+
+    fn resplit(&mut self, idx: ShardIdx, bits: u8) -> KeyspaceResult<MigrationPlan<N>> {
+        let shards =
+            self.shards
+                .split(&self.nodes, &*self.placement, &self.replication, idx, bits)?;
+        self.update_shards(shards)
+    }
+
+    fn update_shards(&mut self, mut shards: Shards<N, RF>) -> KeyspaceResult<MigrationPlan<N>> {
+        self.arrange(&mut shards, &self.nodes);
+        let old_shards = std::mem::replace(&mut self.shards, shards);
+        self.refresh_availability();
 
         // Calculate migration plan from updated shards.
         let new_version = self.version + 1;
-        MigrationPlan::new(new_version, &old_shards, &self.shards).and_then(|plan| {
-            self.version = new_version;
-            Ok(plan)
+        self.plan(new_version, &old_shards, &self.shards, &self.nodes)
+            .inspect(|_| self.version = new_version)
+    }
+
+    /// Recomputes the substitutes of the replicas that are down, recording
+    /// the hints they keep. Nodes no longer in the keyspace are forgotten.
+    fn refresh_availability(&mut self) {
+        let nodes = &self.nodes;
+        self.availability.retain(|id| nodes.contains(id));
+        let substitutes = if self.availability.has_down() {
+            self.shards
+                .substitutes(&self.nodes, &*self.placement, &self.replication, |id| {
+                    self.availability.is_down(id)
+                })
+        } else {
+            Vec::new()
+        };
+        self.availability
+            .update(&self.shards, substitutes, self.replica_roles);
+    }
+
+    /// Reorders the nodes of the replica sets that are about to replace the
+    /// current ones.
+    ///
+    /// Nodes of erasure coded shards keep their fragments, otherwise
+    /// primaries are balanced, if enabled.
+    fn arrange(&self, shards: &mut Shards<N, RF>, nodes: &Nodes<N>) {
+        if self.erasure_coding.is_some() {
+            shards.align(&self.shards);
+        } else if self.primary_balancing {
+            shards.balance_primaries(nodes, self.replica_roles, Some(&self.shards));
+        }
+    }
+
+    /// Migration plan from the old shards to the new ones, where `nodes` are
+    /// the nodes of the new keyspace.
+    fn plan(
+        &self,
+        version: u64,
+        old_shards: &Shards<N, RF>,
+        new_shards: &Shards<N, RF>,
+        nodes: &Nodes<N>,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        match self.erasure_coding {
+            Some(erasure_coding) => MigrationPlan::with_erasure_coding(
+                version,
+                old_shards,
+                new_shards,
+                erasure_coding,
+                |id| nodes.contains(id),
+            ),
+            None => MigrationPlan::new(
+                version,
+                old_shards,
+                new_shards,
+                self.replica_roles,
+                self.primary_balancing,
+            ),
+        }
+    }
+
+    /// Checks that the replication factor is positive, and matches the
+    /// number of fragments in erasure coded keyspaces, and the number of
+    /// replicas in keyspaces with replica roles.
+    fn check_replication_factor(
+        replication_factor: usize,
+        erasure_coding: Option<ErasureCoding>,
+        replica_roles: Option<ReplicaRoles>,
+    ) -> KeyspaceResult<()> {
+        let mismatch = |expected: Option<usize>| expected.is_some_and(|n| n != replication_factor);
+        if replication_factor == 0
+            || mismatch(erasure_coding.map(|coding| coding.fragments()))
+            || mismatch(replica_roles.map(|roles| roles.total()))
+        {
+            return Err(KeyspaceError::InvalidReplicationFactor(replication_factor));
+        }
+        Ok(())
+    }
+}
+
+impl<N, R, H> Keyspace<N, R, DYNAMIC_RF, H>
+where
+    N: KeyspaceNode,
+    R: ContextualReplicationStrategy<N>,
+    H: BuildHasher,
+{
+    /// Change the replication factor of the keyspace.
+    ///
+    /// When the replication factor grows, the returned plan pulls the data to
+    /// the new replicas. When it shrinks, nodes leaving replica sets are
+    /// listed in the plan's cleanup intervals (see
+    /// [`MigrationPlan::cleanup_intervals`]). If the replica sets cannot be
+    /// filled, the keyspace is left intact.
+    ///
+    /// Ranges with their own replication factor (see
+    /// [`Keyspace::set_range_replication`]) are not affected. Replication
+    /// factor of erasure coded keyspaces, and of keyspaces with replica roles,
+    /// is fixed to the number of fragments (replicas).
+    pub fn set_replication_factor(
+        &mut self,
+        replication_factor: usize,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        Self::check_replication_factor(
+            replication_factor,
+            self.erasure_coding,
+            self.replica_roles,
+        )?;
+        self.replan(Replication {
+            factor: replication_factor,
+            ..self.replication.clone()
         })
     }
+
+    /// Assign replication factor, and optionally a replication strategy, to
+    /// the given range of the keyspace (e.g. more replicas for metadata, and
+    /// fewer for cache-like data).
+    ///
+    /// Settings are applied at the granularity of shards: each shard (or
+    /// sub-shard) follows the settings of the range containing its start.
+    /// Ranges assigned later take precedence over the overlapping ones
+    /// assigned earlier, and assigning the same range again replaces its
+    /// settings.
+    ///
+    /// Returns the plan of moving the data, and leaves the keyspace intact if
+    /// the replica sets cannot be filled.
+    pub fn set_range_replication(
+        &mut self,
+        key_range: KeyRange,
+        replication_factor: usize,
+        replication_strategy: Option<R>,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        Self::check_replication_factor(
+            replication_factor,
+            self.erasure_coding,
+            self.replica_roles,
+        )?;
+        let mut replication = self.replication.clone();
+        replication
+            .ranges
+            .retain(|range| range.key_range != key_range);
+        replication.ranges.push(RangeReplication {
+            key_range,
+            factor: replication_factor,
+            strategy: replication_strategy,
+        });
+        self.replan(replication)
+    }
+
+    /// Remove the settings assigned to the given range of the keyspace (see
+    /// [`Keyspace::set_range_replication`]).
+    ///
+    /// Shards of the range fall back to the settings of the overlapping
+    /// ranges, or to the ones of the keyspace.
+    pub fn clear_range_replication(
+        &mut self,
+        key_range: KeyRange,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        let mut replication = self.replication.clone();
+        replication
+            .ranges
+            .retain(|range| range.key_range != key_range);
+        self.replan(replication)
+    }
+
+    /// Ranges of the keyspace with their own replication factor, as
+    /// `(key range, replication factor)` tuples, in the order of assignment.
+    pub fn range_replication(&self) -> impl Iterator<Item = (KeyRange, usize)> {
+        self.replication
+            .ranges
+            .iter()
+            .map(|range| (range.key_range, range.factor))
+    }
 }
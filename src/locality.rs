@@ -0,0 +1,108 @@
+use {
+    super::{KeyPosition, KeyspaceNode, NodeRef, ReplicaRole, placement},
+    std::hash::Hash,
+};
+
+/// Proximity of the nodes to the caller of a read, used to order replicas
+/// (see [`Keyspace::read_replicas`](crate::Keyspace::read_replicas)).
+///
+/// Implemented for closures returning the distance to a node, e.g. measured
+/// latency, and by [`Locality`], for callers placed in the same topology as
+/// the nodes.
+pub trait Proximity<N> {
+    /// Distance from the caller to the node, nearer nodes having smaller
+    /// distances.
+    fn distance(&self, node: &N) -> u64;
+
+    /// Seed identifying the caller, used to break ties between equally near
+    /// replicas, so that different callers prefer different replicas.
+    ///
+    /// Defaults to `0`, in which case ties are still spread over the shards,
+    /// but all the callers break them the same way.
+    fn seed(&self) -> u64 {
+        0
+    }
+}
+
+impl<N, F: Fn(&N) -> u64> Proximity<N> for F {
+    fn distance(&self, node: &N) -> u64 {
+        self(node)
+    }
+}
+
+/// Location of the caller in the topology of the nodes (see
+/// [`KeyspaceNode::topology`]).
+///
+/// Distance to a node is the number of topology levels, counting from the
+/// narrowest one, the caller does not share with the node: `0` for the same
+/// host, `1` for the same rack, and so on.
+#[derive(Debug, Clone, Default)]
+pub struct Locality {
+    topology: Vec<String>,
+    seed: u64,
+}
+
+impl Locality {
+    /// Caller with the given topology labels, ordered from the widest to the
+    /// narrowest, e.g. `["eu-west", "eu-west-1a"]` for region and zone.
+    pub fn new<I, S>(topology: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            topology: topology.into_iter().map(Into::into).collect(),
+            seed: 0,
+        }
+    }
+
+    /// Identify the caller, so that ties between equally near replicas are
+    /// broken differently by different callers (see [`Proximity::seed`]).
+    pub fn with_caller<T: Hash>(mut self, caller: &T) -> Self {
+        self.seed = placement::seed(caller);
+        self
+    }
+
+    /// Topology labels of the caller.
+    pub fn topology(&self) -> &[String] {
+        &self.topology
+    }
+}
+
+impl<N: KeyspaceNode> Proximity<N> for Locality {
+    fn distance(&self, node: &N) -> u64 {
+        let topology = node.topology();
+        let shared = self
+            .topology
+            .iter()
+            .zip(topology)
+            .take_while(|(a, b)| a == b)
+            .count();
+        (self.topology.len().max(topology.len()) - shared) as u64
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+/// Orders the replicas storing data for reads by the caller: nearest first,
+/// with ties broken by the caller's seed and the start of the key range the
+/// replicas control.
+pub(crate) fn order<N, I, P>(replicas: I, caller: &P, start: KeyPosition) -> Vec<NodeRef<N>>
+where
+    N: KeyspaceNode,
+    I: IntoIterator<Item = (NodeRef<N>, ReplicaRole)>,
+    P: Proximity<N>,
+{
+    let mut replicas = replicas
+        .into_iter()
+        .filter(|(_, role)| role.stores_data())
+        .map(|(node, _)| {
+            let tie = placement::seed(&(caller.seed(), start, node.id()));
+            (caller.distance(&node), tie, node)
+        })
+        .collect::<Vec<_>>();
+    replicas.sort_by_key(|(distance, tie, _)| (*distance, *tie));
+    replicas.into_iter().map(|(_, _, node)| node).collect()
+}
@@ -1,19 +1,73 @@
 use {
     super::{
+        ErasureCoding,
         KeyspaceError,
         KeyspaceResult,
-        interval::Interval,
+        NodeRef,
+        Reconstruction,
+        ReplicaRole,
+        ReplicaRoles,
+        interval::{Interval, KeyRange},
         node::KeyspaceNode,
+        replication::{self, ReplicaSet},
         sharding::Shards,
     },
     std::{collections::HashMap, fmt, ops::Deref},
 };
 
+/// Change of the role of a node in the replica set controlling a key range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoleChange {
+    key_range: KeyRange,
+    from: Option<ReplicaRole>,
+    to: Option<ReplicaRole>,
+    primary: (bool, bool),
+}
+
+impl RoleChange {
+    /// Range of keys of the replica set.
+    pub fn key_range(&self) -> KeyRange {
+        self.key_range
+    }
+
+    /// Role before the change, `None` if the node joins the replica set.
+    pub fn from(&self) -> Option<ReplicaRole> {
+        self.from
+    }
+
+    /// Role after the change, `None` if the node leaves the replica set.
+    pub fn to(&self) -> Option<ReplicaRole> {
+        self.to
+    }
+
+    /// Whether the node was the primary before the change.
+    pub fn was_primary(&self) -> bool {
+        self.primary.0
+    }
+
+    /// Whether the node is the primary after the change.
+    pub fn is_primary(&self) -> bool {
+        self.primary.1
+    }
+}
+
 /// Data migration plan.
 pub struct MigrationPlan<N: KeyspaceNode> {
     /// Mapping of node id to the intervals that need to be migrated to it.
     intervals: HashMap<N::Id, Vec<Interval<N>>>,
 
+    /// Mapping of node id to the key ranges it no longer controls, and can
+    /// clean up once the migration is complete.
+    cleanup: HashMap<N::Id, Vec<KeyRange>>,
+
+    /// Mapping of node id to the fragments it needs to rebuild, in erasure
+    /// coded keyspaces.
+    reconstructions: HashMap<N::Id, Vec<Reconstruction<N>>>,
+
+    /// Mapping of node id to the changes of its roles, in keyspaces with
+    /// replica roles, or with balanced primaries.
+    role_changes: HashMap<N::Id, Vec<RoleChange>>,
+
     /// Version of keyspace.
     version: u64,
 }
@@ -33,35 +87,126 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MigrationPlan")
             .field("intervals", &self.intervals)
+            .field("cleanup", &self.cleanup)
+            .field("reconstructions", &self.reconstructions)
+            .field("role_changes", &self.role_changes)
             .finish_non_exhaustive()
     }
 }
 
 impl<N: KeyspaceNode> MigrationPlan<N> {
     /// Creates a new migration plan.
+    ///
+    /// Without replica roles, all the replicas are voters. Role changes are
+    /// recorded if there are replica roles, or if `primaries` is set.
     pub(crate) fn new<const RF: usize>(
         version: u64,
         old_shards: &Shards<N, RF>,
         new_shards: &Shards<N, RF>,
+        roles: Option<ReplicaRoles>,
+        primaries: bool,
     ) -> KeyspaceResult<Self> {
-        let mut intervals = HashMap::new();
         if old_shards.len() != new_shards.len() {
             return Err(KeyspaceError::ShardCountMismatch);
         }
 
-        for (old_shard, new_shard) in old_shards.iter().zip(new_shards.iter()) {
-            let old_replica_set = old_shard.replica_set();
-            let new_replica_set = new_shard.replica_set();
-            if old_replica_set == new_replica_set {
+        // Shards might be split differently in the old and new keyspaces, so they
+        // are compared at the finest granularity of the two.
+        Ok(Self::from_segments(
+            version,
+            old_shards.zip(new_shards),
+            roles,
+            primaries,
+        ))
+    }
+
+    /// Creates a new migration plan from the keyspace segments.
+    ///
+    /// Each segment is a key range, along with the replica sets controlling it
+    /// before and after the keyspace update. Roles of the replicas follow
+    /// their ranks in the replica sets: nodes pull the data when they start
+    /// storing it, from the nodes that stored it (witnesses are never used as
+    /// sources), and clean it up when they stop storing it.
+    ///
+    /// Role changes (including changes of primaries) are recorded if there
+    /// are replica roles, or if `primaries` is set.
+    pub(crate) fn from_segments<'a, const RF: usize, I>(
+        version: u64,
+        segments: I,
+        roles: Option<ReplicaRoles>,
+        primaries: bool,
+    ) -> Self
+    where
+        N: 'a,
+        I: IntoIterator<Item = (KeyRange, &'a ReplicaSet<N, RF>, &'a ReplicaSet<N, RF>)>,
+    {
+        let role_in = |replica_set: &ReplicaSet<N, RF>, node| {
+            replica_set
+                .iter()
+                .position(|other| other == node)
+                .map(|rank| replication::role_of(roles, rank))
+        };
+        let stores_data = |role: Option<ReplicaRole>| role.is_some_and(|role| role.stores_data());
+        let track_roles = roles.is_some() || primaries;
+
+        let mut intervals = HashMap::new();
+        let mut cleanup = HashMap::new();
+        let mut role_changes = HashMap::new();
+        for (key_range, old_replica_set, new_replica_set) in segments {
+            // When roles are tracked, ranks matter, not just the nodes.
+            if old_replica_set == new_replica_set
+                && (!track_roles || old_replica_set[..] == new_replica_set[..])
+            {
                 continue;
             }
-            assert_eq!(old_shard.key_range(), new_shard.key_range());
+            let primary = (&old_replica_set[0], &new_replica_set[0]);
+            let mut record = |node: &NodeRef<N>, from, to| {
+                let primary = (primary.0 == node, primary.1 == node);
+                if track_roles && (from != to || primary.0 != primary.1) {
+                    role_changes
+                        .entry(node.id().clone())
+                        .or_insert_with(Vec::new)
+                        .push(RoleChange {
+                            key_range,
+                            from,
+                            to,
+                            primary,
+                        });
+                }
+            };
+
+            for source_node in old_replica_set.iter() {
+                let (from, to) = (
+                    role_in(old_replica_set, source_node),
+                    role_in(new_replica_set, source_node),
+                );
+
+                // Nodes that no longer store the data can drop it.
+                if stores_data(from) && !stores_data(to) {
+                    cleanup
+                        .entry(source_node.id().clone())
+                        .or_insert_with(Vec::new)
+                        .push(key_range);
+                }
+                record(source_node, from, to);
+            }
+
+            let sources = old_replica_set
+                .with_roles(roles)
+                .filter(|(_, role)| role.stores_data())
+                .collect::<Vec<_>>();
+            for target_node in new_replica_set.iter() {
+                let (from, to) = (
+                    role_in(old_replica_set, target_node),
+                    role_in(new_replica_set, target_node),
+                );
+                if from.is_none() {
+                    record(target_node, from, to);
+                }
 
-            let key_range = old_shard.key_range();
-            for target_node in new_replica_set.iter().cloned() {
-                // Ignore the nodes that are were in the old replica set.
+                // Ignore the nodes that already store the data.
                 // No need to migrate data to them.
-                if old_replica_set.contains(&target_node) {
+                if stores_data(from) || !stores_data(to) {
                     continue;
                 }
 
@@ -69,11 +214,92 @@ impl<N: KeyspaceNode> MigrationPlan<N> {
                 intervals
                     .entry(target_node.id().clone())
                     .or_insert_with(Vec::new)
-                    .push(Interval::new(key_range, old_replica_set.iter().cloned()));
+                    .push(Interval::new(key_range, sources.iter().cloned()));
+            }
+        }
+
+        Self {
+            version,
+            intervals,
+            cleanup,
+            reconstructions: HashMap::new(),
+            role_changes,
+        }
+    }
+
+    /// Creates a new migration plan of an erasure coded keyspace.
+    ///
+    /// Replica sets of the new keyspace must be aligned with the old ones
+    /// (see [`Shards::align`]), so that position of a node in a replica set
+    /// is the index of the fragment it stores. Nodes that are no longer in
+    /// the keyspace (as told by `contains`) cannot serve their fragments.
+    pub(crate) fn with_erasure_coding<const RF: usize, F>(
+        version: u64,
+        old_shards: &Shards<N, RF>,
+        new_shards: &Shards<N, RF>,
+        erasure_coding: ErasureCoding,
+        contains: F,
+    ) -> KeyspaceResult<Self>
+    where
+        F: Fn(&N::Id) -> bool,
+    {
+        if old_shards.len() != new_shards.len() {
+            return Err(KeyspaceError::ShardCountMismatch);
+        }
+
+        let mut cleanup = HashMap::new();
+        let mut reconstructions = HashMap::new();
+        for (key_range, old_replica_set, new_replica_set) in old_shards.zip(new_shards) {
+            // Positions matter, not just the nodes.
+            if old_replica_set[..] == new_replica_set[..] {
+                continue;
+            }
+
+            for source_node in old_replica_set.iter() {
+                if !new_replica_set.contains(source_node) {
+                    cleanup
+                        .entry(source_node.id().clone())
+                        .or_insert_with(Vec::new)
+                        .push(key_range);
+                }
+            }
+
+            // Fragments that are still available, data fragments first, as
+            // they can be read without decoding.
+            let remaining = old_replica_set
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| contains(node.id()))
+                .map(|(fragment, node)| (fragment, node.clone()))
+                .collect::<Vec<_>>();
+
+            for (fragment, target_node) in new_replica_set.iter().enumerate() {
+                let previous = old_replica_set.get(fragment);
+                if previous == Some(target_node) {
+                    continue;
+                }
+
+                let sources = match previous {
+                    Some(node) if contains(node.id()) => vec![(fragment, node.clone())],
+                    _ if remaining.len() >= erasure_coding.data_fragments() => {
+                        remaining[..erasure_coding.data_fragments()].to_vec()
+                    }
+                    _ => Vec::new(),
+                };
+                reconstructions
+                    .entry(target_node.id().clone())
+                    .or_insert_with(Vec::new)
+                    .push(Reconstruction::new(key_range, fragment, sources));
             }
         }
 
-        Ok(Self { version, intervals })
+        Ok(Self {
+            version,
+            intervals: HashMap::new(),
+            cleanup,
+            reconstructions,
+            role_changes: HashMap::new(),
+        })
     }
 
     /// Returns the version of the migration plan.
@@ -88,4 +314,43 @@ impl<N: KeyspaceNode> MigrationPlan<N> {
             .into_iter()
             .flat_map(|intervals| intervals.iter())
     }
+
+    /// Fragments that need to be rebuilt on the given node, in erasure coded
+    /// keyspaces (see [`ErasureCoding`]).
+    ///
+    /// Erasure coded keyspaces do not pull intervals, as whole data is not
+    /// stored on any single node: each node stores its own fragment, which
+    /// is either copied from the node that stored it so far, or decoded from
+    /// the fragments of the other nodes.
+    pub fn reconstructions(&self, node_id: &N::Id) -> impl Iterator<Item = &Reconstruction<N>> {
+        self.reconstructions
+            .get(node_id)
+            .into_iter()
+            .flat_map(|reconstructions| reconstructions.iter())
+    }
+
+    /// Changes of the roles of the given node, in keyspaces with replica roles
+    /// (see [`ReplicaRoles`]), or with balanced primaries (see
+    /// [`KeyspaceBuilder::with_primary_balancing`](crate::KeyspaceBuilder::with_primary_balancing)).
+    ///
+    /// Lists every key range where the node joins, leaves, changes its role
+    /// in the replica set, or becomes (or stops being) the primary, e.g. so
+    /// that consensus groups can be reconfigured accordingly. Changes of
+    /// primaries within the same replica set involve no data movement.
+    pub fn role_changes(&self, node_id: &N::Id) -> impl Iterator<Item = &RoleChange> {
+        self.role_changes
+            .get(node_id)
+            .into_iter()
+            .flat_map(|role_changes| role_changes.iter())
+    }
+
+    /// Key ranges that the given node no longer controls (or no longer
+    /// stores the data of, as a witness), and can clean up, once the data is
+    /// pulled by the new replicas.
+    pub fn cleanup_intervals(&self, node_id: &N::Id) -> impl Iterator<Item = &KeyRange> {
+        self.cleanup
+            .get(node_id)
+            .into_iter()
+            .flat_map(|key_ranges| key_ranges.iter())
+    }
 }
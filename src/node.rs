@@ -1,6 +1,5 @@
 use {
     auto_impl::auto_impl,
-    hrw_hash::HrwNode,
     parking_lot::RwLock,
     std::{borrow::Borrow, collections::HashMap, fmt, hash::Hash, ops::Deref, sync::Arc},
 };
@@ -31,6 +30,21 @@ pub trait KeyspaceNode: fmt::Debug + Hash + PartialEq + Eq {
     fn capacity(&self) -> usize {
         1
     }
+
+    /// Topology labels of the node.
+    ///
+    /// Labels identify failure domains the node belongs to, ordered from the
+    /// widest to the narrowest, e.g. `["eu-west", "eu-west-1a", "rack7",
+    /// "host3"]` for region, zone, rack and host. Failure domain at a given
+    /// level is identified by all the labels up to that level, so racks with
+    /// the same name in different zones are distinct domains.
+    ///
+    /// Used by [`FailureDomainReplicationStrategy`] to spread replicas.
+    ///
+    /// [`FailureDomainReplicationStrategy`]: crate::FailureDomainReplicationStrategy
+    fn topology(&self) -> &[String] {
+        &[]
+    }
 }
 
 macro_rules! impl_keyspace_node {
@@ -57,15 +71,6 @@ impl_keyspace_node!(String, &'static str, u8, u16, u32, u64, usize);
 #[derive(Debug, Hash)]
 pub struct NodeRef<N>(Option<Arc<N>>);
 
-impl<N: KeyspaceNode> HrwNode for NodeRef<N> {
-    fn capacity(&self) -> usize {
-        match self.0.as_ref() {
-            Some(node) => node.capacity(),
-            None => 0,
-        }
-    }
-}
-
 impl<N> Default for NodeRef<N> {
     fn default() -> Self {
         Self(None)
@@ -194,6 +199,14 @@ impl<N: KeyspaceNode> NodeRef<N> {
             .as_ref()
             .expect("Cannot get inner node from an empty NodeRef")
     }
+
+    /// Address of the referenced node.
+    ///
+    /// References cloned from the same node share the address, so it can be
+    /// used as a cheap node identity. Empty reference has address of `0`.
+    pub(crate) fn addr(&self) -> usize {
+        self.0.as_ref().map_or(0, |node| Arc::as_ptr(node) as usize)
+    }
 }
 
 /// Nodes collection.
@@ -201,8 +214,38 @@ impl<N: KeyspaceNode> NodeRef<N> {
 /// The collection assigns each node an index (by hashing the node), which
 /// serves as a handle throughout the rest of the system. This way wherever we
 /// need to store the node, we store the index (which takes 8 bytes, `u64`).
-#[derive(Debug, Clone)]
-pub(crate) struct Nodes<N: KeyspaceNode>(Arc<RwLock<HashMap<N::Id, NodeRef<N>>>>);
+///
+/// Order in which nodes were added is preserved, so that placement algorithms
+/// relying on the order of nodes (e.g. jump consistent hash) are
+/// deterministic.
+#[derive(Debug)]
+pub(crate) struct Nodes<N: KeyspaceNode>(Arc<RwLock<NodesInner<N>>>);
+
+/// Clones share the nodes, see [`Nodes::snapshot`] for a deep copy.
+impl<N: KeyspaceNode> Clone for Nodes<N> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+#[derive(Debug)]
+struct NodesInner<N: KeyspaceNode> {
+    /// Nodes along with their insertion sequence numbers.
+    nodes: HashMap<N::Id, (u64, NodeRef<N>)>,
+
+    /// Sequence number of the next inserted node.
+    next_seq: u64,
+}
+
+/// Derive macro would require `Clone` on `N`.
+impl<N: KeyspaceNode> Clone for NodesInner<N> {
+    fn clone(&self) -> Self {
+        Self {
+            nodes: self.nodes.clone(),
+            next_seq: self.next_seq,
+        }
+    }
+}
 
 impl<N: KeyspaceNode> Default for Nodes<N> {
     fn default() -> Self {
@@ -214,7 +257,10 @@ impl<N: KeyspaceNode> Default for Nodes<N> {
 impl<N: KeyspaceNode> Nodes<N> {
     /// Creates a new empty nodes collection.
     pub fn new() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
+        Self(Arc::new(RwLock::new(NodesInner {
+            nodes: HashMap::new(),
+            next_seq: 0,
+        })))
     }
 
     /// Creates a new nodes collection from an iterator of nodes.
@@ -222,53 +268,82 @@ impl<N: KeyspaceNode> Nodes<N> {
     where
         I: IntoIterator<Item = N>,
     {
-        Self(Arc::new(RwLock::new(HashMap::from_iter(
-            nodes
-                .into_iter()
-                .map(|node| (node.id().clone(), NodeRef::new(node))),
-        ))))
+        let collection = Self::new();
+        for node in nodes {
+            collection.insert(node);
+        }
+        collection
     }
 
     /// Adds a node to the collection.
     ///
-    /// If the node with given ID was already present, the value is updated, and
-    /// the old value is returned.
+    /// If the node with given ID was already present, the value is updated
+    /// (keeping its position in the insertion order), and the old value is
+    /// returned.
     pub fn insert(&self, node: N) -> Option<NodeRef<N>> {
-        self.0.write().insert(node.id().clone(), NodeRef::new(node))
+        let mut inner = self.0.write();
+        let seq = match inner.nodes.get(node.id()) {
+            Some((seq, _)) => *seq,
+            None => {
+                inner.next_seq += 1;
+                inner.next_seq - 1
+            }
+        };
+        inner
+            .nodes
+            .insert(node.id().clone(), (seq, NodeRef::new(node)))
+            .map(|(_, node)| node)
     }
 
     /// Removes and returns (if existed) a node from the collection.
     pub fn remove(&self, id: &N::Id) -> Option<NodeRef<N>> {
-        self.0.write().remove(id)
+        self.0.write().nodes.remove(id).map(|(_, node)| node)
     }
 
     /// Returns a reference to the node with given index.
     pub fn get(&self, id: N::Id) -> Option<NodeRef<N>> {
-        self.0.read().get(&id).and_then(|node| Some(node.clone()))
+        self.0.read().nodes.get(&id).map(|(_, node)| node.clone())
     }
 
     /// Number of nodes in the collection.
     pub fn len(&self) -> usize {
-        self.0.read().len()
+        self.0.read().nodes.len()
     }
 
     /// Checks if the collection contains a node.
     pub fn contains(&self, id: &N::Id) -> bool {
-        self.0.read().contains_key(id)
+        self.0.read().nodes.contains_key(id)
     }
 
     /// Node IDs in the collection.
     pub fn keys(&self) -> Vec<N::Id> {
-        self.0.read().keys().map(|key| key.clone()).collect()
+        self.0.read().nodes.keys().cloned().collect()
+    }
+
+    /// Independent copy of the collection, i.e. nodes added to or removed
+    /// from the copy are not seen by the original, and vice versa.
+    pub fn snapshot(&self) -> Self {
+        Self(Arc::new(RwLock::new(self.0.read().clone())))
+    }
+
+    /// Replaces the nodes of the collection with the nodes of another one, at
+    /// once.
+    pub fn assign(&self, other: &Self) {
+        let inner = other.0.read().clone();
+        *self.0.write() = inner;
     }
 
-    /// Node references in the collection.
+    /// Node references in the collection, in insertion order.
     pub fn values(&self) -> Vec<NodeRef<N>> {
-        self.0.read().values().cloned().collect()
+        let inner = self.0.read();
+        let mut values = inner.nodes.values().collect::<Vec<_>>();
+        values.sort_unstable_by_key(|(seq, _)| *seq);
+        values.into_iter().map(|(_, node)| node.clone()).collect()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::to_string_trait_impl)]
 mod tests {
     use {
         super::*,
@@ -339,5 +414,15 @@ mod tests {
 
         // Check if the node exists
         assert!(nodes.contains(&node1a.id()));
+
+        // Nodes are kept in insertion order, updates keep the position.
+        nodes.remove(&"node2".to_string());
+        nodes.insert(Node::new("node0", "127.0.0.1", 2051, 10));
+        let ids = nodes
+            .values()
+            .iter()
+            .map(|node| node.id().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["node1", "node3", "node0"]);
     }
 }
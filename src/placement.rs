@@ -0,0 +1,422 @@
+use {
+    super::{DefaultHasher, KeyspaceNode, NodeRef},
+    std::{
+        hash::{BuildHasher, BuildHasherDefault, Hash},
+        mem,
+        sync::Arc,
+    },
+};
+
+/// Algorithm used to assign nodes to portions of the keyspace.
+///
+/// For each portion of the keyspace (shard, sub-shard or tablet), the
+/// algorithm provides candidate nodes in the order of preference. Replication
+/// strategy then picks the first `RF` candidates it accepts, so all the
+/// algorithms share the same replication and migration plan semantics.
+///
+/// The default algorithm is [`Hrw`] (Highest Random Weight).
+pub trait PlacementAlgorithm<N: KeyspaceNode>: Send + Sync {
+    /// Prepares the placement of keyspace portions over the given nodes.
+    ///
+    /// Nodes are provided in the order they were added to the keyspace.
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a;
+}
+
+/// Placement of keyspace portions over a fixed set of nodes.
+pub trait Placement<N: KeyspaceNode> {
+    /// Candidate nodes for the keyspace portion identified by the given seed,
+    /// in the order of preference.
+    ///
+    /// Each node must be returned at most once.
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_>;
+}
+
+/// Returns the seed of a keyspace portion identified by the given key.
+pub(crate) fn seed<K: Hash>(key: &K) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(key)
+}
+
+/// Hashes node with the default hasher.
+fn node_hash<N: KeyspaceNode>(node: &NodeRef<N>) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(node)
+}
+
+/// Mixes two hashes into a single one.
+const fn merge(a: u64, b: u64) -> u64 {
+    let mut distance = a ^ b;
+    distance ^= distance >> 33;
+    distance = distance.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    distance ^= distance >> 33;
+    distance = distance.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    distance ^= distance >> 33;
+    distance
+}
+
+/// Placement algorithm with seeds mixed with a salt.
+///
+/// Keyspaces using different salts get uncorrelated placements over the same
+/// nodes, while keyspaces sharing a salt (and the algorithm) get the same
+/// candidates for each keyspace portion.
+pub(crate) struct Salted<N: KeyspaceNode> {
+    algorithm: Arc<dyn PlacementAlgorithm<N>>,
+    salt: u64,
+}
+
+impl<N: KeyspaceNode> Salted<N> {
+    pub fn new(algorithm: Arc<dyn PlacementAlgorithm<N>>, salt: u64) -> Self {
+        Self { algorithm, salt }
+    }
+}
+
+impl<N: KeyspaceNode> PlacementAlgorithm<N> for Salted<N> {
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a,
+    {
+        Box::new(SaltedPlacement {
+            placement: self.algorithm.placement(nodes),
+            salt: self.salt,
+        })
+    }
+}
+
+struct SaltedPlacement<'a, N: KeyspaceNode> {
+    placement: Box<dyn Placement<N> + 'a>,
+    salt: u64,
+}
+
+impl<N: KeyspaceNode> Placement<N> for SaltedPlacement<'_, N> {
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_> {
+        self.placement.candidates(merge(seed, self.salt))
+    }
+}
+
+/// Yields nodes at the given indexes, skipping repeated ones.
+///
+/// Nodes not reached by the indexes are appended at the end, so that every
+/// node is a candidate.
+fn distinct<'a, N, I>(nodes: &'a [NodeRef<N>], indexes: I) -> impl Iterator<Item = &'a NodeRef<N>>
+where
+    N: KeyspaceNode,
+    I: Iterator<Item = usize> + 'a,
+{
+    let mut seen = vec![false; nodes.len()];
+    indexes
+        .chain(0..nodes.len())
+        .filter(move |&idx| !mem::replace(&mut seen[idx], true))
+        .take(nodes.len())
+        .map(|idx| &nodes[idx])
+}
+
+/// Highest Random Weight (rendezvous hashing) placement.
+///
+/// Each node is scored for each keyspace portion, and nodes are preferred in
+/// the order of decreasing score. Scores are weighted by node capacity, and
+/// adding or removing a node only moves portions to or from that node.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Hrw;
+
+impl<N: KeyspaceNode> PlacementAlgorithm<N> for Hrw {
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a,
+    {
+        let total_capacity = nodes.iter().map(|node| node.capacity()).sum();
+        let nodes = nodes
+            .into_iter()
+            .map(|node| {
+                let hash = node_hash(&node);
+                (node, hash)
+            })
+            .collect();
+        Box::new(HrwPlacement {
+            nodes,
+            total_capacity,
+        })
+    }
+}
+
+struct HrwPlacement<N: KeyspaceNode> {
+    nodes: Vec<(NodeRef<N>, u64)>,
+    total_capacity: usize,
+}
+
+impl<N: KeyspaceNode> Placement<N> for HrwPlacement<N> {
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_> {
+        let mut scored = self
+            .nodes
+            .iter()
+            .map(|(node, hash)| {
+                let weight = node.capacity() as f64 / self.total_capacity as f64;
+                let hash = merge(*hash, seed) as f64 / u64::MAX as f64;
+                ((1.0 / -hash.ln()) * weight, node)
+            })
+            .collect::<Vec<_>>();
+        scored.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+        Box::new(scored.into_iter().map(|(_, node)| node))
+    }
+}
+
+/// Maglev lookup table placement.
+///
+/// Nodes fill the slots of a lookup table, following their own permutations
+/// of the table, with nodes of higher capacity filling proportionally more
+/// slots. Keyspace portion is placed by walking the table from the slot
+/// selected by its seed. Lookup is cheap and the load is very evenly spread,
+/// at the cost of slightly more movement than [`Hrw`] on node changes.
+#[derive(Debug, Clone, Copy)]
+pub struct Maglev {
+    table_size: usize,
+}
+
+impl Default for Maglev {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Maglev {
+    /// Default size of the lookup table.
+    pub const DEFAULT_TABLE_SIZE: usize = 65537;
+
+    /// Creates Maglev placement with the default lookup table size.
+    pub fn new() -> Self {
+        Self {
+            table_size: Self::DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    /// Creates Maglev placement with the given lookup table size.
+    ///
+    /// The size should be considerably larger than the number of nodes. It is
+    /// rounded up to the nearest prime, so that permutations of the nodes
+    /// cover the whole table.
+    pub fn with_table_size(table_size: usize) -> Self {
+        let is_prime = |n: usize| {
+            n >= 2
+                && (2..)
+                    .take_while(|d| d * d <= n)
+                    .all(|d| !n.is_multiple_of(d))
+        };
+        Self {
+            table_size: (table_size..).find(|&n| is_prime(n)).unwrap_or(2),
+        }
+    }
+
+    /// Size of the lookup table.
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+}
+
+impl<N: KeyspaceNode> PlacementAlgorithm<N> for Maglev {
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a,
+    {
+        let size = self.table_size;
+        let mut table = vec![usize::MAX; size];
+
+        // Each node walks its own permutation of the table, taking as many
+        // free slots per round as its capacity.
+        let mut permutations = nodes
+            .iter()
+            .map(|node| {
+                let hash = node_hash(node);
+                let offset = (hash % size as u64) as usize;
+                let skip = (merge(hash, 0) % (size as u64 - 1)) as usize + 1;
+                (offset, skip, 0usize)
+            })
+            .collect::<Vec<_>>();
+        let mut filled = 0;
+        if nodes.iter().any(|node| node.capacity() > 0) {
+            'fill: loop {
+                for (idx, node) in nodes.iter().enumerate() {
+                    let (offset, skip, next) = &mut permutations[idx];
+                    for _ in 0..node.capacity() {
+                        let slot = loop {
+                            let slot = (*offset + *skip * *next) % size;
+                            *next += 1;
+                            if table[slot] == usize::MAX {
+                                break slot;
+                            }
+                        };
+                        table[slot] = idx;
+                        filled += 1;
+                        if filled == size {
+                            break 'fill;
+                        }
+                    }
+                }
+            }
+        }
+
+        Box::new(MaglevPlacement { nodes, table })
+    }
+}
+
+struct MaglevPlacement<N: KeyspaceNode> {
+    nodes: Vec<NodeRef<N>>,
+    table: Vec<usize>,
+}
+
+impl<N: KeyspaceNode> Placement<N> for MaglevPlacement<N> {
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_> {
+        let size = self.table.len();
+        let start = (seed % size as u64) as usize;
+        let slots = (0..size)
+            .map(move |step| self.table[(start + step) % size])
+            .filter(|&idx| idx != usize::MAX);
+        Box::new(distinct(&self.nodes, slots))
+    }
+}
+
+/// Jump consistent hash placement.
+///
+/// Nodes are treated as numbered buckets, in the order they were added to
+/// the keyspace. Node capacities are ignored. Placement requires no state and
+/// the load is evenly spread, but only adding nodes (or removing the most
+/// recently added ones) moves a minimal portion of the keyspace. Best suited
+/// for single-replica caches.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JumpHash;
+
+impl JumpHash {
+    /// Returns the bucket, out of `buckets`, for the given key.
+    fn bucket(mut key: u64, buckets: usize) -> usize {
+        let (mut b, mut j) = (-1i64, 0i64);
+        while j < buckets as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+        b as usize
+    }
+}
+
+impl<N: KeyspaceNode> PlacementAlgorithm<N> for JumpHash {
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a,
+    {
+        Box::new(JumpHashPlacement(nodes))
+    }
+}
+
+struct JumpHashPlacement<N: KeyspaceNode>(Vec<NodeRef<N>>);
+
+impl<N: KeyspaceNode> Placement<N> for JumpHashPlacement<N> {
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_> {
+        // The first candidate is the bucket of the seed, each subsequent one is
+        // selected out of the remaining nodes, with a re-hashed seed.
+        let mut remaining = (0..self.0.len()).collect::<Vec<_>>();
+        let mut seed = seed;
+        Box::new(std::iter::from_fn(move || {
+            if remaining.is_empty() {
+                return None;
+            }
+            let idx = remaining.remove(JumpHash::bucket(seed, remaining.len()));
+            seed = merge(seed, idx as u64);
+            Some(&self.0[idx])
+        }))
+    }
+}
+
+/// Consistent hash ring with virtual nodes.
+///
+/// Each node is placed at multiple points of a hash ring, proportionally to
+/// its capacity. Keyspace portion is placed by walking the ring clockwise
+/// from its seed. Compatible with classic ring based systems.
+#[derive(Debug, Clone, Copy)]
+pub struct Ring {
+    vnodes: usize,
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ring {
+    /// Default number of virtual nodes per unit of capacity.
+    pub const DEFAULT_VNODES: usize = 160;
+
+    /// Creates ring placement with the default number of virtual nodes.
+    pub fn new() -> Self {
+        Self::with_vnodes(Self::DEFAULT_VNODES)
+    }
+
+    /// Creates ring placement with the given number of virtual nodes per unit
+    /// of node capacity.
+    pub fn with_vnodes(vnodes: usize) -> Self {
+        Self { vnodes }
+    }
+
+    /// Number of virtual nodes per unit of node capacity.
+    pub fn vnodes(&self) -> usize {
+        self.vnodes
+    }
+}
+
+impl<N: KeyspaceNode> PlacementAlgorithm<N> for Ring {
+    fn placement<'a>(&self, nodes: Vec<NodeRef<N>>) -> Box<dyn Placement<N> + 'a>
+    where
+        N: 'a,
+    {
+        let mut points = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| {
+                let hash = node_hash(node);
+                (0..self.vnodes * node.capacity())
+                    .map(move |vnode| (merge(hash, vnode as u64), idx))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        Box::new(RingPlacement { nodes, points })
+    }
+}
+
+struct RingPlacement<N: KeyspaceNode> {
+    nodes: Vec<NodeRef<N>>,
+    points: Vec<(u64, usize)>,
+}
+
+impl<N: KeyspaceNode> Placement<N> for RingPlacement<N> {
+    fn candidates(&self, seed: u64) -> Box<dyn Iterator<Item = &NodeRef<N>> + '_> {
+        let len = self.points.len();
+        let start = self.points.partition_point(|&(point, _)| point < seed);
+        let points = (0..len).map(move |step| self.points[(start + step) % len].1);
+        Box::new(distinct(&self.nodes, points))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_are_distinct() {
+        let algorithms: [Box<dyn PlacementAlgorithm<u64>>; 4] = [
+            Box::new(Hrw),
+            Box::new(Maglev::with_table_size(1000)),
+            Box::new(JumpHash),
+            Box::new(Ring::new()),
+        ];
+        for algorithm in algorithms {
+            let placement = algorithm.placement((0..10u64).map(NodeRef::new).collect());
+            for seed in 0..100 {
+                let mut candidates = placement
+                    .candidates(seed)
+                    .map(|node| *node.inner())
+                    .collect::<Vec<_>>();
+                candidates.sort_unstable();
+                assert_eq!(candidates, (0..10).collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(Maglev::with_table_size(1000).table_size(), 1009);
+    }
+}
@@ -0,0 +1,588 @@
+use {
+    super::{
+        DefaultReplicationStrategy,
+        Interval,
+        KeyPosition,
+        KeyRange,
+        KeyspaceError,
+        KeyspaceKey,
+        KeyspaceNode,
+        KeyspaceResult,
+        MigrationPlan,
+        NodeRef,
+        PlacementAlgorithm,
+        Proximity,
+        ReplicaRole,
+        ReplicaRoles,
+        builder::Options,
+        locality,
+        node::Nodes,
+        placement,
+        replication::{ContextualReplicationStrategy, PlacementCounts, ReplicaSet},
+    },
+    std::{collections::BTreeMap, iter, ops::Bound, sync::Arc},
+};
+
+/// Maps keys to positions in the keyspace, preserving their order.
+///
+/// For any two keys `a <= b`, `encode(a) <= encode(b)` must hold, so that
+/// sorted keys occupy contiguous ranges of the keyspace.
+pub trait KeyEncoder<K: ?Sized> {
+    /// Returns position of the key in the keyspace.
+    fn encode(&self, key: &K) -> KeyPosition;
+}
+
+/// Encodes keys by their first 8 bytes.
+///
+/// Bytes are interpreted as a big-endian number, and shorter keys are padded
+/// with zeros, which preserves the lexicographic order of keys.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrefixEncoder;
+
+impl<K: KeyspaceKey + ?Sized> KeyEncoder<K> for PrefixEncoder {
+    fn encode(&self, key: &K) -> KeyPosition {
+        let bytes = key.key_bytes();
+        let mut buf = [0; 8];
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        KeyPosition::from_be_bytes(buf)
+    }
+}
+
+/// Uses unsigned integer keys (e.g. timestamps or sequential ids) as their
+/// positions.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IntegerEncoder;
+
+macro_rules! impl_integer_encoder {
+    ($($t:ty),+) => {
+        $(
+            impl KeyEncoder<$t> for IntegerEncoder {
+                fn encode(&self, key: &$t) -> KeyPosition {
+                    *key as KeyPosition
+                }
+            }
+        )+
+    };
+}
+
+impl_integer_encoder!(u8, u16, u32, u64, usize);
+
+/// Contiguous range of the keyspace controlled by a replica set of nodes.
+#[derive(Debug)]
+struct Tablet<N: KeyspaceNode, const RF: usize> {
+    /// Identifier of the tablet, used to select its replica set.
+    ///
+    /// Identifier is assigned when the tablet is created, and does not change
+    /// when tablet boundaries are moved, so that replica set stays the same.
+    id: u64,
+    replica_set: ReplicaSet<N, RF>,
+}
+
+impl<N: KeyspaceNode, const RF: usize> Clone for Tablet<N, RF> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            replica_set: self.replica_set.clone(),
+        }
+    }
+}
+
+/// Keyspace divided into tablets, keyed by their start position.
+///
+/// The first tablet always starts at position `0`, and each tablet extends up
+/// to the start of the next one.
+#[derive(Debug)]
+struct Tablets<N: KeyspaceNode, const RF: usize>(BTreeMap<KeyPosition, Tablet<N, RF>>);
+
+impl<N: KeyspaceNode, const RF: usize> Clone for Tablets<N, RF> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<N: KeyspaceNode, const RF: usize> Tablets<N, RF> {
+    /// Selects replica sets for tablets, given as `(start, id)` pairs.
+    fn new<R, I>(
+        nodes: &Nodes<N>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication_strategy: &R,
+        replication_factor: usize,
+        layout: I,
+    ) -> KeyspaceResult<Self>
+    where
+        R: ContextualReplicationStrategy<N>,
+        I: IntoIterator<Item = (KeyPosition, u64)>,
+    {
+        if nodes.len() < replication_factor {
+            return Err(KeyspaceError::NotEnoughNodes(replication_factor));
+        }
+
+        let nodes = nodes.values();
+        let replication_strategy = replication_strategy.prepare(
+            &nodes.iter().map(|node| &***node).collect::<Vec<_>>(),
+            replication_factor,
+        );
+        let placement = placement.placement(nodes);
+        let layout = layout.into_iter().collect::<BTreeMap<_, _>>();
+        let mut counts = PlacementCounts::default();
+        let mut tablets = BTreeMap::new();
+        let mut layout = layout.into_iter().peekable();
+        while let Some((start, id)) = layout.next() {
+            let key_range = KeyRange::new(start, layout.peek().map(|(end, _)| *end));
+            let candidates = placement.candidates(placement::seed(&id));
+            let replica_set = ReplicaSet::select(
+                candidates,
+                &replication_strategy,
+                replication_factor,
+                key_range,
+                &counts,
+            )?;
+            counts.record(&replica_set[..]);
+            tablets.insert(start, Tablet { id, replica_set });
+        }
+        Ok(Self(tablets))
+    }
+
+    /// Layout of the tablets, as `(start, id)` pairs.
+    fn layout(&self) -> impl Iterator<Item = (KeyPosition, u64)> {
+        self.0.iter().map(|(start, tablet)| (*start, tablet.id))
+    }
+
+    /// Returns the key range and the tablet containing the given position.
+    fn get(&self, pos: KeyPosition) -> (KeyRange, &Tablet<N, RF>) {
+        let (start, tablet) = self
+            .0
+            .range(..=pos)
+            .next_back()
+            .expect("First tablet must start at 0");
+        (KeyRange::new(*start, self.end_of(*start)), tablet)
+    }
+
+    /// Returns the end of the tablet starting at the given position.
+    fn end_of(&self, start: KeyPosition) -> Option<KeyPosition> {
+        self.0
+            .range((Bound::Excluded(start), Bound::Unbounded))
+            .next()
+            .map(|(end, _)| *end)
+    }
+
+    /// Iterator over the tablets along with their key ranges.
+    fn iter(&self) -> impl Iterator<Item = (KeyRange, &Tablet<N, RF>)> {
+        self.0
+            .iter()
+            .map(|(start, tablet)| (KeyRange::new(*start, self.end_of(*start)), tablet))
+    }
+
+    /// Iterator over the tablets of two keyspaces, aligned at boundaries of
+    /// both.
+    fn zip<'a>(
+        &'a self,
+        other: &'a Self,
+    ) -> impl Iterator<Item = (KeyRange, &'a ReplicaSet<N, RF>, &'a ReplicaSet<N, RF>)> {
+        let mut next = Some(0);
+        iter::from_fn(move || {
+            let pos = next?;
+            let (a_range, a) = self.get(pos);
+            let (b_range, b) = other.get(pos);
+            next = match (a_range.end(), b_range.end()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            Some((KeyRange::new(pos, next), &a.replica_set, &b.replica_set))
+        })
+    }
+}
+
+/// Range-partitioned keyspace.
+///
+/// Alternative to [`Keyspace`](crate::Keyspace), where keys are mapped to
+/// positions using an order-preserving [`KeyEncoder`] instead of a hash
+/// function, so that sorted keys (e.g. time based or lexicographic ids) are
+/// stored next to each other, and range scans only involve the nodes
+/// controlling the scanned range.
+///
+/// The keyspace is divided into tablets at explicit boundaries, which can be
+/// added ([`RangeKeyspace::split`]), removed ([`RangeKeyspace::merge`]) or
+/// moved ([`RangeKeyspace::move_boundary`]). Each tablet is controlled by a
+/// replica set of nodes, selected using the same placement algorithm and
+/// replication strategies as in the hash partitioned keyspace, and the same
+/// migration plans are provided on updates.
+pub struct RangeKeyspace<N, R = DefaultReplicationStrategy, const RF: usize = 3, E = PrefixEncoder>
+where
+    N: KeyspaceNode,
+    R: ContextualReplicationStrategy<N>,
+{
+    nodes: Arc<Nodes<N>>,
+    tablets: Tablets<N, RF>,
+    replication_strategy: R,
+    placement: Arc<dyn PlacementAlgorithm<N>>,
+    replication_factor: usize,
+    replica_roles: Option<ReplicaRoles>,
+    encoder: E,
+    next_tablet_id: u64,
+    version: u64,
+}
+
+impl<N, R, const RF: usize, E> RangeKeyspace<N, R, RF, E>
+where
+    N: KeyspaceNode,
+    R: ContextualReplicationStrategy<N>,
+{
+    /// Create new keyspace, consisting of a single tablet.
+    pub(crate) fn with_encoder<I: IntoIterator<Item = N>>(
+        encoder: E,
+        init_nodes: I,
+        replication_strategy: R,
+        options: Options<N>,
+    ) -> KeyspaceResult<Self> {
+        if options.erasure_coding.is_some() {
+            return Err(KeyspaceError::UnsupportedErasureCoding);
+        }
+        if options.key_extractor.is_some() {
+            return Err(KeyspaceError::UnsupportedKeyExtractor);
+        }
+        if options.primary_balancing {
+            return Err(KeyspaceError::UnsupportedPrimaryBalancing);
+        }
+        let replication_factor = options.replication_factor::<RF>()?;
+        if let Some(replica_roles) = options.replica_roles
+            && replica_roles.total() != replication_factor
+        {
+            return Err(KeyspaceError::InvalidReplicationFactor(replication_factor));
+        }
+
+        let nodes = Nodes::from_iter(init_nodes);
+        let tablets = Tablets::new(
+            &nodes,
+            &*options.placement,
+            &replication_strategy,
+            replication_factor,
+            [(0, 0)],
+        )?;
+        Ok(Self {
+            nodes: Arc::new(nodes),
+            tablets,
+            replication_strategy,
+            placement: options.placement,
+            replication_factor,
+            replica_roles: options.replica_roles,
+            encoder,
+            next_tablet_id: 1,
+            version: 0,
+        })
+    }
+
+    /// Add a node to the keyspace.
+    ///
+    /// If the replica sets cannot be filled, the keyspace is left intact.
+    pub fn add_node(&mut self, node: N) -> KeyspaceResult<MigrationPlan<N>> {
+        self.update_nodes(|nodes| {
+            nodes.insert(node);
+        })
+    }
+
+    /// Remove a node from the keyspace.
+    ///
+    /// If the replica sets cannot be filled without the node, the keyspace is
+    /// left intact.
+    pub fn remove_node(&mut self, node_id: &N::Id) -> KeyspaceResult<MigrationPlan<N>> {
+        self.update_nodes(|nodes| {
+            nodes.remove(node_id);
+        })
+    }
+
+    /// Returns position of the given key in the keyspace.
+    pub fn position_of<K>(&self, key: &K) -> KeyPosition
+    where
+        K: ?Sized,
+        E: KeyEncoder<K>,
+    {
+        self.encoder.encode(key)
+    }
+
+    /// Returns replication factor (`RF`) number of nodes responsible for the
+    /// given key.
+    ///
+    /// The first node is assumed to be the primary node.
+    pub fn replicas<K>(&self, key: &K) -> impl Iterator<Item = NodeRef<N>>
+    where
+        K: ?Sized,
+        E: KeyEncoder<K>,
+    {
+        self.replicas_for_position(self.position_of(key))
+    }
+
+    /// Returns replication factor (`RF`) number of nodes responsible for the
+    /// given key position.
+    pub fn replicas_for_position(&self, pos: KeyPosition) -> impl Iterator<Item = NodeRef<N>> {
+        let (_, tablet) = self.tablets.get(pos);
+        tablet.replica_set.iter().cloned()
+    }
+
+    /// Returns the nodes responsible for the given key position, along with
+    /// their roles.
+    ///
+    /// See [`Keyspace::replicas_with_roles`](crate::Keyspace::replicas_with_roles).
+    pub fn replicas_with_roles_for_position(
+        &self,
+        pos: KeyPosition,
+    ) -> impl Iterator<Item = (NodeRef<N>, ReplicaRole)> {
+        let (_, tablet) = self.tablets.get(pos);
+        tablet.replica_set.with_roles(self.replica_roles)
+    }
+
+    /// Returns the nodes storing the data of the given key, ordered for reads
+    /// by the caller: nearest first.
+    ///
+    /// See [`Keyspace::read_replicas`](crate::Keyspace::read_replicas).
+    pub fn read_replicas<K, P>(&self, key: &K, caller: &P) -> impl Iterator<Item = NodeRef<N>>
+    where
+        K: ?Sized,
+        E: KeyEncoder<K>,
+        P: Proximity<N>,
+    {
+        self.read_replicas_for_position(self.position_of(key), caller)
+    }
+
+    /// Returns the nodes storing the data of the given key position, ordered
+    /// for reads by the caller.
+    pub fn read_replicas_for_position<P: Proximity<N>>(
+        &self,
+        pos: KeyPosition,
+        caller: &P,
+    ) -> impl Iterator<Item = NodeRef<N>> {
+        let (key_range, tablet) = self.tablets.get(pos);
+        locality::order(
+            tablet.replica_set.with_roles(self.replica_roles),
+            caller,
+            key_range.start(),
+        )
+        .into_iter()
+    }
+
+    /// Returns the key range of the tablet containing the given position.
+    pub fn tablet_of(&self, pos: KeyPosition) -> KeyRange {
+        self.tablets.get(pos).0
+    }
+
+    /// Intervals of the keyspace overlapping the given key range.
+    ///
+    /// Each interval is a segment of a tablet that falls within the key range,
+    /// along with the replica set controlling it. Since keys are stored in
+    /// order, scanning a range of keys only requires contacting the nodes of
+    /// the returned intervals.
+    pub fn replicas_for_range(&self, key_range: KeyRange) -> impl Iterator<Item = Interval<N>> {
+        let mut next = Some(key_range.start());
+        iter::from_fn(move || {
+            let pos = next.filter(|pos| key_range.contains(*pos))?;
+            let (tablet_range, tablet) = self.tablets.get(pos);
+            next = tablet_range.end();
+
+            let end = match (next, key_range.end()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            Some(Interval::new(
+                KeyRange::new(pos, end),
+                tablet.replica_set.with_roles(self.replica_roles),
+            ))
+        })
+    }
+
+    /// Tablet boundaries, i.e. start positions of all tablets but the first
+    /// one, in ascending order.
+    pub fn boundaries(&self) -> impl Iterator<Item = KeyPosition> {
+        self.tablets.0.keys().skip(1).copied()
+    }
+
+    /// Tablets running degraded, i.e. with replica sets filled using fallback
+    /// tiers of the replication strategy.
+    ///
+    /// Returned as `(key range, tier)` tuples, see
+    /// [`Keyspace::degraded_shards`](crate::Keyspace::degraded_shards).
+    pub fn degraded_tablets(&self) -> impl Iterator<Item = (KeyRange, usize)> {
+        self.tablets.iter().filter_map(|(key_range, tablet)| {
+            let replica_set = &tablet.replica_set;
+            replica_set
+                .is_degraded()
+                .then_some((key_range, replica_set.fallback_tier()))
+        })
+    }
+
+    /// Split the tablet containing the given position into two tablets at that
+    /// position.
+    ///
+    /// The lower tablet keeps its replica set, the upper one gets a new one.
+    pub fn split(&mut self, boundary: KeyPosition) -> KeyspaceResult<MigrationPlan<N>> {
+        if self.tablets.0.contains_key(&boundary) {
+            return Err(KeyspaceError::InvalidBoundary(boundary));
+        }
+
+        let mut layout = self.tablets.layout().collect::<BTreeMap<_, _>>();
+        layout.insert(boundary, self.next_tablet_id);
+        let plan = self.rebuild(layout)?;
+        self.next_tablet_id += 1;
+        Ok(plan)
+    }
+
+    /// Merge the tablet starting at the given boundary into the preceding one.
+    ///
+    /// The merged tablet is controlled by the replica set of the lower tablet.
+    pub fn merge(&mut self, boundary: KeyPosition) -> KeyspaceResult<MigrationPlan<N>> {
+        if boundary == 0 || !self.tablets.0.contains_key(&boundary) {
+            return Err(KeyspaceError::InvalidBoundary(boundary));
+        }
+
+        let mut layout = self.tablets.layout().collect::<BTreeMap<_, _>>();
+        layout.remove(&boundary);
+        self.rebuild(layout)
+    }
+
+    /// Move a tablet boundary to a new position.
+    ///
+    /// The new position must remain strictly between the neighbouring
+    /// boundaries. Tablets keep their replica sets, only the keys between the
+    /// old and new positions change hands.
+    pub fn move_boundary(
+        &mut self,
+        boundary: KeyPosition,
+        new_boundary: KeyPosition,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        if boundary == 0 || !self.tablets.0.contains_key(&boundary) {
+            return Err(KeyspaceError::InvalidBoundary(boundary));
+        }
+
+        let lower = self
+            .tablets
+            .0
+            .range(..boundary)
+            .next_back()
+            .map(|(s, _)| *s);
+        let upper = self.tablets.end_of(boundary);
+        let within_lower = lower.is_some_and(|lower| new_boundary > lower);
+        let within_upper = upper.is_none_or(|upper| new_boundary < upper);
+        if !within_lower || !within_upper {
+            return Err(KeyspaceError::InvalidBoundary(new_boundary));
+        }
+
+        let mut layout = self.tablets.layout().collect::<BTreeMap<_, _>>();
+        let id = layout.remove(&boundary).expect("Boundary must exist");
+        layout.insert(new_boundary, id);
+        self.rebuild(layout)
+    }
+
+    /// Replication factor, i.e. the number of nodes in each replica set.
+    ///
+    /// See [`Keyspace::replication_factor`](crate::Keyspace::replication_factor).
+    pub fn replication_factor(&self) -> usize {
+        self.replication_factor
+    }
+
+    /// Keyspace version.
+    ///
+    /// Version is incremented each time the keyspace is modified.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Keyspace as intervals controlled by the nodes.
+    ///
+    /// The intervals are returned as `(key range, node ref)` tuples, one for
+    /// each replica of each tablet.
+    pub fn iter(&self) -> impl Iterator<Item = (KeyRange, NodeRef<N>)> {
+        self.tablets.iter().flat_map(|(key_range, tablet)| {
+            tablet
+                .replica_set
+                .iter()
+                .map(move |node| (key_range, node.clone()))
+        })
+    }
+
+    /// Keyspace intervals controlled by the given node.
+    pub fn iter_node(&self, node_id: &N::Id) -> impl Iterator<Item = KeyRange> {
+        self.iter().filter_map(move |(key_range, node)| {
+            if node.id() == node_id {
+                Some(key_range)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Applies the update to a copy of the nodes, and reselects the replica
+    /// sets over it. Nodes are only updated if the replica sets can be filled,
+    /// so that a failed update leaves the keyspace intact.
+    fn update_nodes<F>(&mut self, update: F) -> KeyspaceResult<MigrationPlan<N>>
+    where
+        F: FnOnce(&Nodes<N>),
+    {
+        let nodes = self.nodes.snapshot();
+        update(&nodes);
+        let tablets = Tablets::new(
+            &nodes,
+            &*self.placement,
+            &self.replication_strategy,
+            self.replication_factor,
+            self.tablets.layout(),
+        )?;
+        self.nodes.assign(&nodes);
+        Ok(self.update_tablets(tablets))
+    }
+
+    fn rebuild<I>(&mut self, layout: I) -> KeyspaceResult<MigrationPlan<N>>
+    where
+        I: IntoIterator<Item = (KeyPosition, u64)>,
+    {
+        let tablets = Tablets::new(
+            &self.nodes,
+            &*self.placement,
+            &self.replication_strategy,
+            self.replication_factor,
+            layout,
+        )?;
+        Ok(self.update_tablets(tablets))
+    }
+
+    fn update_tablets(&mut self, tablets: Tablets<N, RF>) -> MigrationPlan<N> {
+        let old_tablets = std::mem::replace(&mut self.tablets, tablets);
+
+        self.version += 1;
+        MigrationPlan::from_segments(
+            self.version,
+            old_tablets.zip(&self.tablets),
+            self.replica_roles,
+            false,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoders_preserve_order() {
+        let mut keys = [
+            "",
+            "a",
+            "aa",
+            "ab",
+            "b",
+            "user:0001",
+            "user:0002",
+            "zzzzzzzzz",
+        ];
+        keys.sort();
+        let positions = keys
+            .iter()
+            .map(|key| PrefixEncoder.encode(*key))
+            .collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(PrefixEncoder.encode("a"), 0x61 << 56);
+        assert_eq!(PrefixEncoder.encode(b"\xff".as_slice()), 0xff << 56);
+
+        assert_eq!(IntegerEncoder.encode(&42u64), 42);
+        assert_eq!(IntegerEncoder.encode(&7u8), 7);
+    }
+}
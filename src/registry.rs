@@ -0,0 +1,332 @@
+use {
+    super::{
+        ContextualReplicationStrategy,
+        DefaultHasher,
+        DefaultReplicationStrategy,
+        Interval,
+        KeyExtractor,
+        Keyspace,
+        KeyspaceError,
+        KeyspaceNode,
+        KeyspaceResult,
+        MigrationPlan,
+        PlacementAlgorithm,
+        builder::Options,
+        node::Nodes,
+        placement::Salted,
+        replication::{DYNAMIC_RF, Replication},
+        sharding::Shards,
+    },
+    std::{
+        collections::{BTreeMap, HashSet},
+        hash::{BuildHasher, BuildHasherDefault},
+        sync::Arc,
+    },
+};
+
+/// How placement of a table relates to placement of the other tables.
+enum Partitioning {
+    /// Placed the same way as a standalone keyspace.
+    Unsalted,
+
+    /// Placement seeds are mixed with the salt.
+    Salted(u64),
+
+    /// Placed the same way as the given table.
+    CoPartitioned(String),
+}
+
+/// Settings of a table in a [`KeyspaceRegistry`].
+pub struct TableConfig<N: KeyspaceNode, R> {
+    replication_strategy: R,
+    partitioning: Partitioning,
+    options: Options<N>,
+}
+
+impl<N: KeyspaceNode, R> TableConfig<N, R> {
+    /// Table with the given replication strategy and factor.
+    pub fn new(replication_strategy: R, replication_factor: usize) -> Self {
+        Self {
+            replication_strategy,
+            partitioning: Partitioning::Unsalted,
+            options: Options {
+                replication_factor,
+                ..Options::default()
+            },
+        }
+    }
+
+    /// Set the key extractor, used to determine which portion of a key defines
+    /// its position in the keyspace.
+    ///
+    /// See [`Keyspace::replicas_for_key`].
+    pub fn with_key_extractor<E: KeyExtractor + 'static>(mut self, key_extractor: E) -> Self {
+        self.options.key_extractor = Some(Arc::new(key_extractor));
+        self
+    }
+
+    /// Set the algorithm used to assign nodes to portions of the keyspace.
+    ///
+    /// Defaults to [`Hrw`](crate::Hrw).
+    pub fn with_placement_algorithm<P: PlacementAlgorithm<N> + 'static>(
+        mut self,
+        placement: P,
+    ) -> Self {
+        self.options.placement = Arc::new(placement);
+        self
+    }
+
+    /// Mix the salt into the placement of the table.
+    ///
+    /// Tables with different salts get uncorrelated placements, so that the
+    /// shards of a failed node are spread over different nodes in each table.
+    /// By default, tables are not salted, and are placed the same way as a
+    /// standalone keyspace.
+    pub fn with_salt(mut self, salt: u64) -> Self {
+        self.partitioning = Partitioning::Salted(salt);
+        self
+    }
+
+    /// Place the table the same way as the given (existing) one.
+    ///
+    /// Co-partitioned tables share the placement algorithm and salt, so that
+    /// keys at the same position get the same candidate nodes in both tables.
+    /// With the same replication factor and strategy, such keys are stored on
+    /// the same replica sets, which keeps multi-table operations local. The
+    /// placement algorithm and salt of this table are ignored.
+    pub fn co_partitioned_with(mut self, table: impl Into<String>) -> Self {
+        self.partitioning = Partitioning::CoPartitioned(table.into());
+        self
+    }
+}
+
+/// Registry of named keyspaces (tables), sharing a single set of nodes.
+///
+/// Each table has its own replication factor (set at runtime) and strategy,
+/// while nodes are managed by the registry: a membership change is applied to
+/// all the tables at once, and either succeeds for all of them, returning a
+/// migration plan per table, or leaves all of them intact.
+pub struct KeyspaceRegistry<
+    N,
+    R = DefaultReplicationStrategy,
+    H = BuildHasherDefault<DefaultHasher>,
+> where
+    N: KeyspaceNode,
+    R: ContextualReplicationStrategy<N>,
+    H: BuildHasher,
+{
+    nodes: Nodes<N>,
+    down: HashSet<N::Id>,
+    tables: BTreeMap<String, Keyspace<N, R, DYNAMIC_RF, H>>,
+    build_hasher: H,
+}
+
+impl<N, R> KeyspaceRegistry<N, R>
+where
+    N: KeyspaceNode + 'static,
+    R: ContextualReplicationStrategy<N>,
+{
+    /// Create new registry, with no tables.
+    pub fn new<I: IntoIterator<Item = N>>(init_nodes: I) -> Self {
+        Self::with_build_hasher(init_nodes, BuildHasherDefault::default())
+    }
+}
+
+impl<N, R, H> KeyspaceRegistry<N, R, H>
+where
+    N: KeyspaceNode + 'static,
+    R: ContextualReplicationStrategy<N>,
+    H: BuildHasher + Clone,
+{
+    /// Create new registry, with no tables, where keys of all the tables are
+    /// hashed with the given build hasher.
+    pub fn with_build_hasher<I: IntoIterator<Item = N>>(init_nodes: I, build_hasher: H) -> Self {
+        Self {
+            nodes: Nodes::from_iter(init_nodes),
+            down: HashSet::new(),
+            tables: BTreeMap::new(),
+            build_hasher,
+        }
+    }
+
+    /// Create a table with the given name and settings.
+    pub fn create_table(
+        &mut self,
+        name: impl Into<String>,
+        config: TableConfig<N, R>,
+    ) -> KeyspaceResult<&Keyspace<N, R, DYNAMIC_RF, H>> {
+        let name = name.into();
+        if self.tables.contains_key(&name) {
+            return Err(KeyspaceError::TableExists(name));
+        }
+
+        let TableConfig {
+            replication_strategy,
+            partitioning,
+            mut options,
+        } = config;
+        match partitioning {
+            Partitioning::Unsalted => {}
+            Partitioning::Salted(salt) => {
+                options.placement = Arc::new(Salted::new(options.placement, salt));
+            }
+            Partitioning::CoPartitioned(table) => {
+                let Some(table) = self.tables.get(&table) else {
+                    return Err(KeyspaceError::TableNotFound(table));
+                };
+                options.placement = table.placement.clone();
+            }
+        }
+
+        let replication = Replication::new(
+            replication_strategy,
+            options.replication_factor::<DYNAMIC_RF>()?,
+        );
+        let mut keyspace = Keyspace::with_nodes(
+            self.nodes.clone(),
+            self.build_hasher.clone(),
+            replication,
+            options,
+        )?;
+        for node_id in &self.down {
+            keyspace.mark_down(node_id);
+        }
+        Ok(self.tables.entry(name).or_insert(keyspace))
+    }
+
+    /// Drop the table with the given name.
+    ///
+    /// Returns `false` if there was no such table.
+    pub fn drop_table(&mut self, name: &str) -> bool {
+        self.tables.remove(name).is_some()
+    }
+
+    /// Returns the table with the given name.
+    pub fn table(&self, name: &str) -> Option<&Keyspace<N, R, DYNAMIC_RF, H>> {
+        self.tables.get(name)
+    }
+
+    /// Iterator over the tables, as `(name, keyspace)` tuples, ordered by name.
+    pub fn tables(&self) -> impl Iterator<Item = (&str, &Keyspace<N, R, DYNAMIC_RF, H>)> {
+        self.tables
+            .iter()
+            .map(|(name, keyspace)| (name.as_str(), keyspace))
+    }
+
+    /// Add a node to all the tables.
+    ///
+    /// Returns migration plans of the tables, keyed by table name. If any of
+    /// the tables cannot be placed, none of them is modified.
+    pub fn add_node(&mut self, node: N) -> KeyspaceResult<BTreeMap<String, MigrationPlan<N>>> {
+        self.update_nodes(|nodes| {
+            nodes.insert(node);
+        })
+    }
+
+    /// Remove a node from all the tables.
+    ///
+    /// See [`KeyspaceRegistry::add_node`].
+    pub fn remove_node(
+        &mut self,
+        node_id: &N::Id,
+    ) -> KeyspaceResult<BTreeMap<String, MigrationPlan<N>>> {
+        self.update_nodes(|nodes| {
+            nodes.remove(node_id);
+        })
+    }
+
+    /// Mark the node as down in all the tables, without changing their
+    /// placement. Tables created while the node is down see it down too.
+    ///
+    /// Returns `false` if there is no such node, or it is already down. See
+    /// [`Keyspace::mark_down`].
+    pub fn mark_down(&mut self, node_id: &N::Id) -> bool {
+        if !self.nodes.contains(node_id) || !self.down.insert(node_id.clone()) {
+            return false;
+        }
+        for table in self.tables.values_mut() {
+            table.mark_down(node_id);
+        }
+        true
+    }
+
+    /// Mark the node, that was marked down, as up again in all the tables.
+    ///
+    /// Returns the hints to replay to the node, keyed by table name, or
+    /// `None` if the node was not down. See [`Keyspace::mark_up`].
+    pub fn mark_up(&mut self, node_id: &N::Id) -> Option<BTreeMap<String, Vec<Interval<N>>>> {
+        if !self.down.remove(node_id) {
+            return None;
+        }
+        Some(
+            self.tables
+                .iter_mut()
+                .filter_map(|(name, table)| Some((name.clone(), table.mark_up(node_id)?)))
+                .collect(),
+        )
+    }
+
+    /// Change the replication factor of the given table.
+    ///
+    /// See [`Keyspace::set_replication_factor`].
+    pub fn set_replication_factor(
+        &mut self,
+        name: &str,
+        replication_factor: usize,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        self.table_mut(name)?
+            .set_replication_factor(replication_factor)
+    }
+
+    /// Change the replication strategy of the given table.
+    ///
+    /// See [`Keyspace::set_replication_strategy`].
+    pub fn set_replication_strategy(
+        &mut self,
+        name: &str,
+        replication_strategy: R,
+    ) -> KeyspaceResult<MigrationPlan<N>> {
+        self.table_mut(name)?
+            .set_replication_strategy(replication_strategy)
+    }
+
+    /// Table with the given name, for updates that do not affect the nodes.
+    fn table_mut(&mut self, name: &str) -> KeyspaceResult<&mut Keyspace<N, R, DYNAMIC_RF, H>> {
+        self.tables
+            .get_mut(name)
+            .ok_or_else(|| KeyspaceError::TableNotFound(name.to_string()))
+    }
+
+    /// Applies the update to a copy of the nodes, and places all the tables
+    /// over it. Only if all the tables can be placed, the nodes and tables
+    /// are updated.
+    fn update_nodes<F>(&mut self, update: F) -> KeyspaceResult<BTreeMap<String, MigrationPlan<N>>>
+    where
+        F: FnOnce(&Nodes<N>),
+    {
+        let nodes = self.nodes.snapshot();
+        update(&nodes);
+
+        let shards = self
+            .tables
+            .values()
+            .map(|table| {
+                Shards::with_splits(
+                    &nodes,
+                    &*table.placement,
+                    &table.replication,
+                    &table.shards.splits(),
+                )
+            })
+            .collect::<KeyspaceResult<Vec<_>>>()?;
+
+        // Tables share the nodes, so all of them see the update.
+        self.nodes.assign(&nodes);
+        self.down.retain(|id| nodes.contains(id));
+        self.tables
+            .iter_mut()
+            .zip(shards)
+            .map(|((name, table), shards)| Ok((name.clone(), table.update_shards(shards)?)))
+            .collect()
+    }
+}
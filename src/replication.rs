@@ -1,15 +1,225 @@
 use {
-    super::{KeyspaceError, KeyspaceNode, KeyspaceResult, NodeRef},
-    std::ops::Deref,
+    super::{DefaultHasher, KeyRange, KeyspaceError, KeyspaceNode, KeyspaceResult, NodeRef},
+    rapidhash::fast::RandomState,
+    std::{
+        collections::{HashMap, HashSet},
+        hash::{BuildHasher, BuildHasherDefault},
+        ops::Deref,
+        sync::Arc,
+    },
 };
 
 /// Replication strategy determines how to choose the nodes for redundancy.
 ///
 /// Each instance of `ReplicationStrategy` is assumed to operate on a single
 /// shard of the keyspace, i.e. a single replica set of nodes.
+///
+/// Strategy may declare a fallback, with relaxed constraints, which is used
+/// whenever the strategy cannot find enough eligible nodes to fill a replica
+/// set (for example, when a whole availability zone is lost). Fallbacks can
+/// be chained into tiers, e.g. "distinct zones, else distinct racks, else any
+/// node".
 pub trait ReplicationStrategy<N>: Clone {
     /// Checks if the given node is eligible for inclusion into a replica set.
     fn is_eligible_replica(&mut self, node: &N) -> bool;
+
+    /// Strategy with relaxed constraints, used to fill the replica set when
+    /// this strategy cannot.
+    ///
+    /// Called on the instance that failed to fill the replica set, i.e. after
+    /// it has checked all the candidates, and should return a fresh instance.
+    /// Nodes already selected by the stricter strategy are kept, and passed to
+    /// the fallback strategy first (disregarding its verdict), so that it can
+    /// account for them when checking the remaining candidates.
+    ///
+    /// By default, there's no fallback, and replica sets that cannot be filled
+    /// result in [`KeyspaceError::IncompleteReplicaSet`].
+    fn fallback(&self) -> Option<Self> {
+        None
+    }
+
+    /// Strategy adapted for placing replica sets of `replication_factor` nodes
+    /// over the given nodes (all the nodes of the keyspace), e.g. to take
+    /// their topology into account, or to precompute per node state.
+    ///
+    /// Called once per placement, and each replica set then gets a copy of
+    /// the adapted strategy. By default, the strategy is used as is.
+    fn adapt(&self, _nodes: &[&N], _replication_factor: usize) -> Self {
+        self.clone()
+    }
+
+    /// Number of fallback tiers that are forced by the topology of the nodes
+    /// the strategy was adapted to (see [`ReplicationStrategy::adapt`]), e.g.
+    /// spreading replicas over racks, when there are fewer zones than
+    /// replicas.
+    ///
+    /// Replica sets filled using at most that many fallbacks satisfy the
+    /// strategy as well as the nodes allow, and are not reported as degraded.
+    /// By default, every fallback degrades the replica set.
+    fn forced_fallbacks(&self) -> usize {
+        0
+    }
+}
+
+/// Replication strategy that is aware of the context of the replica set being
+/// selected.
+///
+/// In addition to the candidate node, the strategy is provided with the key
+/// range of the shard, the nodes already selected into the replica set, and
+/// the number of replica sets placed on each node so far (see
+/// [`ReplicaContext`]). This allows for policies like "ranges in the lower half
+/// of the keyspace only use EU nodes", or "at most `n` shards per node".
+///
+/// Implemented for every [`ReplicationStrategy`], ignoring the context, so
+/// that either of the traits can be used to configure the keyspace.
+pub trait ContextualReplicationStrategy<N: KeyspaceNode>: Clone {
+    /// Checks if the given node is eligible for inclusion into the replica set
+    /// described by the context.
+    fn is_eligible(&mut self, node: &N, ctx: &ReplicaContext<'_, N>) -> bool;
+
+    /// Strategy with relaxed constraints, used to fill the replica set when
+    /// this strategy cannot.
+    ///
+    /// See [`ReplicationStrategy::fallback`].
+    fn relaxed(&self) -> Option<Self> {
+        None
+    }
+
+    /// Strategy prepared for placing replica sets of `replication_factor`
+    /// nodes over the given nodes.
+    ///
+    /// See [`ReplicationStrategy::adapt`].
+    fn prepare(&self, _nodes: &[&N], _replication_factor: usize) -> Self {
+        self.clone()
+    }
+
+    /// Number of relaxations that are forced by the topology of the nodes
+    /// the strategy was prepared for.
+    ///
+    /// See [`ReplicationStrategy::forced_fallbacks`].
+    fn forced_relaxations(&self) -> usize {
+        0
+    }
+}
+
+impl<N: KeyspaceNode, R: ReplicationStrategy<N>> ContextualReplicationStrategy<N> for R {
+    fn is_eligible(&mut self, node: &N, _ctx: &ReplicaContext<'_, N>) -> bool {
+        self.is_eligible_replica(node)
+    }
+
+    fn relaxed(&self) -> Option<Self> {
+        self.fallback()
+    }
+
+    fn prepare(&self, nodes: &[&N], replication_factor: usize) -> Self {
+        self.adapt(nodes, replication_factor)
+    }
+
+    fn forced_relaxations(&self) -> usize {
+        self.forced_fallbacks()
+    }
+}
+
+/// Context of the replica set being selected.
+#[derive(Debug)]
+pub struct ReplicaContext<'a, N: KeyspaceNode> {
+    key_range: KeyRange,
+    replicas: &'a [NodeRef<N>],
+    counts: &'a PlacementCounts<N>,
+}
+
+impl<'a, N: KeyspaceNode> ReplicaContext<'a, N> {
+    pub(crate) fn new(
+        key_range: KeyRange,
+        replicas: &'a [NodeRef<N>],
+        counts: &'a PlacementCounts<N>,
+    ) -> Self {
+        Self {
+            key_range,
+            replicas,
+            counts,
+        }
+    }
+
+    /// Range of keys controlled by the replica set, i.e. the key range of the
+    /// shard (or sub-shard, or tablet).
+    pub fn key_range(&self) -> KeyRange {
+        self.key_range
+    }
+
+    /// Nodes already selected into the replica set, ordered by rank (primary
+    /// first).
+    pub fn replicas(&self) -> &'a [NodeRef<N>] {
+        self.replicas
+    }
+
+    /// Rank the candidate node would get, if selected (`0` for primary).
+    pub fn rank(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Keyspace-wide placement counts, of the replica sets placed so far.
+    pub fn counts(&self) -> &'a PlacementCounts<N> {
+        self.counts
+    }
+}
+
+/// Number of replica sets placed on each node.
+///
+/// Replica sets are placed shard by shard, in order of their key ranges, so
+/// when provided to a strategy, counts reflect the shards preceding the one
+/// being placed (and all the other shards, when a single shard is re-placed,
+/// e.g. on a split).
+#[derive(Debug)]
+pub struct PlacementCounts<N: KeyspaceNode> {
+    /// Number of replica sets, and of those where the node is primary.
+    nodes: HashMap<N::Id, (usize, usize), RandomState>,
+
+    /// Number of replica sets placed.
+    replica_sets: usize,
+}
+
+impl<N: KeyspaceNode> Default for PlacementCounts<N> {
+    fn default() -> Self {
+        Self {
+            nodes: HashMap::default(),
+            replica_sets: 0,
+        }
+    }
+}
+
+impl<N: KeyspaceNode> PlacementCounts<N> {
+    /// Records the placement of a replica set.
+    pub(crate) fn record(&mut self, replica_set: &[NodeRef<N>]) {
+        for (rank, node) in replica_set.iter().enumerate() {
+            let primary = usize::from(rank == 0);
+            match self.nodes.get_mut(node.id()) {
+                Some((replicas, primaries)) => {
+                    *replicas += 1;
+                    *primaries += primary;
+                }
+                None => {
+                    self.nodes.insert(node.id().clone(), (1, primary));
+                }
+            }
+        }
+        self.replica_sets += 1;
+    }
+
+    /// Number of replica sets the node belongs to.
+    pub fn replicas(&self, id: &N::Id) -> usize {
+        self.nodes.get(id).map_or(0, |(replicas, _)| *replicas)
+    }
+
+    /// Number of replica sets where the node is primary.
+    pub fn primaries(&self, id: &N::Id) -> usize {
+        self.nodes.get(id).map_or(0, |(_, primaries)| *primaries)
+    }
+
+    /// Number of replica sets placed.
+    pub fn replica_sets(&self) -> usize {
+        self.replica_sets
+    }
 }
 
 /// Default replication strategy.
@@ -31,53 +241,743 @@ impl DefaultReplicationStrategy {
     }
 }
 
+/// Replication strategy that spreads replicas across failure domains.
+///
+/// Failure domains of the nodes are defined by their topology labels (see
+/// [`KeyspaceNode::topology`]). Replicas are first placed in distinct domains
+/// at the widest level (e.g. regions). If there are not enough of them, the
+/// remaining replicas are placed in distinct domains at the next level (e.g.
+/// zones), and so on, down to distinct nodes. Replica sets that could not be
+/// spread across the widest level with at least as many domains as replicas
+/// are reported as degraded (see
+/// [`Keyspace::degraded_shards`](crate::Keyspace::degraded_shards)), e.g. in
+/// a single region cluster, spreading replicas over distinct zones instead of
+/// regions is not a degradation, as the nodes allow no better.
+///
+/// Additionally, number of replicas per failure domain at any level can be
+/// capped (see [`FailureDomainReplicationStrategy::with_cap`]). Caps are never
+/// relaxed: if they cannot be satisfied, replica set cannot be filled.
+#[derive(Debug, Default, Clone)]
+pub struct FailureDomainReplicationStrategy {
+    /// Level at which replicas must be in distinct failure domains.
+    level: usize,
+
+    /// Maximum number of replicas per failure domain, as `(level, max)`.
+    caps: Vec<(usize, usize)>,
+
+    /// Failure domains of the selected replicas, per level.
+    selected: Vec<Arc<[u64]>>,
+
+    /// Deepest topology seen among the checked nodes.
+    depth: usize,
+
+    /// Failure domains of the nodes per level, by node id hash, precomputed
+    /// when the strategy is adapted to the nodes.
+    domains: Arc<HashMap<u64, Arc<[u64]>, RandomState>>,
+
+    /// Number of the widest levels with fewer domains than replicas.
+    forced: usize,
+}
+
+impl FailureDomainReplicationStrategy {
+    /// Creates a new failure domain replication strategy, without caps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows at most `max` replicas per failure domain at the given topology
+    /// level, e.g. `with_cap(1, 2)` for "at most 2 per zone", when topology
+    /// labels are region, zone, rack and host.
+    pub fn with_cap(mut self, level: usize, max: usize) -> Self {
+        self.caps.push((level, max));
+        self
+    }
+
+    /// The deepest level the strategy needs failure domains at.
+    fn levels(&self) -> usize {
+        self.caps
+            .iter()
+            .map(|&(level, _)| level)
+            .fold(self.level, usize::max)
+    }
+
+    /// Key of the node in the precomputed failure domains.
+    fn key<N: KeyspaceNode>(node: &N) -> u64 {
+        BuildHasherDefault::<DefaultHasher>::default().hash_one(node.id())
+    }
+
+    /// Failure domains of the node at levels up to (and including) `levels`.
+    fn domains_of<N: KeyspaceNode>(node: &N, levels: usize) -> Arc<[u64]> {
+        (0..=levels)
+            .map(|level| Self::domain(node, level))
+            .collect()
+    }
+
+    /// Failure domain of the node at the given level.
+    ///
+    /// Nodes with no label at the level are domains on their own.
+    fn domain<N: KeyspaceNode>(node: &N, level: usize) -> u64 {
+        let build_hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let labels = node.topology();
+        if level < labels.len() {
+            build_hasher.hash_one(&labels[..=level])
+        } else {
+            build_hasher.hash_one((node.id(), level))
+        }
+    }
+}
+
+impl<N: KeyspaceNode> ReplicationStrategy<N> for FailureDomainReplicationStrategy {
+    fn is_eligible_replica(&mut self, node: &N) -> bool {
+        self.depth = self.depth.max(node.topology().len());
+
+        let levels = self.levels();
+        let domains = match self.domains.get(&Self::key(node)) {
+            Some(domains) if domains.len() > levels => domains.clone(),
+            _ => Self::domains_of(node, levels),
+        };
+        let count = |level: usize| {
+            self.selected
+                .iter()
+                .filter(|selected| selected[level] == domains[level])
+                .count()
+        };
+
+        let eligible =
+            count(self.level) == 0 && self.caps.iter().all(|&(level, max)| count(level) < max);
+        if eligible {
+            self.selected.push(domains);
+        }
+        eligible
+    }
+
+    fn fallback(&self) -> Option<Self> {
+        // Below the deepest topology level all the nodes are distinct domains,
+        // so there's nothing to relax further.
+        (self.level < self.depth).then(|| Self {
+            level: self.level + 1,
+            caps: self.caps.clone(),
+            selected: Vec::new(),
+            depth: self.depth,
+            domains: self.domains.clone(),
+            forced: self.forced,
+        })
+    }
+
+    fn adapt(&self, nodes: &[&N], replication_factor: usize) -> Self {
+        let depth = nodes
+            .iter()
+            .map(|node| node.topology().len())
+            .fold(self.depth, usize::max);
+        let levels = self.levels().max(depth);
+        let domains = nodes
+            .iter()
+            .map(|node| (Self::key(*node), Self::domains_of(*node, levels)))
+            .collect::<HashMap<_, _, RandomState>>();
+
+        // Replicas cannot be spread over a level with fewer domains than
+        // replicas, whatever the placement.
+        let forced = (self.level..depth)
+            .take_while(|&level| {
+                let count = domains
+                    .values()
+                    .map(|domains| domains[level])
+                    .collect::<HashSet<_>>()
+                    .len();
+                count < replication_factor
+            })
+            .count();
+
+        Self {
+            level: self.level,
+            caps: self.caps.clone(),
+            selected: Vec::new(),
+            depth,
+            domains: Arc::new(domains),
+            forced,
+        }
+    }
+
+    fn forced_fallbacks(&self) -> usize {
+        self.forced
+    }
+}
+
+/// Role of a node in a replica set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplicaRole {
+    /// Voting member, storing the data.
+    Voter,
+
+    /// Non-voting member, storing the data (e.g. catching up, or serving
+    /// reads only).
+    Learner,
+
+    /// Voting member, storing no data.
+    Witness,
+}
+
+impl ReplicaRole {
+    /// Whether the replica stores the data, i.e. can be used as a source of
+    /// data.
+    pub fn stores_data(&self) -> bool {
+        !matches!(self, Self::Witness)
+    }
+
+    /// Whether the replica votes.
+    pub fn votes(&self) -> bool {
+        !matches!(self, Self::Learner)
+    }
+}
+
+/// Number of replicas of each role in a replica set.
+///
+/// Roles are assigned by rank: voters first (so that the primary is always a
+/// voter), then learners, then witnesses. Total number of replicas must match
+/// the replication factor of the keyspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicaRoles {
+    voters: usize,
+    learners: usize,
+    witnesses: usize,
+}
+
+impl ReplicaRoles {
+    /// Replica sets of `voters` voters, `learners` learners, and `witnesses`
+    /// witnesses.
+    ///
+    /// At least one voter is required.
+    pub fn new(voters: usize, learners: usize, witnesses: usize) -> KeyspaceResult<Self> {
+        if voters == 0 {
+            return Err(KeyspaceError::InvalidReplicaRoles(
+                voters, learners, witnesses,
+            ));
+        }
+        Ok(Self {
+            voters,
+            learners,
+            witnesses,
+        })
+    }
+
+    /// Number of voters.
+    pub fn voters(&self) -> usize {
+        self.voters
+    }
+
+    /// Number of learners.
+    pub fn learners(&self) -> usize {
+        self.learners
+    }
+
+    /// Number of witnesses.
+    pub fn witnesses(&self) -> usize {
+        self.witnesses
+    }
+
+    /// Total number of replicas.
+    pub fn total(&self) -> usize {
+        self.voters + self.learners + self.witnesses
+    }
+
+    /// Role of the replica with the given rank (`0` for primary).
+    pub fn role_of(&self, rank: usize) -> ReplicaRole {
+        if rank < self.voters {
+            ReplicaRole::Voter
+        } else if rank < self.voters + self.learners {
+            ReplicaRole::Learner
+        } else {
+            ReplicaRole::Witness
+        }
+    }
+}
+
+/// Role of the replica with the given rank, where all replicas are voters,
+/// unless roles are configured.
+pub(crate) fn role_of(roles: Option<ReplicaRoles>, rank: usize) -> ReplicaRole {
+    roles.map_or(ReplicaRole::Voter, |roles| roles.role_of(rank))
+}
+
+/// Replication factor and strategy assigned to a range of the keyspace.
+#[derive(Debug, Clone)]
+pub(crate) struct RangeReplication<R> {
+    pub key_range: KeyRange,
+    pub factor: usize,
+    pub strategy: Option<R>,
+}
+
+/// Replication settings of a keyspace.
+///
+/// Ranges of the keyspace may override the replication factor, and
+/// optionally the strategy. Settings are resolved per shard (or sub-shard),
+/// by the start of its key range, and ranges assigned later take precedence.
+#[derive(Debug, Clone)]
+pub(crate) struct Replication<R> {
+    pub strategy: R,
+    pub factor: usize,
+    pub ranges: Vec<RangeReplication<R>>,
+}
+
+impl<R> Replication<R> {
+    /// Replication with no range overrides.
+    pub fn new(strategy: R, factor: usize) -> Self {
+        Self {
+            strategy,
+            factor,
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Replication strategy and factor of the shard with the given key range.
+    pub fn resolve(&self, key_range: KeyRange) -> (&R, usize) {
+        match self
+            .ranges
+            .iter()
+            .rev()
+            .find(|range| range.key_range.contains(key_range.start()))
+        {
+            Some(range) => (
+                range.strategy.as_ref().unwrap_or(&self.strategy),
+                range.factor,
+            ),
+            None => (&self.strategy, self.factor),
+        }
+    }
+
+    /// The largest replication factor used in the keyspace.
+    pub fn max_factor(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| range.factor)
+            .fold(self.factor, usize::max)
+    }
+    /// Replication with the strategies prepared for placing replica sets over
+    /// the given nodes (see [`ContextualReplicationStrategy::prepare`]), each
+    /// for the replication factor it is used with.
+    pub fn prepare<N>(&self, nodes: &[NodeRef<N>]) -> Self
+    where
+        N: KeyspaceNode,
+        R: ContextualReplicationStrategy<N>,
+    {
+        let nodes = nodes.iter().map(|node| &***node).collect::<Vec<_>>();
+        Self {
+            strategy: self.strategy.prepare(&nodes, self.factor),
+            factor: self.factor,
+            ranges: self
+                .ranges
+                .iter()
+                .map(|range| RangeReplication {
+                    key_range: range.key_range,
+                    factor: range.factor,
+                    strategy: Some(
+                        range
+                            .strategy
+                            .as_ref()
+                            .unwrap_or(&self.strategy)
+                            .prepare(&nodes, range.factor),
+                    ),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Trace of a replica set selection.
+#[derive(Debug)]
+pub(crate) struct SelectionTrace<N: KeyspaceNode> {
+    /// Selected nodes, set when the replica set cannot be filled.
+    pub selected: Vec<NodeRef<N>>,
+
+    /// Nodes rejected by each tier of the strategy.
+    pub rejected: Vec<Vec<NodeRef<N>>>,
+}
+
+impl<N: KeyspaceNode> Default for SelectionTrace<N> {
+    fn default() -> Self {
+        Self {
+            selected: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+}
+
+/// Substitutes of the nodes of a replica set that are down, as `(rank, node)`
+/// tuples, where rank is the position of the substituted node.
+pub(crate) type Substitutes<N> = Vec<(usize, NodeRef<N>)>;
+
+/// Replication factor, that is set at runtime instead of compile time.
+///
+/// Keyspaces with this replication factor (see
+/// [`KeyspaceBuilder::with_runtime_replication_factor`](crate::KeyspaceBuilder::with_runtime_replication_factor))
+/// store their replica sets on the heap, and can change their replication
+/// factor on the fly (see
+/// [`Keyspace::set_replication_factor`](crate::Keyspace::set_replication_factor)).
+pub const DYNAMIC_RF: usize = 0;
+
+/// Nodes of a replica set, stored inline, unless the size of the set is only
+/// known at runtime (see [`DYNAMIC_RF`]).
+#[derive(Debug)]
+enum ReplicaNodes<N: KeyspaceNode, const RF: usize> {
+    Inline([NodeRef<N>; RF]),
+    Dynamic(Box<[NodeRef<N>]>),
+}
+
+impl<N: KeyspaceNode, const RF: usize> Clone for ReplicaNodes<N, RF> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Inline(nodes) => Self::Inline(nodes.clone()),
+            Self::Dynamic(nodes) => Self::Dynamic(nodes.clone()),
+        }
+    }
+}
+
 /// Set of nodes that are used to store a replica of the data.
 #[derive(Debug)]
-pub(crate) struct ReplicaSet<N: KeyspaceNode, const RF: usize>([NodeRef<N>; RF]);
+pub(crate) struct ReplicaSet<N: KeyspaceNode, const RF: usize> {
+    nodes: ReplicaNodes<N, RF>,
+
+    /// Number of fallback strategies that were needed to fill the set, `0`
+    /// if the replication strategy was satisfied.
+    fallback_tier: usize,
+
+    /// Number of fallback tiers forced by the topology of the nodes (see
+    /// [`ReplicationStrategy::forced_fallbacks`]).
+    forced_tiers: usize,
+}
 
 impl<N: KeyspaceNode, const RF: usize> Clone for ReplicaSet<N, RF> {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            nodes: self.nodes.clone(),
+            fallback_tier: self.fallback_tier,
+            forced_tiers: self.forced_tiers,
+        }
     }
 }
 
 impl<N: KeyspaceNode, const RF: usize> PartialEq for ReplicaSet<N, RF> {
     fn eq(&self, other: &Self) -> bool {
-        if self.0.len() != other.0.len() {
+        if self.len() != other.len() {
             return false;
         }
-        self.0.iter().all(|n| other.0.contains(n))
+        self.iter().all(|n| other.contains(n))
     }
 }
 
 impl<N: KeyspaceNode, const RF: usize> Eq for ReplicaSet<N, RF> {}
 
 impl<N: KeyspaceNode, const RF: usize> Deref for ReplicaSet<N, RF> {
-    type Target = [NodeRef<N>; RF];
+    type Target = [NodeRef<N>];
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        match &self.nodes {
+            ReplicaNodes::Inline(nodes) => nodes,
+            ReplicaNodes::Dynamic(nodes) => nodes,
+        }
     }
 }
 
 impl<N: KeyspaceNode, const RF: usize> ReplicaSet<N, RF> {
-    pub fn try_from_iter<I: IntoIterator<Item = NodeRef<N>>>(iter: I) -> KeyspaceResult<Self> {
+    /// Selects replica set for the given key range from the candidate nodes,
+    /// ordered by preference.
+    ///
+    /// Replica set consists of `replication_factor` nodes, which must match
+    /// `RF`, unless it is [`DYNAMIC_RF`].
+    ///
+    /// Each replica set gets a fresh copy of the replication strategy. If the
+    /// strategy cannot fill the replica set, its fallback tiers are tried in
+    /// order, each filling the remaining slots.
+    pub fn select<'a, I, R>(
+        candidates: I,
+        replication_strategy: &R,
+        replication_factor: usize,
+        key_range: KeyRange,
+        counts: &PlacementCounts<N>,
+    ) -> KeyspaceResult<Self>
+    where
+        N: 'a,
+        I: IntoIterator<Item = &'a NodeRef<N>>,
+        R: ContextualReplicationStrategy<N>,
+    {
+        Self::select_traced(
+            candidates,
+            replication_strategy,
+            replication_factor,
+            key_range,
+            counts,
+            None,
+        )
+    }
+
+    /// Selects replica set, same as [`ReplicaSet::select`], recording the
+    /// selected nodes, and the nodes rejected by each tier of the strategy.
+    pub fn select_traced<'a, I, R>(
+        candidates: I,
+        replication_strategy: &R,
+        replication_factor: usize,
+        key_range: KeyRange,
+        counts: &PlacementCounts<N>,
+        mut trace: Option<&mut SelectionTrace<N>>,
+    ) -> KeyspaceResult<Self>
+    where
+        N: 'a,
+        I: IntoIterator<Item = &'a NodeRef<N>>,
+        R: ContextualReplicationStrategy<N>,
+    {
+        let mut reject = |tier: usize, node: &NodeRef<N>| {
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.rejected.resize_with(tier + 1, Vec::new);
+                trace.rejected[tier].push(node.clone());
+            }
+        };
+
+        let mut strategy = replication_strategy.clone();
+        let mut checked = Vec::new();
+        let mut selected = Vec::with_capacity(replication_factor);
+        for node in candidates {
+            checked.push(node);
+            if strategy.is_eligible(node, &ReplicaContext::new(key_range, &selected, counts)) {
+                selected.push(node.clone());
+                if selected.len() == replication_factor {
+                    break;
+                }
+            } else {
+                reject(0, node);
+            }
+        }
+
+        // All candidates have been checked, relax the constraints.
+        let mut fallback_tier = 0;
+        while selected.len() < replication_factor {
+            let Some(relaxed) = strategy.relaxed() else {
+                if let Some(trace) = trace {
+                    trace.selected = selected;
+                }
+                return Err(KeyspaceError::IncompleteReplicaSet);
+            };
+            strategy = relaxed;
+            fallback_tier += 1;
+
+            for (rank, node) in selected.iter().enumerate() {
+                strategy.is_eligible(
+                    node,
+                    &ReplicaContext::new(key_range, &selected[..rank], counts),
+                );
+            }
+            for node in &checked {
+                if selected.len() == replication_factor {
+                    break;
+                }
+                if selected.contains(node) {
+                    continue;
+                }
+                if strategy.is_eligible(node, &ReplicaContext::new(key_range, &selected, counts)) {
+                    selected.push((*node).clone());
+                } else {
+                    reject(fallback_tier, node);
+                }
+            }
+        }
+
+        let mut replica_set = Self::try_from_iter(selected, replication_factor)?;
+        replica_set.fallback_tier = fallback_tier;
+        replica_set.forced_tiers = replication_strategy.forced_relaxations();
+        Ok(replica_set)
+    }
+
+    /// Creates replica set of the first `replication_factor` nodes (which
+    /// must match `RF`, unless it is [`DYNAMIC_RF`]).
+    pub fn try_from_iter<I: IntoIterator<Item = NodeRef<N>>>(
+        iter: I,
+        replication_factor: usize,
+    ) -> KeyspaceResult<Self> {
         use std::array::from_fn;
         let mut iter = iter.into_iter();
+
+        if RF == DYNAMIC_RF {
+            let dynamic = iter.take(replication_factor).collect::<Box<[_]>>();
+            if dynamic.len() < replication_factor {
+                return Err(KeyspaceError::IncompleteReplicaSet);
+            }
+            return Ok(ReplicaSet {
+                nodes: ReplicaNodes::Dynamic(dynamic),
+                fallback_tier: 0,
+                forced_tiers: 0,
+            });
+        }
+
         let mut count = 0;
-        let items: [NodeRef<N>; RF] = from_fn(|_| {
-            iter.next()
-                .and_then(|item| {
-                    count += 1;
-                    Some(item)
-                })
-                .unwrap_or_default()
-        });
+        let nodes: [NodeRef<N>; RF] =
+            from_fn(|_| iter.next().inspect(|_| count += 1).unwrap_or_default());
 
         if count < RF {
             return Err(KeyspaceError::IncompleteReplicaSet);
         }
 
-        Ok(ReplicaSet(items))
+        Ok(ReplicaSet {
+            nodes: ReplicaNodes::Inline(nodes),
+            fallback_tier: 0,
+            forced_tiers: 0,
+        })
+    }
+
+    /// Nodes of the replica set, along with their roles (all voters, unless
+    /// roles are given).
+    pub fn with_roles(
+        &self,
+        roles: Option<ReplicaRoles>,
+    ) -> impl Iterator<Item = (NodeRef<N>, ReplicaRole)> {
+        self.iter()
+            .enumerate()
+            .map(move |(rank, node)| (node.clone(), role_of(roles, rank)))
+    }
+
+    /// Selects substitutes of the nodes of the set that are down, from the
+    /// candidate nodes ordered by preference.
+    ///
+    /// Candidates that are members of the set, or are down themselves, are
+    /// skipped. The strategy is first given the members that are up, so that
+    /// substitutes satisfy it along with them, and its fallback tiers are
+    /// tried in order, if it cannot substitute every node that is down.
+    ///
+    /// Nodes that cannot be substituted are omitted.
+    pub fn substitutes<'a, I, R, F>(
+        &self,
+        candidates: I,
+        replication_strategy: &R,
+        key_range: KeyRange,
+        counts: &PlacementCounts<N>,
+        is_down: F,
+    ) -> Substitutes<N>
+    where
+        N: 'a,
+        I: IntoIterator<Item = &'a NodeRef<N>>,
+        R: ContextualReplicationStrategy<N>,
+        F: Fn(&N::Id) -> bool,
+    {
+        let down = (0..self.len())
+            .filter(|&rank| is_down(self[rank].id()))
+            .collect::<Vec<_>>();
+        if down.is_empty() {
+            return Vec::new();
+        }
+        let candidates = candidates
+            .into_iter()
+            .filter(|node| !self.contains(node) && !is_down(node.id()))
+            .collect::<Vec<_>>();
+
+        let mut strategy = replication_strategy.clone();
+        let mut substitutes = Vec::with_capacity(down.len());
+        loop {
+            let mut selected = Vec::with_capacity(self.len());
+            let up = self.iter().filter(|node| !is_down(node.id()));
+            for node in up.chain(substitutes.iter().map(|(_, node)| node)) {
+                strategy.is_eligible(node, &ReplicaContext::new(key_range, &selected, counts));
+                selected.push(node.clone());
+            }
+            for node in &candidates {
+                if substitutes.len() == down.len() {
+                    break;
+                }
+                if selected.contains(node) {
+                    continue;
+                }
+                if strategy.is_eligible(node, &ReplicaContext::new(key_range, &selected, counts)) {
+                    selected.push((*node).clone());
+                    substitutes.push((down[substitutes.len()], (*node).clone()));
+                }
+            }
+
+            if substitutes.len() == down.len() {
+                return substitutes;
+            }
+            match strategy.relaxed() {
+                Some(relaxed) => strategy = relaxed,
+                None => return substitutes,
+            }
+        }
+    }
+
+    /// Reorders the nodes, so that the nodes that are also in the other
+    /// replica set keep their positions in it, and the rest of the nodes take
+    /// the freed positions, in order.
+    ///
+    /// Replica sets of different sizes are left as is.
+    pub fn align(&mut self, other: &Self) {
+        let nodes = self.nodes_mut();
+        if nodes.len() != other.len() {
+            return;
+        }
+
+        let mut incoming = nodes
+            .iter()
+            .filter(|node| !other.contains(node))
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter();
+        let aligned = other
+            .iter()
+            .map(|node| {
+                if nodes.contains(node) {
+                    node.clone()
+                } else {
+                    incoming.next().expect("Each left node is replaced")
+                }
+            })
+            .collect::<Vec<_>>();
+        nodes.clone_from_slice(&aligned);
+    }
+
+    /// Nodes of the replica set, which can be reordered, but not replaced
+    /// (as the fallback tier would no longer be valid).
+    pub fn nodes_mut(&mut self) -> &mut [NodeRef<N>] {
+        match &mut self.nodes {
+            ReplicaNodes::Inline(nodes) => nodes,
+            ReplicaNodes::Dynamic(nodes) => nodes,
+        }
+    }
+
+    /// Number of fallback tiers of the replication strategy that were needed
+    /// to fill the replica set.
+    ///
+    /// Replica set with a non-zero tier does not satisfy the constraints of
+    /// the replication strategy.
+    pub fn fallback_tier(&self) -> usize {
+        self.fallback_tier
+    }
+
+    /// Whether the replica set is degraded, i.e. it needed more fallback tiers
+    /// than the topology of the nodes forces.
+    pub fn is_degraded(&self) -> bool {
+        self.fallback_tier > self.forced_tiers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::mem::size_of};
+
+    #[test]
+    fn replica_set_size() {
+        #[derive(Debug, Hash, PartialEq, Eq)]
+        struct Node(u64);
+
+        impl KeyspaceNode for Node {
+            type Id = u64;
+
+            fn id(&self) -> &Self::Id {
+                &self.0
+            }
+        }
+
+        // Nodes are stored once, either inline or on the heap, along with the
+        // discriminant and the fallback tiers.
+        let overhead = 3 * size_of::<usize>();
+        assert!(size_of::<ReplicaSet<Node, 3>>() <= size_of::<[NodeRef<Node>; 3]>() + overhead);
+        assert!(size_of::<ReplicaSet<Node, 5>>() <= size_of::<[NodeRef<Node>; 5]>() + overhead);
+        assert!(
+            size_of::<ReplicaSet<Node, DYNAMIC_RF>>()
+                <= size_of::<Box<[NodeRef<Node>]>>() + overhead
+        );
     }
 }
@@ -4,17 +4,40 @@ use {
         KeyspaceError,
         KeyspaceNode,
         KeyspaceResult,
-        ReplicationStrategy,
+        PlacementAlgorithm,
         interval::KeyRange,
-        node::Nodes,
-        replication::ReplicaSet,
+        node::{NodeRef, Nodes},
+        placement::{self, Placement},
+        replication::{
+            ContextualReplicationStrategy,
+            PlacementCounts,
+            ReplicaRoles,
+            ReplicaSet,
+            Replication,
+            SelectionTrace,
+            Substitutes,
+        },
+    },
+    std::{
+        collections::{BTreeMap, HashMap},
+        ops::Deref,
     },
-    hrw_hash::HrwNodes,
-    std::ops::Deref,
 };
 
+/// Maximum number of bits by which a single shard can be split.
+///
+/// Splitting a shard by `k` bits divides it into `2^k` equally sized
+/// sub-shards.
+pub const MAX_SPLIT_BITS: u8 = 16;
+
+/// Number of bits of the key position that are used to address a shard.
+const SHARD_BITS: u32 = 16;
+
+/// Number of bits of the key position within a shard.
+const SHARD_OFFSET_BITS: u32 = KeyPosition::BITS - SHARD_BITS;
+
 /// Shard index.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct ShardIdx(u16);
 
 impl Deref for ShardIdx {
@@ -30,46 +53,131 @@ impl ShardIdx {
 
     /// Creates a new shard index from the given key position.
     pub fn from_position(pos: KeyPosition) -> Self {
-        ShardIdx((pos >> 48) as u16)
+        ShardIdx((pos >> SHARD_OFFSET_BITS) as u16)
+    }
+
+    /// Returns the range of keys controlled by the shard.
+    pub fn key_range(&self) -> KeyRange {
+        self.sub_key_range(0, 0)
+    }
+
+    /// Returns the range of keys of the `sub_idx`-th sub-shard, when the shard
+    /// is split by `bits` bits.
+    ///
+    /// Unsplit shard is a shard split by 0 bits, with a single sub-shard.
+    fn sub_key_range(&self, bits: u8, sub_idx: u64) -> KeyRange {
+        let width = 1u64 << (SHARD_OFFSET_BITS - bits as u32);
+        let start = ((self.0 as u64) << SHARD_OFFSET_BITS) + sub_idx * width;
+        KeyRange::new(start, start.checked_add(width))
+    }
+
+    /// Key ranges and placement seeds of the sub-shards, when the shard is
+    /// split by `bits` bits (the whole shard, if `bits` is 0).
+    fn sub_shards(self, bits: u8) -> impl Iterator<Item = (KeyRange, u64)> {
+        (0..1u64 << bits).map(move |sub_idx| {
+            let seed = if bits == 0 {
+                placement::seed(&self.0)
+            } else {
+                placement::seed(&(self.0, bits, sub_idx))
+            };
+            (self.sub_key_range(bits, sub_idx), seed)
+        })
+    }
+}
+
+/// Returns the index of a sub-shard that the key position falls into, when
+/// the shard is split by `bits` bits.
+fn sub_idx(pos: KeyPosition, bits: u8) -> u64 {
+    (pos >> (SHARD_OFFSET_BITS - bits as u32)) & ((1u64 << bits) - 1)
+}
+
+/// Replica sets controlling a single shard.
+#[derive(Debug)]
+enum ShardReplicas<N: KeyspaceNode, const RF: usize> {
+    /// The whole shard is controlled by a single replica set.
+    Whole(ReplicaSet<N, RF>),
+
+    /// The shard is split into `2^bits` sub-shards, each controlled by its
+    /// own replica set.
+    Split {
+        bits: u8,
+        replica_sets: Vec<ReplicaSet<N, RF>>,
+    },
+}
+
+impl<N: KeyspaceNode, const RF: usize> Clone for ShardReplicas<N, RF> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Whole(replica_set) => Self::Whole(replica_set.clone()),
+            Self::Split { bits, replica_sets } => Self::Split {
+                bits: *bits,
+                replica_sets: replica_sets.clone(),
+            },
+        }
+    }
+}
+
+impl<N: KeyspaceNode, const RF: usize> ShardReplicas<N, RF> {
+    /// Number of bits by which the shard is split.
+    fn bits(&self) -> u8 {
+        match self {
+            Self::Whole(_) => 0,
+            Self::Split { bits, .. } => *bits,
+        }
+    }
+
+    /// Returns replica set of the `sub_idx`-th sub-shard.
+    fn replica_set(&self, sub_idx: u64) -> &ReplicaSet<N, RF> {
+        match self {
+            Self::Whole(replica_set) => replica_set,
+            Self::Split { replica_sets, .. } => &replica_sets[sub_idx as usize],
+        }
+    }
+
+    /// Returns mutable replica set of the `sub_idx`-th sub-shard.
+    fn replica_set_mut(&mut self, sub_idx: u64) -> &mut ReplicaSet<N, RF> {
+        match self {
+            Self::Whole(replica_set) => replica_set,
+            Self::Split { replica_sets, .. } => &mut replica_sets[sub_idx as usize],
+        }
     }
 }
 
 /// Shard is a portion of the keyspace controlled by a set of nodes.
 #[derive(Debug)]
 pub(crate) struct Shard<'a, N: KeyspaceNode, const RF: usize> {
-    idx: ShardIdx,
+    key_range: KeyRange,
     replica_set: &'a ReplicaSet<N, RF>,
 }
 
 impl<'a, N: KeyspaceNode, const RF: usize> Shard<'a, N, RF> {
-    /// Creates a new shard with the given index and replica set.
-    pub fn new(idx: ShardIdx, replica_set: &'a ReplicaSet<N, RF>) -> Self {
-        Self { idx, replica_set }
+    /// Creates a new shard with the given key range and replica set.
+    fn new(key_range: KeyRange, replica_set: &'a ReplicaSet<N, RF>) -> Self {
+        Self {
+            key_range,
+            replica_set,
+        }
     }
 
     /// Returns the replica set of the shard.
-    pub fn replica_set(&self) -> &ReplicaSet<N, RF> {
+    pub fn replica_set(&self) -> &'a ReplicaSet<N, RF> {
         self.replica_set
     }
 
     /// Returns the range of keys that are controlled by this shard.
     pub fn key_range(&self) -> KeyRange {
-        let start = (self.idx.0 as u64) << 48;
-        let end = if self.idx.0 == u16::MAX {
-            None
-        } else {
-            Some(((self.idx.0 as u64) + 1) << 48)
-        };
-        KeyRange::new(start, end)
+        self.key_range
     }
 }
 
 /// Keyspace is uniformly divided into shards.
 ///
 /// Each shard is a replica set of nodes that are responsible for the data in
-/// that keyspace portion.
+/// that keyspace portion. Individual shards can be further split into
+/// sub-shards, each with its own replica set, so that load of a hot shard is
+/// spread over more nodes.
 #[derive(Debug)]
-pub(crate) struct Shards<N: KeyspaceNode, const RF: usize>(Vec<ReplicaSet<N, RF>>);
+pub(crate) struct Shards<N: KeyspaceNode, const RF: usize>(Vec<ShardReplicas<N, RF>>);
 
 /// `ReplicaSet<N, RF>` holds `NodeRef<N>` (which implements `Clone`).
 /// Derive macro cannot see that `NodeRef<N>` implements `Clone`, and requires
@@ -83,51 +191,395 @@ impl<N: KeyspaceNode, const RF: usize> Clone for Shards<N, RF> {
 impl<N: KeyspaceNode, const RF: usize> Shards<N, RF> {
     /// Creates a new keyspace with each shard controlled by a replica set of
     /// nodes.
-    pub fn new<R>(nodes: &Nodes<N>, replication_strategy: R) -> KeyspaceResult<Self>
+    pub fn new<R>(
+        nodes: &Nodes<N>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication: &Replication<R>,
+    ) -> KeyspaceResult<Self>
     where
-        N: KeyspaceNode,
-        R: ReplicationStrategy<N>,
+        R: ContextualReplicationStrategy<N>,
     {
-        if nodes.len() < RF {
-            return Err(KeyspaceError::NotEnoughNodes(RF));
+        Self::with_splits(nodes, placement, replication, &BTreeMap::new())
+    }
+
+    /// Creates a new keyspace, where given shards are split into sub-shards.
+    ///
+    /// Splits are provided as a mapping from shard index to the number of bits
+    /// the shard is split by.
+    pub fn with_splits<R>(
+        nodes: &Nodes<N>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication: &Replication<R>,
+        splits: &BTreeMap<ShardIdx, u8>,
+    ) -> KeyspaceResult<Self>
+    where
+        R: ContextualReplicationStrategy<N>,
+    {
+        let replication_factor = replication.max_factor();
+        if nodes.len() < replication_factor {
+            return Err(KeyspaceError::NotEnoughNodes(replication_factor));
         }
 
-        // Highest random weight (HRW) algorithm is used to select the nodes.
-        let hrw = HrwNodes::new(nodes.values());
+        let nodes = nodes.values();
+        let replication = replication.prepare(&nodes);
+        let placement = placement.placement(nodes);
 
+        let mut counts = PlacementCounts::default();
         let mut shards = Vec::with_capacity(ShardIdx::MAX.0 as usize + 1);
         for idx in 0..=ShardIdx::MAX.0 {
-            // Each replica set gets a fresh copy of the replication strategy.
-            let mut replication_strategy = replication_strategy.clone();
-            let selected_replicas = hrw.sorted(&idx).filter_map(|node| {
-                if replication_strategy.is_eligible_replica(&node) {
-                    Some(node.clone())
-                } else {
-                    None
+            let bits = splits.get(&ShardIdx(idx)).copied().unwrap_or(0);
+            shards.push(Self::place_shard(
+                &*placement,
+                &replication,
+                &mut counts,
+                ShardIdx(idx),
+                bits,
+            )?);
+        }
+
+        Ok(Self(shards))
+    }
+
+    /// Returns a copy of the keyspace, with the given shard split by `bits`
+    /// bits.
+    ///
+    /// Splitting by 0 bits merges the shard back (if it was split).
+    pub fn split<R>(
+        &self,
+        nodes: &Nodes<N>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication: &Replication<R>,
+        idx: ShardIdx,
+        bits: u8,
+    ) -> KeyspaceResult<Self>
+    where
+        R: ContextualReplicationStrategy<N>,
+    {
+        if bits > MAX_SPLIT_BITS {
+            return Err(KeyspaceError::InvalidSplitBits(bits));
+        }
+
+        // Counts reflect all the other shards.
+        let mut counts = PlacementCounts::default();
+        for (other, shard) in self.0.iter().enumerate() {
+            if other != idx.0 as usize {
+                for sub_idx in 0..1u64 << shard.bits() {
+                    counts.record(&shard.replica_set(sub_idx)[..]);
                 }
-            });
+            }
+        }
+
+        let nodes = nodes.values();
+        let replication = replication.prepare(&nodes);
+        let placement = placement.placement(nodes);
+        let shard = Self::place_shard(&*placement, &replication, &mut counts, idx, bits)?;
+
+        let mut shards = self.clone();
+        shards.0[idx.0 as usize] = shard;
+        Ok(shards)
+    }
+
+    /// Places all the shards, as [`Shards::with_splits`] does, without
+    /// stopping at the shards that cannot be filled.
+    ///
+    /// Shards that cannot be filled are passed to `unfilled`, along with
+    /// their replication factor and the trace of their replica set selection.
+    /// Their partial replica sets do not count towards the placement counts.
+    pub fn check<R, F>(
+        nodes: Vec<NodeRef<N>>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication: &Replication<R>,
+        splits: &BTreeMap<ShardIdx, u8>,
+        mut unfilled: F,
+    ) where
+        R: ContextualReplicationStrategy<N>,
+        F: FnMut(KeyRange, usize, SelectionTrace<N>),
+    {
+        let replication = replication.prepare(&nodes);
+        let placement = placement.placement(nodes);
 
-            shards.push(ReplicaSet::try_from_iter(selected_replicas)?);
+        let mut counts = PlacementCounts::default();
+        for idx in 0..=ShardIdx::MAX.0 {
+            let idx = ShardIdx(idx);
+            let bits = splits.get(&idx).copied().unwrap_or(0);
+            for (key_range, seed) in idx.sub_shards(bits) {
+                let (replication_strategy, replication_factor) = replication.resolve(key_range);
+                let mut trace = SelectionTrace::default();
+                match ReplicaSet::<N, RF>::select_traced(
+                    placement.candidates(seed),
+                    replication_strategy,
+                    replication_factor,
+                    key_range,
+                    &counts,
+                    Some(&mut trace),
+                ) {
+                    Ok(replica_set) => counts.record(&replica_set[..]),
+                    Err(_) => unfilled(key_range, replication_factor, trace),
+                }
+            }
         }
+    }
 
-        Ok(Self(shards))
+    /// Shards that are split into sub-shards, along with the number of bits
+    /// they are split by.
+    pub fn splits(&self) -> BTreeMap<ShardIdx, u8> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, shard)| shard.bits() > 0)
+            .map(|(idx, shard)| (ShardIdx(idx as u16), shard.bits()))
+            .collect()
     }
 
     /// Iterator over the shards in the keyspace.
+    ///
+    /// Split shards are returned as their sub-shards.
     pub fn iter(&self) -> impl Iterator<Item = Shard<'_, N, RF>> {
+        self.0.iter().enumerate().flat_map(|(idx, shard)| {
+            let idx = ShardIdx(idx as u16);
+            let bits = shard.bits();
+            (0..1u64 << bits).map(move |sub_idx| {
+                Shard::new(idx.sub_key_range(bits, sub_idx), shard.replica_set(sub_idx))
+            })
+        })
+    }
+
+    /// Iterator over the shards of two keyspaces, aligned at the finest
+    /// granularity of the two.
+    ///
+    /// Whenever a shard is split differently in the keyspaces, it is returned
+    /// as sub-shards of the more finely split one, so that each returned key
+    /// range is controlled by a single replica set in both keyspaces.
+    pub fn zip<'a>(
+        &'a self,
+        other: &'a Self,
+    ) -> impl Iterator<Item = (KeyRange, &'a ReplicaSet<N, RF>, &'a ReplicaSet<N, RF>)> {
         self.0
             .iter()
+            .zip(other.0.iter())
             .enumerate()
-            .map(|(idx, replica_set)| Shard::new(ShardIdx(idx as u16), replica_set))
+            .flat_map(|(idx, (a, b))| {
+                let idx = ShardIdx(idx as u16);
+                let bits = a.bits().max(b.bits());
+                (0..1u64 << bits).map(move |sub_idx| {
+                    (
+                        idx.sub_key_range(bits, sub_idx),
+                        a.replica_set(sub_idx >> (bits - a.bits())),
+                        b.replica_set(sub_idx >> (bits - b.bits())),
+                    )
+                })
+            })
+    }
+
+    /// Reorders the nodes of the replica sets, so that the nodes controlling
+    /// the same keys in the other keyspace keep their positions (see
+    /// [`ReplicaSet::align`]).
+    ///
+    /// Each shard (or sub-shard) is aligned with the replica set controlling
+    /// its start in the other keyspace.
+    pub fn align(&mut self, other: &Self) {
+        for (idx, shard) in self.0.iter_mut().enumerate() {
+            let idx = ShardIdx(idx as u16);
+            let bits = shard.bits();
+            for sub_idx in 0..1u64 << bits {
+                let start = idx.sub_key_range(bits, sub_idx).start();
+                shard
+                    .replica_set_mut(sub_idx)
+                    .align(other.replica_set(start));
+            }
+        }
     }
 
-    /// Returns the number of shards in the keyspace.
+    /// Reorders the replica sets, so that primaries are spread over the nodes
+    /// in proportion to their capacities, keeping the members of the replica
+    /// sets.
+    ///
+    /// Replica sets with the same members as in the previous keyspace (if
+    /// any) keep their order, so that a membership change only reassigns
+    /// primaries of the replica sets it changes. Of the rest, primary of a
+    /// replica set is kept, unless its node has no capacity, or has already
+    /// reached its share of primaries, in which case one of the members
+    /// (voters, if there are replica roles) takes over: the one with the
+    /// fewest primaries relative to its capacity (on ties, the one ranked
+    /// closer to the primary). Nodes with no capacity never take over. Old
+    /// primary swaps ranks with the new one.
+    pub fn balance_primaries(
+        &mut self,
+        nodes: &Nodes<N>,
+        roles: Option<ReplicaRoles>,
+        previous: Option<&Self>,
+    ) {
+        let eligible = roles.map_or(usize::MAX, |roles| roles.voters());
+        let total_capacity = nodes
+            .values()
+            .iter()
+            .map(|node| node.capacity())
+            .sum::<usize>();
+        if total_capacity == 0 {
+            return;
+        }
+        let replica_sets = self.iter().count() as f64;
+        let share = |node: &NodeRef<N>| {
+            (replica_sets * node.capacity() as f64 / total_capacity as f64).ceil() as usize
+        };
+
+        // Keep the order of the replica sets that have not changed.
+        let mut counts = HashMap::<N::Id, usize>::new();
+        let mut changed = Vec::new();
+        for (idx, shard) in self.0.iter_mut().enumerate() {
+            let idx = ShardIdx(idx as u16);
+            let bits = shard.bits();
+            for sub_idx in 0..1u64 << bits {
+                let key_range = idx.sub_key_range(bits, sub_idx);
+                let replica_set = shard.replica_set_mut(sub_idx);
+                let unchanged = previous
+                    .map(|previous| previous.shard(key_range.start()))
+                    .filter(|other| {
+                        other.key_range() == key_range && other.replica_set() == &*replica_set
+                    });
+                match unchanged {
+                    Some(other) => {
+                        replica_set
+                            .nodes_mut()
+                            .clone_from_slice(other.replica_set());
+                        *counts.entry(replica_set[0].id().clone()).or_default() += 1;
+                    }
+                    None => changed.push((idx.0 as usize, sub_idx)),
+                }
+            }
+        }
+
+        // Keep the primaries of the changed replica sets that are within
+        // their share.
+        let mut overflow = Vec::new();
+        for (idx, sub_idx) in changed {
+            let primary = &self.0[idx].replica_set(sub_idx)[0];
+            let count = counts.entry(primary.id().clone()).or_default();
+            if *count < share(primary) && primary.capacity() > 0 {
+                *count += 1;
+            } else {
+                overflow.push((idx, sub_idx));
+            }
+        }
+
+        for (idx, sub_idx) in overflow {
+            let nodes = self.0[idx].replica_set_mut(sub_idx).nodes_mut();
+            let load = |node: &NodeRef<N>| {
+                let count = counts.get(node.id()).copied().unwrap_or(0);
+                (count + 1) as f64 / node.capacity() as f64
+            };
+            let rank = (0..nodes.len().min(eligible))
+                .filter(|&rank| nodes[rank].capacity() > 0)
+                .min_by(|a, b| load(&nodes[*a]).total_cmp(&load(&nodes[*b])))
+                .unwrap_or(0);
+            *counts.entry(nodes[rank].id().clone()).or_default() += 1;
+            nodes.swap(0, rank);
+        }
+    }
+
+    /// Substitutes of the replicas that are down, for each shard (or
+    /// sub-shard) having such replicas (see [`ReplicaSet::substitutes`]).
+    ///
+    /// Candidates are provided by the placement over the given nodes, and the
+    /// strategy sees the placement counts of all the shards. Returned as
+    /// `(key range, substitutes)` tuples, in order of the key ranges.
+    pub fn substitutes<R, F>(
+        &self,
+        nodes: &Nodes<N>,
+        placement: &dyn PlacementAlgorithm<N>,
+        replication: &Replication<R>,
+        is_down: F,
+    ) -> Vec<(KeyRange, Substitutes<N>)>
+    where
+        R: ContextualReplicationStrategy<N>,
+        F: Fn(&N::Id) -> bool,
+    {
+        let nodes = nodes.values();
+        let replication = replication.prepare(&nodes);
+        let placement = placement.placement(nodes);
+        let mut counts = PlacementCounts::default();
+        for shard in self.iter() {
+            counts.record(&shard.replica_set()[..]);
+        }
+
+        let mut substitutes = Vec::new();
+        for (idx, shard) in self.0.iter().enumerate() {
+            let idx = ShardIdx(idx as u16);
+            for (sub_idx, (key_range, seed)) in idx.sub_shards(shard.bits()).enumerate() {
+                let replica_set = shard.replica_set(sub_idx as u64);
+                if !replica_set.iter().any(|node| is_down(node.id())) {
+                    continue;
+                }
+                let (replication_strategy, _) = replication.resolve(key_range);
+                substitutes.push((
+                    key_range,
+                    replica_set.substitutes(
+                        placement.candidates(seed),
+                        replication_strategy,
+                        key_range,
+                        &counts,
+                        &is_down,
+                    ),
+                ));
+            }
+        }
+        substitutes
+    }
+
+    /// Returns the number of (top-level) shards in the keyspace.
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
-    /// Returns replica set for the shard at the given index.
-    pub fn replica_set(&self, idx: ShardIdx) -> &ReplicaSet<N, RF> {
-        &self.0[idx.0 as usize]
+    /// Returns replica set controlling the given key position.
+    pub fn replica_set(&self, pos: KeyPosition) -> &ReplicaSet<N, RF> {
+        let shard = &self.0[ShardIdx::from_position(pos).0 as usize];
+        shard.replica_set(sub_idx(pos, shard.bits()))
+    }
+
+    /// Returns the shard (or sub-shard, if split) containing the given key
+    /// position.
+    pub fn shard(&self, pos: KeyPosition) -> Shard<'_, N, RF> {
+        let idx = ShardIdx::from_position(pos);
+        let shard = &self.0[idx.0 as usize];
+        let bits = shard.bits();
+        let sub_idx = sub_idx(pos, bits);
+        Shard::new(idx.sub_key_range(bits, sub_idx), shard.replica_set(sub_idx))
+    }
+
+    /// Selects replica sets of the shard, split into `2^bits` sub-shards
+    /// (unsplit shard, if `bits` is 0).
+    ///
+    /// Placement of the selected replica sets is recorded into `counts`.
+    fn place_shard<R>(
+        placement: &dyn Placement<N>,
+        replication: &Replication<R>,
+        counts: &mut PlacementCounts<N>,
+        idx: ShardIdx,
+        bits: u8,
+    ) -> KeyspaceResult<ShardReplicas<N, RF>>
+    where
+        R: ContextualReplicationStrategy<N>,
+    {
+        let mut replica_sets = idx
+            .sub_shards(bits)
+            .map(|(key_range, seed)| {
+                let (replication_strategy, replication_factor) = replication.resolve(key_range);
+                let candidates = placement.candidates(seed);
+                ReplicaSet::select(
+                    candidates,
+                    replication_strategy,
+                    replication_factor,
+                    key_range,
+                    counts,
+                )
+                .inspect(|replica_set: &ReplicaSet<N, RF>| counts.record(&replica_set[..]))
+            })
+            .collect::<KeyspaceResult<Vec<_>>>()?;
+
+        if bits == 0 {
+            return Ok(ShardReplicas::Whole(replica_sets.remove(0)));
+        }
+        Ok(ShardReplicas::Split { bits, replica_sets })
     }
 }
@@ -0,0 +1,428 @@
+//! Cluster churn simulation.
+//!
+//! [`Simulation`] drives a keyspace through a randomized sequence of node
+//! additions, removals and capacity changes, and measures each step against
+//! the theoretical optimum:
+//!
+//! ```
+//! use keyspace::{
+//!     KeyspaceBuilder,
+//!     simulation::{SimNode, Simulation},
+//! };
+//!
+//! let nodes = (0..10).map(|id| SimNode::new(id, 1));
+//! let keyspace = KeyspaceBuilder::new(nodes)
+//!     .build()
+//!     .expect("Failed to create keyspace");
+//!
+//! let mut simulation = Simulation::new(keyspace)
+//!     .with_keys(10_000)
+//!     .with_capacities(1, 4)
+//!     .with_seed(42);
+//! for report in simulation.run(5).expect("Failed to simulate") {
+//!     println!("{report:?}");
+//!     if let Some(ratio) = report.movement_ratio() {
+//!         println!("moved {ratio:.2}x the minimum");
+//!     }
+//!     assert_eq!(report.degraded_shards(), 0);
+//!     assert_eq!(report.invariant_violation(), None);
+//! }
+//! ```
+//!
+//! Steps are reproducible: the same keyspace, settings and seed yield the
+//! same sequence of events and measurements.
+//!
+//! Available with the `simulation` feature.
+
+use {
+    super::{ContextualReplicationStrategy, Keyspace, KeyspaceError, KeyspaceNode, KeyspaceResult},
+    std::{
+        collections::{HashMap, HashSet},
+        hash::{BuildHasher, Hash, Hasher},
+    },
+};
+
+/// Node of a simulated cluster.
+///
+/// Nodes are identified (compared and hashed) by their ids only, so that a
+/// capacity change neither reshuffles the placement, nor makes the node look
+/// like a new one to the migration plans.
+#[derive(Debug, Clone)]
+pub struct SimNode {
+    id: u64,
+    capacity: usize,
+    topology: Vec<String>,
+}
+
+impl SimNode {
+    /// Node with the given id and capacity.
+    pub fn new(id: u64, capacity: usize) -> Self {
+        Self {
+            id,
+            capacity,
+            topology: Vec::new(),
+        }
+    }
+
+    /// Place the node into the given zone, i.e. set its topology to
+    /// `["zone{zone}"]`.
+    pub fn with_zone(mut self, zone: usize) -> Self {
+        self.topology = vec![format!("zone{zone}")];
+        self
+    }
+}
+
+impl PartialEq for SimNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for SimNode {}
+
+impl Hash for SimNode {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        self.id.hash(state);
+    }
+}
+
+impl KeyspaceNode for SimNode {
+    type Id = u64;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn topology(&self) -> &[String] {
+        &self.topology
+    }
+}
+
+/// Change applied to the cluster in a step of the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChurnEvent {
+    /// Node with the given id and capacity was added.
+    Added(u64, usize),
+
+    /// Node with the given id and capacity was removed.
+    Removed(u64, usize),
+
+    /// Capacity of the node with the given id was changed (from, to).
+    CapacityChanged(u64, usize, usize),
+}
+
+/// Measurements of a single step of the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepReport {
+    event: ChurnEvent,
+    moved: f64,
+    minimal: f64,
+    fairness: f64,
+    degraded_shards: usize,
+    invariant_violation: Option<String>,
+    plan_size: usize,
+}
+
+impl StepReport {
+    /// Change applied to the cluster.
+    pub fn event(&self) -> ChurnEvent {
+        self.event
+    }
+
+    /// Fraction of the replicas of the synthetic keys moved to other nodes.
+    pub fn moved(&self) -> f64 {
+        self.moved
+    }
+
+    /// Theoretical minimum of the fraction of replicas moved: the change of
+    /// the capacity share of the node added, removed or resized.
+    pub fn minimal(&self) -> f64 {
+        self.minimal
+    }
+
+    /// Ratio of the moved fraction of replicas to the theoretical minimum,
+    /// i.e. `1.0` for the optimal movement.
+    ///
+    /// Returns `None` when nothing had to move (e.g. a node with no capacity
+    /// was added), so that the ratio is undefined.
+    pub fn movement_ratio(&self) -> Option<f64> {
+        (self.minimal > 0.0).then(|| self.moved / self.minimal)
+    }
+
+    /// Largest relative deviation of a node's share of replicas from its
+    /// share of the total capacity, e.g. `0.1` when some node stores 10% more
+    /// (or less) than it should.
+    ///
+    /// Nodes with no capacity have no share to deviate from, so they are not
+    /// taken into account.
+    pub fn fairness(&self) -> f64 {
+        self.fairness
+    }
+
+    /// Number of shards whose replica sets do not satisfy the replication
+    /// strategy (see [`Keyspace::degraded_shards`]).
+    pub fn degraded_shards(&self) -> usize {
+        self.degraded_shards
+    }
+
+    /// Description of the first violated invariant of the keyspace after
+    /// the step, if any (see [`Keyspace::check_invariants`]).
+    pub fn invariant_violation(&self) -> Option<&str> {
+        self.invariant_violation.as_deref()
+    }
+
+    /// Size of the migration plan of the step: the number of intervals to
+    /// pull and to clean up, over all the nodes.
+    pub fn plan_size(&self) -> usize {
+        self.plan_size
+    }
+}
+
+/// Randomized churn of the nodes of a keyspace.
+///
+/// Each step either adds a node, removes one, or changes capacity of one,
+/// keeping the number of nodes within the limits (see
+/// [`Simulation::with_node_limits`]). Added nodes get the next free id, a
+/// random capacity (see [`Simulation::with_capacities`]), and are spread over
+/// the zones (see [`Simulation::with_zones`]).
+pub struct Simulation<R, const RF: usize, H>
+where
+    R: ContextualReplicationStrategy<SimNode>,
+    H: BuildHasher,
+{
+    keyspace: Keyspace<SimNode, R, RF, H>,
+    keys: u64,
+    capacities: (usize, usize),
+    limits: (usize, usize),
+    zones: usize,
+    rng: SplitMix64,
+}
+
+impl<R, const RF: usize, H> Simulation<R, RF, H>
+where
+    R: ContextualReplicationStrategy<SimNode>,
+    H: BuildHasher,
+{
+    /// Simulation of the churn of the nodes of the given keyspace.
+    ///
+    /// Defaults to 100,000 synthetic keys, nodes of capacity 1, and between
+    /// the replication factor and twice the initial number of nodes.
+    pub fn new(keyspace: Keyspace<SimNode, R, RF, H>) -> Self {
+        let nodes = keyspace.nodes.len();
+        let min_nodes = keyspace.replication.max_factor().max(1);
+        Self {
+            keyspace,
+            keys: 100_000,
+            capacities: (1, 1),
+            limits: (min_nodes, (2 * nodes).max(min_nodes + 1)),
+            zones: 0,
+            rng: SplitMix64(0),
+        }
+    }
+
+    /// Set the number of synthetic keys, used to measure data movement.
+    pub fn with_keys(mut self, keys: u64) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Set the range of capacities (inclusive) of the added nodes, which is
+    /// also used for capacity changes. Capacities are only changed when the
+    /// range has more than one value.
+    pub fn with_capacities(mut self, min: usize, max: usize) -> Self {
+        self.capacities = (min.max(1), max.max(min).max(1));
+        self
+    }
+
+    /// Set the minimal and maximal number of nodes.
+    pub fn with_node_limits(mut self, min: usize, max: usize) -> Self {
+        self.limits = (min, max);
+        self
+    }
+
+    /// Spread the added nodes over the given number of zones (see
+    /// [`SimNode::with_zone`]). By default, nodes have no topology.
+    pub fn with_zones(mut self, zones: usize) -> Self {
+        self.zones = zones;
+        self
+    }
+
+    /// Seed the random choice of the events.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = SplitMix64(seed);
+        self
+    }
+
+    /// Simulated keyspace.
+    pub fn keyspace(&self) -> &Keyspace<SimNode, R, RF, H> {
+        &self.keyspace
+    }
+
+    /// Runs the given number of steps, returning their reports.
+    pub fn run(&mut self, steps: usize) -> KeyspaceResult<Vec<StepReport>> {
+        (0..steps).map(|_| self.step()).collect()
+    }
+
+    /// Applies a random event to the keyspace, and measures the outcome.
+    pub fn step(&mut self) -> KeyspaceResult<StepReport> {
+        let mut nodes = self.keyspace.nodes.values();
+        nodes.sort_by_key(|node| node.id);
+        let total_capacity = nodes.iter().map(|node| node.capacity).sum::<usize>();
+        let before = self.replica_sets();
+
+        let (min_nodes, max_nodes) = self.limits;
+        let (min_capacity, max_capacity) = self.capacities;
+        let mut choices = Vec::with_capacity(3);
+        if nodes.len() < max_nodes {
+            choices.push(0);
+        }
+        if nodes.len() > min_nodes {
+            choices.push(1);
+        }
+        if min_capacity < max_capacity && !nodes.is_empty() {
+            choices.push(2);
+        }
+        if choices.is_empty() {
+            choices.push(0);
+        }
+
+        let (event, plan, minimal) = match choices[self.rng.below(choices.len())] {
+            0 => {
+                let id = nodes.last().map_or(0, |node| node.id + 1);
+                let capacity = self.capacity(None);
+                let mut node = SimNode::new(id, capacity);
+                if self.zones > 0 {
+                    node = node.with_zone(id as usize % self.zones);
+                }
+                let minimal = capacity as f64 / (total_capacity + capacity) as f64;
+                let plan = self.keyspace.add_node(node)?;
+                (ChurnEvent::Added(id, capacity), plan, minimal)
+            }
+            1 => {
+                let node = &nodes[self.rng.below(nodes.len())];
+                let minimal = node.capacity as f64 / total_capacity as f64;
+                let plan = self.keyspace.remove_node(&node.id)?;
+                (ChurnEvent::Removed(node.id, node.capacity), plan, minimal)
+            }
+            _ => {
+                let node = &nodes[self.rng.below(nodes.len())];
+                let capacity = self.capacity(Some(node.capacity));
+                let updated_capacity = total_capacity - node.capacity + capacity;
+                let minimal = (capacity as f64 / updated_capacity as f64
+                    - node.capacity as f64 / total_capacity as f64)
+                    .abs();
+                let plan = self.keyspace.add_node(SimNode {
+                    capacity,
+                    ..node.inner().clone()
+                })?;
+                (
+                    ChurnEvent::CapacityChanged(node.id, node.capacity, capacity),
+                    plan,
+                    minimal,
+                )
+            }
+        };
+
+        // Replicas of the synthetic keys that were not there before.
+        let after = self.replica_sets();
+        let moved = before
+            .iter()
+            .zip(&after)
+            .map(|(old, new)| new.iter().filter(|id| !old.contains(id)).count())
+            .sum::<usize>();
+        let replicas = after.iter().map(Vec::len).sum::<usize>();
+
+        let ids = nodes
+            .iter()
+            .map(|node| node.id)
+            .chain(self.keyspace.nodes.keys())
+            .collect::<HashSet<_>>();
+        let plan_size = ids
+            .iter()
+            .map(|id| plan.pull_intervals(id).count() + plan.cleanup_intervals(id).count())
+            .sum();
+
+        Ok(StepReport {
+            event,
+            moved: moved as f64 / replicas as f64,
+            minimal,
+            fairness: self.fairness(),
+            degraded_shards: self.keyspace.degraded_shards().count(),
+            invariant_violation: self.invariant_violation(),
+            plan_size,
+        })
+    }
+
+    /// Random capacity within the range, different from the current one.
+    fn capacity(&mut self, current: Option<usize>) -> usize {
+        let (min, max) = self.capacities;
+        loop {
+            let capacity = min + self.rng.below(max - min + 1);
+            if Some(capacity) != current || min == max {
+                return capacity;
+            }
+        }
+    }
+
+    /// Ids of the replicas of each synthetic key.
+    fn replica_sets(&self) -> Vec<Vec<u64>> {
+        (0..self.keys)
+            .map(|key| self.keyspace.replicas(&key).map(|node| node.id).collect())
+            .collect()
+    }
+
+    /// Description of the violated invariant of the keyspace, if any.
+    fn invariant_violation(&self) -> Option<String> {
+        match self.keyspace.check_invariants() {
+            Ok(()) => None,
+            Err(KeyspaceError::InvariantViolation(violation)) => Some(violation),
+            Err(err) => Some(err.to_string()),
+        }
+    }
+
+    /// Largest relative deviation of a node's share of replicas (over all the
+    /// shards) from its share of capacity, of the nodes with capacity.
+    fn fairness(&self) -> f64 {
+        let mut replicas = HashMap::new();
+        let mut total = 0;
+        for (_, node) in self.keyspace.iter() {
+            *replicas.entry(node.id).or_insert(0usize) += 1;
+            total += 1;
+        }
+
+        let nodes = self.keyspace.nodes.values();
+        let total_capacity = nodes.iter().map(|node| node.capacity).sum::<usize>();
+        nodes
+            .iter()
+            .filter(|node| node.capacity > 0)
+            .map(|node| {
+                let share = replicas.get(&node.id).copied().unwrap_or(0) as f64 / total as f64;
+                let expected = node.capacity as f64 / total_capacity as f64;
+                (share / expected - 1.0).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+}
+
+/// SplitMix64 pseudo-random number generator, so that simulations are
+/// reproducible without extra dependencies.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
@@ -0,0 +1,146 @@
+use {
+    super::{
+        ContextualReplicationStrategy,
+        KeyspaceError,
+        KeyspaceNode,
+        KeyspaceResult,
+        NodeRef,
+        PlacementAlgorithm,
+        PlacementReport,
+        ReportedId,
+        UnfilledShard,
+        replication::Replication,
+        sharding::{ShardIdx, Shards},
+    },
+    std::collections::BTreeMap,
+};
+
+/// Checks whether replica sets of all the shards can be filled from the given
+/// nodes.
+pub(crate) fn validate<N, R, const RF: usize>(
+    nodes: Vec<NodeRef<N>>,
+    placement: &dyn PlacementAlgorithm<N>,
+    replication: &Replication<R>,
+    splits: &BTreeMap<ShardIdx, u8>,
+) -> KeyspaceResult<()>
+where
+    N: KeyspaceNode,
+    N::Id: Send + Sync + 'static,
+    R: ContextualReplicationStrategy<N>,
+{
+    let ids = |nodes: Vec<NodeRef<N>>| {
+        nodes
+            .iter()
+            .map(|node| ReportedId::new(node.id().clone()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut unfilled = Vec::new();
+    Shards::<N, RF>::check(
+        nodes,
+        placement,
+        replication,
+        splits,
+        |key_range, replication_factor, trace| {
+            unfilled.push(UnfilledShard::new(
+                key_range,
+                replication_factor,
+                ids(trace.selected),
+                trace.rejected.into_iter().map(ids).collect(),
+            ));
+        },
+    );
+
+    if unfilled.is_empty() {
+        Ok(())
+    } else {
+        Err(KeyspaceError::InfeasiblePlacement(Box::new(
+            PlacementReport::new(unfilled),
+        )))
+    }
+}
+
+/// Finds a minimal set of spare nodes that makes placement feasible, when
+/// added to the given nodes.
+///
+/// Spares are picked greedily, by the number of missing replicas they
+/// eliminate, and then pruned, so that no spare can be removed from the
+/// returned set without making the placement infeasible again (the set is
+/// not guaranteed to be the smallest possible one). If the placement is
+/// infeasible even with all the spares added, the report of such a placement
+/// is returned as an error.
+pub(crate) fn minimal_spares<N, R, const RF: usize>(
+    nodes: Vec<NodeRef<N>>,
+    spares: Vec<NodeRef<N>>,
+    placement: &dyn PlacementAlgorithm<N>,
+    replication: &Replication<R>,
+    splits: &BTreeMap<ShardIdx, u8>,
+) -> KeyspaceResult<Vec<N::Id>>
+where
+    N: KeyspaceNode,
+    N::Id: Send + Sync + 'static,
+    R: ContextualReplicationStrategy<N>,
+{
+    let with = |chosen: &[usize]| {
+        let mut nodes = nodes.clone();
+        nodes.extend(chosen.iter().map(|idx| spares[*idx].clone()));
+        nodes
+    };
+    let missing = |chosen: &[usize]| {
+        let mut missing = 0;
+        Shards::<N, RF>::check(
+            with(chosen),
+            placement,
+            replication,
+            splits,
+            |_, replication_factor, trace| missing += replication_factor - trace.selected.len(),
+        );
+        missing
+    };
+
+    let mut chosen = Vec::new();
+    let mut remaining = missing(&chosen);
+    while remaining > 0 {
+        let best = (0..spares.len())
+            .filter(|idx| !chosen.contains(idx))
+            .map(|idx| {
+                let mut candidate = chosen.clone();
+                candidate.push(idx);
+                (missing(&candidate), idx)
+            })
+            .min();
+        match best {
+            Some((missing, idx)) if missing < remaining => {
+                chosen.push(idx);
+                remaining = missing;
+            }
+            _ => break,
+        }
+    }
+
+    // No single spare helps any further, but several of them together might
+    // (e.g. a strategy requiring two more distinct zones).
+    if remaining > 0 {
+        chosen = (0..spares.len()).collect();
+        validate::<N, R, RF>(with(&chosen), placement, replication, splits)?;
+    }
+
+    // Drop the spares that are not needed, latest picks are checked first,
+    // as early picks are the most helpful ones.
+    for idx in chosen.clone().into_iter().rev() {
+        let pruned = chosen
+            .iter()
+            .copied()
+            .filter(|other| *other != idx)
+            .collect::<Vec<_>>();
+        if missing(&pruned) == 0 {
+            chosen = pruned;
+        }
+    }
+
+    chosen.sort_unstable();
+    Ok(chosen
+        .into_iter()
+        .map(|idx| spares[idx].id().clone())
+        .collect())
+}
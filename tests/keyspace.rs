@@ -1,15 +1,41 @@
 use {
     keyspace::{
+        BatchGrouping,
+        ConsistencyLevel,
+        ContextualReplicationStrategy,
+        DYNAMIC_RF,
         DefaultReplicationStrategy,
+        ErasureCoding,
+        FailureDomainReplicationStrategy,
+        HashTag,
+        Hrw,
+        IntegerEncoder,
+        JumpHash,
         KeyRange,
         KeyspaceBuilder,
         KeyspaceError,
+        KeyspaceKey,
         KeyspaceNode,
+        KeyspaceRegistry,
+        KeyspaceResult,
+        Locality,
+        Maglev,
+        MigrationPlan,
+        NodeRef,
+        PlacementAlgorithm,
+        ReplicaContext,
+        ReplicaRole,
+        ReplicaRoles,
         ReplicationStrategy,
+        Ring,
+        TableConfig,
+        combinators::{And, Filter, MaxPerGroup, Not, Or},
+        simulation::{ChurnEvent, SimNode, Simulation},
     },
+    proptest::prelude::*,
     std::{
         collections::{HashMap, HashSet},
-        hash::{BuildHasher, Hash},
+        hash::{BuildHasher, Hash, Hasher},
     },
 };
 
@@ -31,6 +57,80 @@ impl Node {
     }
 }
 
+/// Node with topology labels, widest failure domain first, and capacity.
+///
+/// Nodes are compared and hashed by their ids only, so a node is placed the
+/// same as [`Node`] with the same id, whatever its labels and capacity.
+#[derive(Debug, Clone)]
+struct LabelledNode {
+    id: String,
+    topology: Vec<String>,
+    capacity: usize,
+}
+
+impl LabelledNode {
+    /// Creates a node of capacity 1, with the given topology labels.
+    fn new(id: &str, topology: &[&str]) -> Self {
+        LabelledNode {
+            id: id.to_string(),
+            topology: topology.iter().map(|label| label.to_string()).collect(),
+            capacity: 1,
+        }
+    }
+
+    /// Sets capacity of the node.
+    fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Topology label at the given level.
+    fn label(&self, level: usize) -> &str {
+        &self.topology[level]
+    }
+}
+
+impl PartialEq for LabelledNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for LabelledNode {}
+
+impl Hash for LabelledNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl KeyspaceNode for LabelledNode {
+    type Id = String;
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn topology(&self) -> &[String] {
+        &self.topology
+    }
+}
+
+/// Creates `count` nodes, spread round-robin across `zones` zones, each
+/// labelled by its zone and by its id as the host.
+fn zoned_nodes(count: usize, zones: usize) -> Vec<LabelledNode> {
+    (0..count)
+        .map(|i| {
+            let id = format!("node{i}");
+            LabelledNode::new(&id, &[&format!("zone{}", i % zones), &id])
+        })
+        .collect()
+}
+
 #[test]
 fn keyspace_builder() {
     let init_nodes = (0..3)
@@ -154,6 +254,7 @@ fn replica_set_fair_distribution() {
 }
 
 #[test]
+#[allow(clippy::map_identity)]
 fn add_node_migration_plan() {
     // Populate the keyspace with nodes.
     // Then add a new node and check migration plan.
@@ -224,6 +325,7 @@ fn add_node_migration_plan() {
 }
 
 #[test]
+#[allow(clippy::map_identity)]
 fn remove_node_migration_plan() {
     // Populate the keyspace with nodes.
     // Then remove a node and check migration plan.
@@ -485,3 +587,2535 @@ fn migrations_and_rebalancing() {
         "Source nodes should be from initial nodes"
     );
 }
+
+#[test]
+fn split_and_merge_shard() {
+    const MAX_NODES: usize = 16;
+    let init_nodes = (0..MAX_NODES)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    let mut keyspace = KeyspaceBuilder::new(init_nodes)
+        .build()
+        .expect("Failed to create keyspace");
+    let total_intervals = keyspace.iter().count();
+
+    // Split the shard that holds a hot key range into 4 sub-shards.
+    let shard_range = KeyRange::new(0x1234 << 48, Some(0x1235 << 48));
+    let hot_key = (0..)
+        .map(|i| format!("key{}", i))
+        .find(|key| shard_range.contains(keyspace.position_of(key)))
+        .unwrap();
+    let hot_pos = keyspace.position_of(&hot_key);
+    let migrations = keyspace.split_shard(hot_pos, 2).expect("Failed to split");
+    assert_eq!(keyspace.version(), 1);
+    assert_eq!(keyspace.splits().collect::<Vec<_>>(), vec![(
+        shard_range,
+        2
+    )]);
+
+    // Each sub-shard is listed separately, with its own replica set.
+    let sub_ranges = keyspace
+        .iter()
+        .map(|(key_range, _)| key_range)
+        .filter(|key_range| match (key_range, shard_range) {
+            (KeyRange::Bounded(start, _), KeyRange::Bounded(lo, hi)) => *start >= lo && *start < hi,
+            _ => false,
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(sub_ranges.len(), 4);
+    assert_eq!(keyspace.iter().count(), total_intervals + 3 * 3);
+    assert!(sub_ranges.contains(&KeyRange::new(0x1234_4000 << 32, Some(0x1234_8000 << 32))));
+
+    // Only the split shard needs data movement.
+    for (_, intervals) in migrations.iter() {
+        for interval in intervals {
+            assert!(sub_ranges.contains(interval.key_range()));
+        }
+    }
+
+    // Keys are routed to the sub-shard replica sets.
+    let sub_replicas = keyspace
+        .iter()
+        .filter(|(key_range, _)| key_range.contains(hot_pos))
+        .map(|(_, node)| node)
+        .collect::<Vec<_>>();
+    assert_eq!(sub_replicas.len(), 3);
+    assert_eq!(
+        keyspace.replicas(&hot_key).collect::<Vec<_>>(),
+        sub_replicas
+    );
+
+    // Splits survive node additions.
+    keyspace
+        .add_node(Node::new("node16"))
+        .expect("Failed to add node");
+    assert_eq!(keyspace.splits().count(), 1);
+
+    // Invalid split requests.
+    assert_eq!(
+        keyspace.split_shard(hot_pos, 0).err(),
+        Some(KeyspaceError::InvalidSplitBits(0))
+    );
+    assert_eq!(
+        keyspace.split_shard(hot_pos, 17).err(),
+        Some(KeyspaceError::InvalidSplitBits(17))
+    );
+    assert_eq!(
+        keyspace.merge_shard(0).err(),
+        Some(KeyspaceError::ShardNotSplit)
+    );
+
+    // Merge the shard back, sub-shards move their data to the merged replica set.
+    let old_nodes = keyspace.iter().count();
+    let migrations = keyspace.merge_shard(hot_pos).expect("Failed to merge");
+    assert_eq!(keyspace.splits().count(), 0);
+    assert_eq!(keyspace.iter().count(), old_nodes - 3 * 3);
+    for (_, intervals) in migrations.iter() {
+        for interval in intervals {
+            assert!(sub_ranges.contains(interval.key_range()));
+        }
+    }
+
+    // Keys are routed to the merged replica set.
+    let merged_replicas = keyspace
+        .iter()
+        .filter(|(key_range, _)| *key_range == shard_range)
+        .map(|(_, node)| node)
+        .collect::<Vec<_>>();
+    assert_eq!(merged_replicas.len(), 3);
+    assert_eq!(
+        keyspace.replicas(&hot_key).collect::<Vec<_>>(),
+        merged_replicas
+    );
+}
+
+#[test]
+fn position_level_routing() {
+    let init_nodes = (0..8)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    let mut keyspace = KeyspaceBuilder::new(init_nodes)
+        .build()
+        .expect("Failed to create keyspace");
+
+    // Routing by a pre-computed position matches routing by key.
+    let pos = keyspace.position_of(&"key0");
+    assert_eq!(
+        keyspace.replicas(&"key0").collect::<Vec<_>>(),
+        keyspace.replicas_for_position(pos).collect::<Vec<_>>()
+    );
+
+    // Shards are uniform, unless split.
+    assert_eq!(keyspace.shard_of(0), KeyRange::new(0, Some(1 << 48)));
+    assert_eq!(
+        keyspace.shard_of(u64::MAX),
+        KeyRange::new(0xffff << 48, None)
+    );
+    keyspace.split_shard(1 << 48, 1).expect("Failed to split");
+    assert_eq!(
+        keyspace.shard_of((1 << 48) + (1 << 47)),
+        KeyRange::new((1 << 48) + (1 << 47), Some(2 << 48))
+    );
+
+    // Range within a single shard.
+    let intervals = keyspace
+        .replicas_for_range(KeyRange::new(10, Some(20)))
+        .collect::<Vec<_>>();
+    assert_eq!(intervals.len(), 1);
+    assert_eq!(intervals[0].key_range(), &KeyRange::new(10, Some(20)));
+    assert_eq!(
+        intervals[0].nodes(),
+        &keyspace.replicas_for_position(10).collect::<Vec<_>>()
+    );
+
+    // Range spanning shards (including the split one) is clipped to the requested
+    // boundaries.
+    let intervals = keyspace
+        .replicas_for_range(KeyRange::new(100, Some((2 << 48) + 100)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        intervals
+            .iter()
+            .map(|interval| *interval.key_range())
+            .collect::<Vec<_>>(),
+        vec![
+            KeyRange::new(100, Some(1 << 48)),
+            KeyRange::new(1 << 48, Some((1 << 48) + (1 << 47))),
+            KeyRange::new((1 << 48) + (1 << 47), Some(2 << 48)),
+            KeyRange::new(2 << 48, Some((2 << 48) + 100)),
+        ]
+    );
+    for interval in &intervals {
+        let start = interval.key_range().start();
+        assert_eq!(
+            interval.nodes(),
+            &keyspace.replicas_for_position(start).collect::<Vec<_>>()
+        );
+    }
+
+    // Unbounded range runs to the end of the keyspace.
+    let intervals = keyspace
+        .replicas_for_range(KeyRange::new(u64::MAX - 1, None))
+        .collect::<Vec<_>>();
+    assert_eq!(intervals.len(), 1);
+    assert_eq!(intervals[0].key_range(), &KeyRange::new(u64::MAX - 1, None));
+    assert_eq!(
+        keyspace.replicas_for_range(KeyRange::new(0, None)).count(),
+        65537
+    );
+
+    // Empty range.
+    assert_eq!(
+        keyspace
+            .replicas_for_range(KeyRange::new(5, Some(5)))
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn route_batch() {
+    let init_nodes = (0..8)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    let mut keyspace = KeyspaceBuilder::new(init_nodes)
+        .build()
+        .expect("Failed to create keyspace");
+    keyspace
+        .split_shard(keyspace.position_of(&"key7"), 4)
+        .expect("Failed to split");
+
+    let keys = (0..10_000).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+
+    // Group by primary node: one group per node.
+    let batch = keyspace.route_batch(&keys, BatchGrouping::Primary);
+    assert_eq!(batch.len(), 8);
+    let mut seen = HashSet::new();
+    for group in batch.iter() {
+        assert_eq!(group.nodes().len(), 1);
+        for &key_idx in group.keys() {
+            assert!(seen.insert(key_idx), "Key must be in a single group");
+            let primary = keyspace.replicas(&keys[key_idx]).next().unwrap();
+            assert_eq!(group.primary(), &primary);
+        }
+    }
+    assert_eq!(seen.len(), keys.len());
+
+    // Group by full replica set.
+    let batch = keyspace.route_batch(&keys, BatchGrouping::ReplicaSet);
+    assert!(batch.len() > 8);
+    let mut seen = HashSet::new();
+    let mut replica_sets = HashSet::new();
+    for group in batch.iter() {
+        assert!(replica_sets.insert(group.nodes().to_vec()));
+        for &key_idx in group.keys() {
+            assert!(seen.insert(key_idx), "Key must be in a single group");
+            let replicas = keyspace.replicas(&keys[key_idx]).collect::<Vec<_>>();
+            assert_eq!(group.nodes(), &replicas[..]);
+        }
+    }
+    assert_eq!(seen.len(), keys.len());
+
+    // Empty batch.
+    let batch = keyspace.route_batch::<String>(&[], BatchGrouping::Primary);
+    assert!(batch.is_empty());
+}
+
+#[test]
+fn borrowed_replicas() {
+    let init_nodes = (0..8)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    let keyspace = KeyspaceBuilder::new(init_nodes)
+        .build()
+        .expect("Failed to create keyspace");
+
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        let replicas = keyspace.replicas(&key).collect::<Vec<_>>();
+        let borrowed = keyspace.replicas_ref(&key).collect::<Vec<_>>();
+        assert_eq!(replicas, borrowed);
+        assert_eq!(&replicas[0], keyspace.primary(&key));
+    }
+}
+
+#[test]
+fn hash_tags() {
+    let init_nodes = (0..16)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    // Without key extractor, the whole key is used, and string keys are routed
+    // the same way as with `replicas()`.
+    let keyspace = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    for key in ["key0", "{user:42}:profile", "{user:42}:cart"] {
+        assert_eq!(keyspace.position_of_key(key), keyspace.position_of(&key));
+        assert_eq!(
+            keyspace.replicas_for_key(key).collect::<Vec<_>>(),
+            keyspace.replicas(&key).collect::<Vec<_>>()
+        );
+    }
+
+    // With hash tags, only the tagged portion determines the position.
+    let keyspace = KeyspaceBuilder::new(init_nodes)
+        .with_replication_factor::<2>()
+        .with_key_extractor(HashTag::new())
+        .build()
+        .expect("Failed to create keyspace");
+
+    let profile = keyspace.position_of_key("{user:42}:profile");
+    assert_eq!(profile, keyspace.position_of_key("{user:42}:cart"));
+    assert_eq!(profile, keyspace.position_of_key(&"user:42".to_string()));
+    assert_eq!(
+        profile,
+        keyspace.position_of_key(b"orders:{user:42}".as_slice())
+    );
+    assert_ne!(profile, keyspace.position_of_key("{user:43}:profile"));
+
+    // Multi-key operations stay on the same replica set.
+    for user in 0..100 {
+        let replicas = keyspace
+            .replicas_for_key(&format!("{{user:{user}}}:profile"))
+            .collect::<Vec<_>>();
+        assert_eq!(replicas.len(), 2);
+        assert_eq!(
+            replicas,
+            keyspace
+                .replicas_for_key(&format!("{{user:{user}}}:cart"))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    // Custom key types.
+    struct OrderKey {
+        raw: String,
+    }
+
+    impl KeyspaceKey for OrderKey {
+        fn key_bytes(&self) -> &[u8] {
+            self.raw.as_bytes()
+        }
+    }
+
+    let order = OrderKey {
+        raw: "order:7{user:42}".to_string(),
+    };
+    assert_eq!(keyspace.position_of_key(&order), profile);
+
+    // Borrowed and batch key routing apply the key extractor.
+    let keys = (0..100)
+        .flat_map(|user| {
+            [
+                format!("{{user:{user}}}:profile"),
+                format!("cart{{user:{user}}}"),
+            ]
+        })
+        .collect::<Vec<_>>();
+    for key in &keys {
+        let expected = keyspace.replicas_for_key(key).collect::<Vec<_>>();
+        assert_eq!(&expected[0], keyspace.primary_for_key(key));
+    }
+
+    let batch = keyspace.route_key_batch(&keys, BatchGrouping::ReplicaSet);
+    assert_eq!(
+        batch.iter().map(|group| group.keys().len()).sum::<usize>(),
+        keys.len()
+    );
+    for group in batch.iter() {
+        for &idx in group.keys() {
+            assert_eq!(
+                group.nodes(),
+                keyspace.replicas_for_key(&keys[idx]).collect::<Vec<_>>()
+            );
+        }
+        // Keys of a user are in the same group.
+        for &idx in group.keys() {
+            assert!(group.keys().contains(&(idx ^ 1)));
+        }
+    }
+
+    // Hashable values are placed by their whole hash, even if their bytes
+    // happen to contain a tag.
+    let plain = KeyspaceBuilder::new((0..16).map(|i| Node::new(&format!("node{}", i))))
+        .with_replication_factor::<2>()
+        .build()
+        .expect("Failed to create keyspace");
+    let tagged = u64::from_le_bytes([b'{', b'A', b'}', 0, 0, 0, 0, 0]);
+    assert_eq!(keyspace.position_of(&tagged), plain.position_of(&tagged));
+    assert_ne!(keyspace.position_of(&tagged), keyspace.position_of_key("A"));
+    assert_eq!(
+        keyspace.replicas(&tagged).collect::<Vec<_>>(),
+        plain.replicas(&tagged).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        keyspace.position_of(&"{user:42}:profile"),
+        plain.position_of(&"{user:42}:profile")
+    );
+}
+
+#[test]
+fn range_partitioned_keyspace() {
+    let init_nodes = (0..8)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+
+    let mut ks = KeyspaceBuilder::new(init_nodes)
+        .build_range_partitioned(IntegerEncoder)
+        .expect("Failed to create keyspace");
+
+    // Initially, the whole keyspace is a single tablet.
+    assert_eq!(ks.boundaries().count(), 0);
+    assert_eq!(ks.tablet_of(42), KeyRange::new(0, None));
+    let replicas = ks.replicas(&0u64).collect::<Vec<_>>();
+    assert_eq!(replicas.len(), 3);
+    assert_eq!(replicas, ks.replicas(&u64::MAX).collect::<Vec<_>>());
+
+    // Split into tablets: lower tablet keeps its replicas.
+    let plan = ks.split(1000).expect("Failed to split");
+    assert_eq!(plan.version(), 1);
+    ks.split(2000).expect("Failed to split");
+    assert_eq!(ks.boundaries().collect::<Vec<_>>(), vec![1000, 2000]);
+    assert_eq!(ks.replicas(&999u64).collect::<Vec<_>>(), replicas);
+    assert_eq!(ks.tablet_of(1500), KeyRange::new(1000, Some(2000)));
+    assert_eq!(
+        ks.split(1000).err(),
+        Some(KeyspaceError::InvalidBoundary(1000))
+    );
+
+    // Any data movement is limited to the split off tablet.
+    for (_, intervals) in plan.iter() {
+        for interval in intervals {
+            assert_eq!(interval.key_range(), &KeyRange::new(1000, None));
+        }
+    }
+
+    // Range scans only touch the overlapping tablets.
+    let intervals = ks
+        .replicas_for_range(KeyRange::new(10, Some(1500)))
+        .collect::<Vec<_>>();
+    assert_eq!(intervals.len(), 2);
+    assert_eq!(intervals[0].key_range(), &KeyRange::new(10, Some(1000)));
+    assert_eq!(intervals[1].key_range(), &KeyRange::new(1000, Some(1500)));
+    assert_eq!(
+        intervals[1].nodes(),
+        &ks.replicas(&1000u64).collect::<Vec<_>>()
+    );
+
+    // Moving a boundary keeps tablet replica sets, only keys in between move.
+    let upper = ks.replicas(&1000u64).collect::<Vec<_>>();
+    let plan = ks.move_boundary(1000, 1200).expect("Failed to move");
+    assert_eq!(ks.boundaries().collect::<Vec<_>>(), vec![1200, 2000]);
+    assert_eq!(ks.replicas(&1200u64).collect::<Vec<_>>(), upper);
+    assert_eq!(ks.replicas(&1100u64).collect::<Vec<_>>(), replicas);
+    for (_, intervals) in plan.iter() {
+        for interval in intervals {
+            assert_eq!(interval.key_range(), &KeyRange::new(1000, Some(1200)));
+        }
+    }
+    assert_eq!(
+        ks.move_boundary(1200, 2000).err(),
+        Some(KeyspaceError::InvalidBoundary(2000))
+    );
+    assert_eq!(
+        ks.move_boundary(1300, 1400).err(),
+        Some(KeyspaceError::InvalidBoundary(1300))
+    );
+
+    // Merge the tablet back into the preceding one.
+    let plan = ks.merge(1200).expect("Failed to merge");
+    assert_eq!(ks.boundaries().collect::<Vec<_>>(), vec![2000]);
+    assert_eq!(ks.replicas(&1500u64).collect::<Vec<_>>(), replicas);
+    for (_, intervals) in plan.iter() {
+        for interval in intervals {
+            assert_eq!(interval.key_range(), &KeyRange::new(1200, Some(2000)));
+        }
+    }
+    assert_eq!(ks.merge(0).err(), Some(KeyspaceError::InvalidBoundary(0)));
+
+    // Node membership changes keep the boundaries.
+    let plan = ks.add_node(Node::new("node8")).expect("Failed to add node");
+    assert_eq!(ks.version(), plan.version());
+    assert_eq!(ks.boundaries().collect::<Vec<_>>(), vec![2000]);
+    assert_eq!(ks.iter().count(), 2 * 3);
+    assert!(ks.iter_node(&"node8".to_string()).count() <= 2);
+
+    // Tablet split off at a former boundary gets a replica set of its own.
+    ks.split(1000).expect("Failed to split");
+    ks.move_boundary(1000, 500).expect("Failed to move");
+    ks.split(1000).expect("Failed to split");
+    assert_ne!(
+        ks.replicas(&500u64).collect::<Vec<_>>(),
+        ks.replicas(&1000u64).collect::<Vec<_>>()
+    );
+
+    // Failed node removal leaves the keyspace intact.
+    let init_nodes = (0..3)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .build_range_partitioned(IntegerEncoder)
+        .expect("Failed to create keyspace");
+    assert_eq!(
+        ks.remove_node(&"node2".to_string()).err(),
+        Some(KeyspaceError::NotEnoughNodes(3))
+    );
+    assert_eq!(ks.version(), 0);
+    ks.add_node(Node::new("node3")).expect("Failed to add node");
+    assert_eq!(ks.iter_node(&"node2".to_string()).count(), 1);
+
+    // Range partitioned keyspaces cannot be erasure coded, and options of
+    // hash partitioned keyspaces are rejected rather than ignored.
+    let coding = ErasureCoding::new(2, 1).expect("Invalid erasure coding");
+    assert_eq!(
+        KeyspaceBuilder::new(init_nodes.clone())
+            .with_erasure_coding(coding)
+            .build_range_partitioned(IntegerEncoder)
+            .err(),
+        Some(KeyspaceError::UnsupportedErasureCoding)
+    );
+    assert_eq!(
+        KeyspaceBuilder::new(init_nodes.clone())
+            .with_key_extractor(HashTag::new())
+            .build_range_partitioned(IntegerEncoder)
+            .err(),
+        Some(KeyspaceError::UnsupportedKeyExtractor)
+    );
+    assert_eq!(
+        KeyspaceBuilder::new(init_nodes)
+            .with_primary_balancing()
+            .build_range_partitioned(IntegerEncoder)
+            .err(),
+        Some(KeyspaceError::UnsupportedPrimaryBalancing)
+    );
+}
+
+#[test]
+fn placement_algorithms() {
+    fn check<P, const RF: usize>(placement: P, max_unfairness: f64, max_movement: f64)
+    where
+        P: PlacementAlgorithm<Node> + 'static,
+    {
+        let init_nodes = (0..8)
+            .map(|i| Node::new(&format!("node{}", i)))
+            .collect::<Vec<_>>();
+        let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+            .with_replication_factor::<RF>()
+            .with_placement_algorithm(placement)
+            .build()
+            .expect("Failed to create keyspace");
+
+        // Replica sets consist of distinct nodes.
+        for key in 0..1000 {
+            let replicas = ks.replicas(&key).collect::<HashSet<_>>();
+            assert_eq!(replicas.len(), RF);
+        }
+
+        // Primary shards are spread evenly.
+        let shards = ks.iter().count() / RF;
+        let mut primaries = HashMap::new();
+        for (_, node) in ks.iter().step_by(RF) {
+            *primaries.entry(node.id().clone()).or_insert(0) += 1;
+        }
+        for node in &init_nodes {
+            let share = primaries[node.id()] as f64 * init_nodes.len() as f64 / shards as f64;
+            assert!((share - 1.0).abs() < max_unfairness, "share: {share}");
+        }
+
+        // Adding a node only moves a minimal portion of replicas to it.
+        let old_replicas = (0..1000)
+            .map(|key| ks.replicas(&key).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let node = Node::new("node8");
+        let plan = ks.add_node(node.clone()).expect("Failed to add node");
+        assert_eq!(plan.version(), 1);
+        let pulled = plan.pull_intervals(node.id()).count() as f64 / (shards * RF) as f64;
+        assert!(pulled * 9.0 < max_movement, "pulled: {pulled}");
+        let moved = (0..1000)
+            .zip(old_replicas)
+            .map(|(key, old)| {
+                let new = ks.replicas(&key).collect::<Vec<_>>();
+                new.iter().filter(|node| !old.contains(node)).count()
+            })
+            .sum::<usize>() as f64
+            / (1000 * RF) as f64;
+        assert!(moved * 9.0 < max_movement, "moved: {moved}");
+    }
+
+    check::<_, 3>(Hrw, 0.1, 1.2);
+    check::<_, 3>(Maglev::new(), 0.1, 1.2);
+    check::<_, 3>(Ring::new(), 0.5, 1.2);
+    check::<_, 1>(JumpHash, 0.1, 1.2);
+}
+
+#[test]
+fn replication_strategy_fallback() {
+    // Distinct zones, else distinct racks, else any node.
+    struct TieredStrategy {
+        tier: usize,
+        used: HashSet<String>,
+    }
+
+    impl TieredStrategy {
+        fn new(tier: usize) -> Self {
+            TieredStrategy {
+                tier,
+                used: HashSet::new(),
+            }
+        }
+    }
+
+    impl Clone for TieredStrategy {
+        fn clone(&self) -> Self {
+            TieredStrategy::new(self.tier)
+        }
+    }
+
+    impl ReplicationStrategy<LabelledNode> for TieredStrategy {
+        fn is_eligible_replica(&mut self, node: &LabelledNode) -> bool {
+            match self.tier {
+                0 | 1 => self.used.insert(node.label(self.tier).to_string()),
+                _ => true,
+            }
+        }
+
+        fn fallback(&self) -> Option<Self> {
+            (self.tier < 2).then(|| TieredStrategy::new(self.tier + 1))
+        }
+    }
+
+    // Only two zones, but three racks.
+    let init_nodes = vec![
+        LabelledNode::new("node0", &["zone1", "rack1"]),
+        LabelledNode::new("node1", &["zone1", "rack1"]),
+        LabelledNode::new("node2", &["zone1", "rack2"]),
+        LabelledNode::new("node3", &["zone1", "rack2"]),
+        LabelledNode::new("node4", &["zone2", "rack3"]),
+        LabelledNode::new("node5", &["zone2", "rack3"]),
+    ];
+    let mut ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_strategy(TieredStrategy::new(0))
+        .build()
+        .expect("Failed to create keyspace");
+
+    // All the shards are degraded to distinct racks.
+    let shards = ks.iter().count() / 3;
+    assert_eq!(ks.degraded_shards().count(), shards);
+    assert!(ks.degraded_shards().all(|(_, tier)| tier == 1));
+    for key in 0..100 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        let zones = replicas.iter().map(|n| n.label(0)).collect::<HashSet<_>>();
+        let racks = replicas.iter().map(|n| n.label(1)).collect::<HashSet<_>>();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(racks.len(), 3);
+
+        // Nodes selected by the strict strategy are kept.
+        assert_ne!(replicas[0].label(0), replicas[1].label(0));
+    }
+
+    // Losing a rack degrades shards further.
+    ks.remove_node(&"node4".to_string()).unwrap();
+    ks.remove_node(&"node5".to_string()).unwrap();
+    assert!(ks.degraded_shards().all(|(_, tier)| tier == 2));
+    assert_eq!(ks.degraded_shards().count(), shards);
+
+    // Once zones are available again, no shard is degraded.
+    ks.add_node(LabelledNode::new("node6", &["zone2", "rack3"]))
+        .unwrap();
+    let plan = ks
+        .add_node(LabelledNode::new("node7", &["zone3", "rack4"]))
+        .unwrap();
+    assert_eq!(ks.degraded_shards().count(), 0);
+    assert!(plan.pull_intervals(&"node7".to_string()).count() > 0);
+}
+
+#[test]
+fn failure_domain_replication_strategy() {
+    // Unbalanced topology, "node10" is the only node in "zone3".
+    let node = |i: usize, zone: &str| {
+        let id = format!("node{i}");
+        LabelledNode::new(&id, &[zone, &id])
+    };
+    let init_nodes = (0..4)
+        .map(|i| node(i, "zone1"))
+        .chain((4..10).map(|i| node(i, "zone2")))
+        .chain([node(10, "zone3")])
+        .collect::<Vec<_>>();
+    let zones = |replicas: &[NodeRef<LabelledNode>]| {
+        let mut zones = HashMap::new();
+        for node in replicas {
+            *zones.entry(node.topology[0].clone()).or_insert(0) += 1;
+        }
+        zones
+    };
+
+    // Replicas are spread across all the zones.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.degraded_shards().count(), 0);
+    for key in 0..1000 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        assert_eq!(zones(&replicas).len(), 3);
+        assert!(replicas.iter().any(|node| node.id() == "node10"));
+    }
+
+    // With more replicas than zones, remaining replicas are placed on distinct
+    // hosts. The topology allows no better, so shards are not degraded.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_factor::<5>()
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.degraded_shards().count(), 0);
+    for key in 0..1000 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        assert_eq!(zones(&replicas).len(), 3);
+        assert_eq!(replicas.iter().collect::<HashSet<_>>().len(), 5);
+    }
+
+    // Caps limit the number of replicas per zone.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_factor::<5>()
+        .with_replication_strategy(FailureDomainReplicationStrategy::new().with_cap(0, 2))
+        .build()
+        .expect("Failed to create keyspace");
+    for key in 0..1000 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        let zones = zones(&replicas);
+        assert_eq!(zones["zone1"], 2);
+        assert_eq!(zones["zone2"], 2);
+        assert_eq!(zones["zone3"], 1);
+    }
+
+    // Caps are never relaxed.
+    let res = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_factor::<4>()
+        .with_replication_strategy(FailureDomainReplicationStrategy::new().with_cap(0, 1))
+        .build();
+    assert_eq!(res.err(), Some(KeyspaceError::IncompleteReplicaSet));
+
+    // Losing a zone relaxes the placement to distinct hosts, instead of
+    // failing, with replicas still spread across the remaining zones.
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    ks.remove_node(&"node10".to_string()).unwrap();
+    assert_eq!(ks.degraded_shards().count(), 0);
+    for key in 0..1000 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        assert_eq!(zones(&replicas).len(), 2);
+    }
+
+    // Single region cluster, replicas are spread across zones, which is not
+    // reported as degraded.
+    let single_region = init_nodes
+        .into_iter()
+        .map(|mut node| {
+            node.topology.insert(0, "region1".to_string());
+            node
+        })
+        .collect::<Vec<_>>();
+    let ks = KeyspaceBuilder::new(single_region)
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.degraded_shards().count(), 0);
+    for key in 0..1000 {
+        let replicas = ks.replicas(&key).collect::<Vec<_>>();
+        let zones = replicas
+            .iter()
+            .map(|node| &node.topology[1])
+            .collect::<HashSet<_>>();
+        assert_eq!(zones.len(), 3);
+    }
+}
+
+#[test]
+fn replication_strategy_combinators() {
+    // Hand-written strategy, which records zones of all the checked nodes.
+    struct DistinctZoneReplicationStrategy {
+        used_zones: HashSet<String>,
+    }
+
+    impl Clone for DistinctZoneReplicationStrategy {
+        fn clone(&self) -> Self {
+            DistinctZoneReplicationStrategy {
+                used_zones: HashSet::new(),
+            }
+        }
+    }
+
+    impl ReplicationStrategy<LabelledNode> for DistinctZoneReplicationStrategy {
+        fn is_eligible_replica(&mut self, node: &LabelledNode) -> bool {
+            self.used_zones.insert(node.label(0).to_string())
+        }
+    }
+
+    let init_nodes = vec![
+        LabelledNode::new("node0", &["zone1"]),
+        LabelledNode::new("node1", &["zone1"]),
+        LabelledNode::new("node2", &["zone1"]),
+        LabelledNode::new("node3", &["zone2"]),
+        LabelledNode::new("node4", &["zone2"]),
+        LabelledNode::new("node5", &["zone3"]),
+    ];
+    // Nodes 0, 1 and 4 are in maintenance.
+    let in_maintenance =
+        |node: &LabelledNode| ["node0", "node1", "node4"].contains(&node.id().as_str());
+    let healthy = move |node: &LabelledNode| !in_maintenance(node);
+
+    // Nodes rejected by the filter do not use up zones.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(And::new(
+            DistinctZoneReplicationStrategy {
+                used_zones: HashSet::new(),
+            },
+            Filter::new(healthy),
+        ))
+        .build()
+        .expect("Failed to create keyspace");
+    for key in 0..1000 {
+        let mut replicas = ks
+            .replicas(&key)
+            .map(|n| n.id().clone())
+            .collect::<Vec<_>>();
+        replicas.sort();
+        assert_eq!(replicas, vec!["node2", "node3", "node5"]);
+    }
+
+    // Same with negation, and with per group limit instead of hand-written
+    // strategy.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(And::new(
+            MaxPerGroup::new(|node: &LabelledNode| node.label(0).to_string(), 1),
+            Not::new(Filter::new(in_maintenance)),
+        ))
+        .build()
+        .expect("Failed to create keyspace");
+    for key in 0..1000 {
+        let mut replicas = ks
+            .replicas(&key)
+            .map(|n| n.id().clone())
+            .collect::<Vec<_>>();
+        replicas.sort();
+        assert_eq!(replicas, vec!["node2", "node3", "node5"]);
+    }
+
+    // Either of the strategies.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_factor::<4>()
+        .with_replication_strategy(Or::new(
+            Filter::new(healthy),
+            Filter::new(|node: &LabelledNode| node.label(0) == "zone2"),
+        ))
+        .build()
+        .expect("Failed to create keyspace");
+    for key in 0..1000 {
+        let mut replicas = ks
+            .replicas(&key)
+            .map(|n| n.id().clone())
+            .collect::<Vec<_>>();
+        replicas.sort();
+        assert_eq!(replicas, vec!["node2", "node3", "node4", "node5"]);
+    }
+
+    // Fallbacks of composed strategies are preserved.
+    let mut ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_factor::<2>()
+        .with_replication_strategy(And::new(
+            FailureDomainReplicationStrategy::new(),
+            Filter::new(healthy),
+        ))
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.degraded_shards().count(), 0);
+    ks.add_node(LabelledNode::new("node6", &["zone1"])).unwrap();
+    ks.remove_node(&"node3".to_string()).unwrap();
+    ks.remove_node(&"node5".to_string()).unwrap();
+    let shards = ks.iter().count() / 2;
+    assert_eq!(ks.degraded_shards().count(), shards);
+    for key in 0..1000 {
+        let mut replicas = ks
+            .replicas(&key)
+            .map(|n| n.id().clone())
+            .collect::<Vec<_>>();
+        replicas.sort();
+        assert_eq!(replicas, vec!["node2", "node6"]);
+    }
+}
+
+#[test]
+fn contextual_replication_strategy() {
+    // Lower half of the keyspace is placed on EU nodes only, and no node gets
+    // more than `max_replicas` replicas.
+    #[derive(Clone)]
+    struct RegionalStrategy {
+        max_replicas: usize,
+    }
+
+    impl ContextualReplicationStrategy<LabelledNode> for RegionalStrategy {
+        fn is_eligible(
+            &mut self,
+            node: &LabelledNode,
+            ctx: &ReplicaContext<'_, LabelledNode>,
+        ) -> bool {
+            let eu_range = ctx.key_range().start() < 1 << 63;
+            (!eu_range || node.label(0) == "eu")
+                && ctx.counts().replicas(node.id()) < self.max_replicas
+                && !ctx
+                    .replicas()
+                    .iter()
+                    .any(|replica| replica.id() == node.id())
+        }
+    }
+
+    let init_nodes = (0..8)
+        .map(|i| LabelledNode::new(&format!("node{i}"), &[if i < 4 { "eu" } else { "us" }]))
+        .collect::<Vec<_>>();
+
+    // Lower half of 2^16 shards with 3 replicas each, takes 24576 replicas of
+    // each EU node, so the cap stops EU nodes from taking their share of the
+    // upper half.
+    let max_replicas = 28000;
+    let ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_strategy(RegionalStrategy { max_replicas })
+        .build()
+        .expect("Failed to create keyspace");
+
+    let mut replicas = HashMap::new();
+    for (key_range, node) in ks.iter() {
+        if key_range.start() < 1 << 63 {
+            assert_eq!(node.label(0), "eu");
+        }
+        *replicas.entry(node.id().clone()).or_insert(0) += 1;
+    }
+    assert_eq!(replicas.len(), 8);
+    for node in ks.iter().map(|(_, node)| node) {
+        let count = replicas[node.id()];
+        if node.label(0) == "eu" {
+            assert_eq!(count, max_replicas);
+        } else {
+            assert!(count < max_replicas);
+        }
+    }
+
+    // Existing strategies keep working, as contextual strategies ignoring
+    // the context.
+    fn assert_contextual<N: KeyspaceNode, R: ContextualReplicationStrategy<N>>(_: &R) {}
+    assert_contextual::<LabelledNode, _>(&DefaultReplicationStrategy::new());
+}
+
+#[test]
+fn feasibility_validation() {
+    // Strictly one replica per zone, no fallback, while there are only two
+    // zones.
+    let strategy = MaxPerGroup::new(|node: &LabelledNode| node.label(0).to_string(), 1);
+    let init_nodes = [
+        LabelledNode::new("node0", &["zone1"]),
+        LabelledNode::new("node1", &["zone1"]),
+        LabelledNode::new("node2", &["zone1"]),
+        LabelledNode::new("node3", &["zone2"]),
+        LabelledNode::new("node4", &["zone2"]),
+    ];
+    let builder =
+        KeyspaceBuilder::new(init_nodes.clone()).with_replication_strategy(strategy.clone());
+
+    let Err(KeyspaceError::InfeasiblePlacement(report)) = builder.validate() else {
+        panic!("Placement should be infeasible");
+    };
+    assert_eq!(report.unfilled().len(), u16::MAX as usize + 1);
+    assert_eq!(report.min_nodes_to_add(), 1);
+    for shard in report.unfilled() {
+        // Both zones are used, and the rest of the nodes are rejected.
+        assert_eq!(shard.selected().len(), 2);
+        assert_eq!(shard.rejected().len(), 1);
+        assert_eq!(shard.rejected()[0].len(), 3);
+        assert!(
+            shard.rejected()[0]
+                .iter()
+                .all(|id| !shard.selected().contains(id))
+        );
+        // Ids are reported as they are, and can be used to look nodes up.
+        let zones = shard
+            .selected()
+            .iter()
+            .map(|id| {
+                let id = id.downcast_ref::<String>().expect("Node id is a string");
+                let node = init_nodes.iter().find(|node| node.id() == id).unwrap();
+                node.label(0).to_string()
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(zones.len(), 2);
+        assert!(shard.selected()[0].downcast_ref::<u64>().is_none());
+    }
+    let shard = &report.unfilled()[0];
+    assert_eq!(shard.key_range(), KeyRange::new(0, Some(1 << 48)));
+    assert!(
+        KeyspaceError::InfeasiblePlacement(report.clone())
+            .to_string()
+            .contains("65536 shards cannot be filled, at least 1 more nodes needed (lower bound)")
+    );
+
+    // Spares in the existing zones do not help, any node in a new zone does.
+    let spares = [
+        LabelledNode::new("node5", &["zone1"]),
+        LabelledNode::new("node6", &["zone2"]),
+        LabelledNode::new("node7", &["zone3"]),
+        LabelledNode::new("node8", &["zone4"]),
+    ];
+    assert_eq!(
+        builder.validate_with_spares(spares.clone()),
+        Ok(vec!["node7".to_string()])
+    );
+    assert!(matches!(
+        builder.validate_with_spares(spares[..2].to_vec()),
+        Err(KeyspaceError::InfeasiblePlacement(_))
+    ));
+
+    // Not enough nodes are reported the same way.
+    let builder = KeyspaceBuilder::new(init_nodes[..2].to_vec());
+    let Err(KeyspaceError::InfeasiblePlacement(report)) = builder.validate() else {
+        panic!("Placement should be infeasible");
+    };
+    assert_eq!(report.min_nodes_to_add(), 1);
+    assert_eq!(
+        builder.validate_with_spares(spares.clone()),
+        Ok(vec!["node5".to_string()])
+    );
+
+    // Built keyspace is feasible as is.
+    let ks = KeyspaceBuilder::new(init_nodes.into_iter().chain([spares[2].clone()]))
+        .with_replication_strategy(strategy)
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.validate(), Ok(()));
+    assert_eq!(ks.validate_with_spares(spares), Ok(vec![]));
+}
+
+#[test]
+fn runtime_replication_factor() {
+    let init_nodes = (0..10)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    let ids = init_nodes
+        .iter()
+        .map(|node| node.id().clone())
+        .collect::<Vec<_>>();
+    let keys = (0..1000).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+
+    // Runtime replication factor places replicas the same way the compile
+    // time one does.
+    let static_ks = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_runtime_replication_factor(3)
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(static_ks.replication_factor(), 3);
+    assert_eq!(ks.replication_factor(), 3);
+    for key in &keys {
+        assert_eq!(
+            ks.replicas(key).collect::<Vec<_>>(),
+            static_ks.replicas(key).collect::<Vec<_>>()
+        );
+    }
+
+    // Growing the replication factor pulls data to new replicas only.
+    let old_replicas = keys
+        .iter()
+        .map(|key| ks.replicas(key).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let plan = ks.set_replication_factor(5).expect("Failed to grow");
+    assert_eq!(ks.replication_factor(), 5);
+    assert_eq!(ks.version(), 1);
+    let pulls = ids
+        .iter()
+        .map(|id| plan.pull_intervals(id).count())
+        .sum::<usize>();
+    let cleanups = ids
+        .iter()
+        .map(|id| plan.cleanup_intervals(id).count())
+        .sum::<usize>();
+    assert_eq!(pulls, (u16::MAX as usize + 1) * 2);
+    assert_eq!(cleanups, 0);
+    for (key, old_replicas) in keys.iter().zip(&old_replicas) {
+        let replicas = ks.replicas(key).collect::<Vec<_>>();
+        assert_eq!(replicas.len(), 5);
+        assert_eq!(&replicas[..3], &old_replicas[..]);
+    }
+
+    // Shrinking it cleans up the dropped replicas.
+    let plan = ks.set_replication_factor(2).expect("Failed to shrink");
+    let pulls = ids
+        .iter()
+        .map(|id| plan.pull_intervals(id).count())
+        .sum::<usize>();
+    let cleanups = ids
+        .iter()
+        .map(|id| plan.cleanup_intervals(id).count())
+        .sum::<usize>();
+    assert_eq!(pulls, 0);
+    assert_eq!(cleanups, (u16::MAX as usize + 1) * 3);
+    for (key, old_replicas) in keys.iter().zip(&old_replicas) {
+        assert_eq!(ks.replicas(key).collect::<Vec<_>>(), old_replicas[..2]);
+    }
+
+    // Batches are grouped by replica sets of the runtime size.
+    let batch = ks.route_batch(&keys, BatchGrouping::ReplicaSet);
+    for group in batch.iter() {
+        assert_eq!(group.nodes().len(), 2);
+        for &key_idx in group.keys() {
+            let replicas = ks.replicas(&keys[key_idx]).collect::<Vec<_>>();
+            assert_eq!(group.nodes(), &replicas[..]);
+        }
+    }
+
+    // Infeasible replication factor leaves the keyspace intact.
+    assert_eq!(
+        ks.set_replication_factor(11).err(),
+        Some(KeyspaceError::NotEnoughNodes(11))
+    );
+    assert_eq!(
+        ks.set_replication_factor(0).err(),
+        Some(KeyspaceError::InvalidReplicationFactor(0))
+    );
+    assert_eq!(ks.replication_factor(), 2);
+    assert_eq!(ks.version(), 2);
+
+    assert!(matches!(
+        KeyspaceBuilder::new(init_nodes.clone())
+            .with_runtime_replication_factor(0)
+            .build(),
+        Err(KeyspaceError::InvalidReplicationFactor(0))
+    ));
+
+    // Range partitioned keyspace supports runtime replication factor as well.
+    let range_ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_strategy(DefaultReplicationStrategy::new())
+        .with_runtime_replication_factor(4)
+        .build_range_partitioned(IntegerEncoder)
+        .expect("Failed to create keyspace");
+    assert_eq!(range_ks.replication_factor(), 4);
+    assert_eq!(range_ks.replicas(&42u64).count(), 4);
+
+    // The type of keyspace with runtime replication factor.
+    let _: &keyspace::Keyspace<Node, DefaultReplicationStrategy, DYNAMIC_RF> = &ks;
+}
+
+#[test]
+fn replication_strategy_swap() {
+    let init_nodes = zoned_nodes(9, 3);
+    let keys = (0..1000).collect::<Vec<u64>>();
+    let zones = |replicas: &[NodeRef<LabelledNode>]| {
+        replicas
+            .iter()
+            .map(|node| node.topology[0].clone())
+            .collect::<HashSet<_>>()
+            .len()
+    };
+
+    // Any node to zone-aware placement.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    let (zone_aware, plan) = ks
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .expect("Failed to swap strategy");
+    assert_eq!(ks.version(), 0);
+    assert_eq!(zone_aware.version(), 1);
+    assert_eq!(plan.version(), 1);
+    assert!(!plan.is_empty());
+    for key in &keys {
+        let old_replicas = ks.replicas(key).collect::<Vec<_>>();
+        let new_replicas = zone_aware.replicas(key).collect::<Vec<_>>();
+        assert_eq!(zones(&new_replicas), 3);
+
+        // New replicas pull the key from the old ones.
+        let pos = ks.position_of(key);
+        for node in new_replicas.iter().filter(|n| !old_replicas.contains(n)) {
+            let interval = plan
+                .pull_intervals(node.id())
+                .find(|interval| interval.key_range().contains(pos))
+                .expect("Key must be pulled");
+            assert_eq!(interval.nodes(), &old_replicas[..]);
+        }
+        for node in old_replicas.iter().filter(|n| !new_replicas.contains(n)) {
+            assert!(
+                plan.cleanup_intervals(node.id())
+                    .any(|key_range| key_range.contains(pos))
+            );
+        }
+    }
+
+    // Strategy of the same type is swapped in place.
+    let filter = |allowed: fn(&LabelledNode) -> bool| Filter::new(allowed);
+    let mut ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_strategy(filter(|_| true))
+        .build()
+        .expect("Failed to create keyspace");
+    let plan = ks
+        .set_replication_strategy(filter(|node| node.topology[0] != "zone0"))
+        .expect("Failed to swap strategy");
+    assert_eq!(ks.version(), 1);
+    assert!(plan.pull_intervals(&"node0".to_string()).next().is_none());
+    assert!(
+        plan.cleanup_intervals(&"node0".to_string())
+            .next()
+            .is_some()
+    );
+    for key in &keys {
+        assert!(ks.replicas(key).all(|node| node.topology[0] != "zone0"));
+    }
+
+    // Infeasible strategy leaves the keyspace intact.
+    let replicas = keys
+        .iter()
+        .map(|key| ks.replicas(key).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ks.set_replication_strategy(filter(|node| node.id == "node1"))
+            .err(),
+        Some(KeyspaceError::IncompleteReplicaSet)
+    );
+    assert_eq!(ks.version(), 1);
+    for (key, replicas) in keys.iter().zip(replicas) {
+        assert_eq!(ks.replicas(key).collect::<Vec<_>>(), replicas);
+    }
+    assert!(matches!(
+        ks.with_replication_strategy(filter(|node| node.id == "node1")),
+        Err(KeyspaceError::IncompleteReplicaSet)
+    ));
+}
+
+#[test]
+fn range_replication_factor() {
+    let init_nodes = (0..10)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    let ids = init_nodes
+        .iter()
+        .map(|node| node.id().clone())
+        .collect::<Vec<_>>();
+    let mut ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_strategy(Filter::new((|_| true) as fn(&Node) -> bool))
+        .with_runtime_replication_factor(3)
+        .build()
+        .expect("Failed to create keyspace");
+    let count = |plan: &keyspace::MigrationPlan<Node>| {
+        let pulls = ids
+            .iter()
+            .map(|id| plan.pull_intervals(id).count())
+            .sum::<usize>();
+        let cleanups = ids
+            .iter()
+            .map(|id| plan.cleanup_intervals(id).count())
+            .sum::<usize>();
+        (pulls, cleanups)
+    };
+
+    // Metadata in the first 16 shards, cache in the last 256 ones.
+    let shard_width = 1u64 << 48;
+    let metadata = KeyRange::new(0, Some(16 * shard_width));
+    let cache = KeyRange::new((u16::MAX as u64 - 255) * shard_width, None);
+
+    let plan = ks
+        .set_range_replication(metadata, 5, None)
+        .expect("Failed to set metadata replication");
+    assert_eq!(count(&plan), (16 * 2, 0));
+    let plan = ks
+        .set_range_replication(cache, 2, None)
+        .expect("Failed to set cache replication");
+    assert_eq!(count(&plan), (0, 256));
+    assert_eq!(ks.range_replication().collect::<Vec<_>>(), vec![
+        (metadata, 5),
+        (cache, 2)
+    ]);
+
+    for (pos, replication_factor) in [
+        (0, 5),
+        (16 * shard_width - 1, 5),
+        (16 * shard_width, 3),
+        (cache.start() - 1, 3),
+        (cache.start(), 2),
+        (u64::MAX, 2),
+    ] {
+        assert_eq!(ks.replication_factor_of(pos), replication_factor);
+        assert_eq!(ks.replicas_for_position(pos).count(), replication_factor);
+    }
+    assert_eq!(ks.replication_factor(), 3);
+
+    // Keyspace-wide replication factor does not affect the ranges.
+    let plan = ks.set_replication_factor(4).expect("Failed to grow");
+    assert_eq!(count(&plan), (u16::MAX as usize + 1 - 16 - 256, 0));
+    assert_eq!(ks.replication_factor_of(0), 5);
+    assert_eq!(ks.replication_factor_of(u64::MAX), 2);
+
+    // Sub-shards follow the range they start in.
+    ks.split_shard(0, 2).expect("Failed to split");
+    assert_eq!(ks.replication_factor_of(0), 5);
+    assert_eq!(ks.replicas_for_position(shard_width - 1).count(), 5);
+
+    // Range with its own strategy.
+    let plan = ks
+        .set_range_replication(cache, 2, Some(Filter::new(|node: &Node| node.0 != "node0")))
+        .expect("Failed to set cache strategy");
+    assert!(plan.pull_intervals(&"node0".to_string()).next().is_none());
+    let batch = ks.route_batch(
+        &(0..10_000u64).collect::<Vec<_>>(),
+        BatchGrouping::ReplicaSet,
+    );
+    for group in batch.iter() {
+        let pos = ks.position_of(&(group.keys()[0] as u64));
+        assert_eq!(group.nodes().len(), ks.replication_factor_of(pos));
+        if cache.contains(pos) {
+            assert!(group.nodes().iter().all(|node| node.0 != "node0"));
+        }
+    }
+
+    // Infeasible ranges leave the keyspace intact.
+    let version = ks.version();
+    assert_eq!(
+        ks.set_range_replication(metadata, 11, None).err(),
+        Some(KeyspaceError::NotEnoughNodes(11))
+    );
+    assert_eq!(
+        ks.set_range_replication(metadata, 0, None).err(),
+        Some(KeyspaceError::InvalidReplicationFactor(0))
+    );
+    assert_eq!(ks.version(), version);
+    assert_eq!(ks.replication_factor_of(0), 5);
+
+    // Cleared range falls back to the keyspace replication factor.
+    let plan = ks
+        .clear_range_replication(metadata)
+        .expect("Failed to clear");
+    assert_eq!(count(&plan), (0, 15 + 4));
+    assert_eq!(ks.replication_factor_of(0), 4);
+}
+
+#[test]
+fn keyspace_registry() {
+    let init_nodes = (0..5)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    let mut registry = KeyspaceRegistry::new(init_nodes.clone());
+    let replicas = |registry: &KeyspaceRegistry<Node>, table: &str, pos: u64| {
+        registry
+            .table(table)
+            .expect("Table not found")
+            .replicas_for_position(pos)
+            .map(|node| node.id().clone())
+            .collect::<Vec<_>>()
+    };
+
+    registry
+        .create_table(
+            "users",
+            TableConfig::new(DefaultReplicationStrategy::new(), 3),
+        )
+        .expect("Failed to create users");
+    registry
+        .create_table(
+            "orders",
+            TableConfig::new(DefaultReplicationStrategy::new(), 3).co_partitioned_with("users"),
+        )
+        .expect("Failed to create orders");
+    registry
+        .create_table(
+            "events",
+            TableConfig::new(DefaultReplicationStrategy::new(), 2).with_salt(42),
+        )
+        .expect("Failed to create events");
+    registry
+        .create_table(
+            "audit",
+            TableConfig::new(DefaultReplicationStrategy::new(), 5),
+        )
+        .expect("Failed to create audit");
+    assert_eq!(
+        registry.tables().map(|(name, _)| name).collect::<Vec<_>>(),
+        vec!["audit", "events", "orders", "users"]
+    );
+
+    // Errors.
+    assert!(matches!(
+        registry.create_table("users", TableConfig::new(DefaultReplicationStrategy::new(), 3)),
+        Err(KeyspaceError::TableExists(name)) if name == "users"
+    ));
+    assert!(matches!(
+        registry.create_table(
+            "items",
+            TableConfig::new(DefaultReplicationStrategy::new(), 3).co_partitioned_with("missing")
+        ),
+        Err(KeyspaceError::TableNotFound(name)) if name == "missing"
+    ));
+    assert!(matches!(
+        registry.set_replication_factor("missing", 2),
+        Err(KeyspaceError::TableNotFound(_))
+    ));
+
+    // Unsalted table is placed as a standalone keyspace, co-partitioned tables
+    // are placed the same way, salted ones are not.
+    let standalone = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    let positions = (0..256u64).map(|i| i << 56).collect::<Vec<_>>();
+    let mut salted_differs = false;
+    for &pos in &positions {
+        let users = replicas(&registry, "users", pos);
+        let expected = standalone
+            .replicas_for_position(pos)
+            .map(|node| node.id().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(users, expected);
+        assert_eq!(replicas(&registry, "orders", pos), users);
+        salted_differs |= replicas(&registry, "events", pos)[..] != users[..2];
+    }
+    assert!(salted_differs);
+
+    // Membership change produces a plan per table.
+    let plans = registry
+        .add_node(Node::new("node5"))
+        .expect("Failed to add node");
+    assert_eq!(plans.keys().collect::<Vec<_>>(), vec![
+        "audit", "events", "orders", "users"
+    ]);
+    for (name, plan) in &plans {
+        assert!(plan.pull_intervals(&"node5".to_string()).count() > 0);
+        assert_eq!(plan.version(), 1, "{name}");
+    }
+    for (_, table) in registry.tables() {
+        assert_eq!(table.version(), 1);
+    }
+    for &pos in &positions {
+        assert_eq!(
+            replicas(&registry, "orders", pos),
+            replicas(&registry, "users", pos)
+        );
+    }
+
+    // Removing two nodes leaves too few for `audit`: nothing changes.
+    registry
+        .remove_node(&"node0".to_string())
+        .expect("Failed to remove node");
+    let before = positions
+        .iter()
+        .map(|&pos| replicas(&registry, "users", pos))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        registry.remove_node(&"node1".to_string()),
+        Err(KeyspaceError::NotEnoughNodes(5))
+    ));
+    for (_, table) in registry.tables() {
+        assert_eq!(table.version(), 2);
+    }
+    let after = positions
+        .iter()
+        .map(|&pos| replicas(&registry, "users", pos))
+        .collect::<Vec<_>>();
+    assert_eq!(before, after);
+    assert!(after.iter().flatten().any(|id| id == "node1"));
+
+    // Once `audit` is dropped, the node can be removed.
+    assert!(registry.drop_table("audit"));
+    assert!(!registry.drop_table("audit"));
+    let plans = registry
+        .remove_node(&"node1".to_string())
+        .expect("Failed to remove node");
+    assert_eq!(plans.len(), 3);
+    for &pos in &positions {
+        assert!(!replicas(&registry, "users", pos).contains(&"node1".to_string()));
+    }
+
+    // Per-table updates.
+    registry
+        .set_replication_factor("events", 3)
+        .expect("Failed to change replication factor");
+    assert_eq!(replicas(&registry, "events", 0).len(), 3);
+    assert_eq!(replicas(&registry, "users", 0).len(), 3);
+}
+
+#[test]
+fn erasure_coded_placement() {
+    assert_eq!(
+        ErasureCoding::new(0, 2),
+        Err(KeyspaceError::InvalidErasureCoding(0, 2))
+    );
+
+    // 4 data + 2 parity fragments, at most 2 fragments per zone.
+    let coding = ErasureCoding::new(4, 2).expect("Invalid erasure coding");
+    assert_eq!(coding.fragments(), 6);
+    let init_nodes = zoned_nodes(12, 4);
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(coding.failure_domains(0))
+        .with_erasure_coding(coding)
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.erasure_coding(), Some(coding));
+    assert_eq!(ks.replication_factor(), 6);
+
+    let positions = (0..256u64).map(|i| i << 56).collect::<Vec<_>>();
+    let fragments = |ks: &keyspace::Keyspace<LabelledNode, _, DYNAMIC_RF>, pos: u64| {
+        ks.fragments_for_position(pos)
+            .map(|(fragment, node)| (fragment, node.id().clone()))
+            .collect::<Vec<_>>()
+    };
+    for &pos in &positions {
+        let nodes = ks.replicas_for_position(pos).collect::<Vec<_>>();
+        assert_eq!(nodes.len(), 6);
+        assert_eq!(nodes.iter().collect::<HashSet<_>>().len(), 6);
+        let mut zones = HashMap::new();
+        for node in &nodes {
+            *zones.entry(node.topology[0].clone()).or_insert(0) += 1;
+        }
+        assert!(zones.values().all(|count| *count <= 2), "{zones:?}");
+    }
+
+    // Lost fragments are decoded from `k` remaining ones, the rest of the
+    // nodes keep their fragments.
+    let before = positions
+        .iter()
+        .map(|&pos| fragments(&ks, pos))
+        .collect::<Vec<_>>();
+    let plan = ks
+        .remove_node(&"node0".to_string())
+        .expect("Failed to remove node");
+    let mut rebuilt = 0;
+    for (pos, before) in positions.iter().zip(&before) {
+        let after = fragments(&ks, *pos);
+        for ((fragment, old), (_, new)) in before.iter().zip(&after) {
+            if old != "node0" {
+                assert_eq!(old, new);
+                continue;
+            }
+
+            let reconstruction = plan
+                .reconstructions(new)
+                .find(|r| r.key_range().contains(*pos))
+                .expect("No reconstruction found");
+            assert_eq!(reconstruction.fragment(), *fragment);
+            assert!(reconstruction.is_recoverable());
+            assert!(!reconstruction.is_copy());
+            let sources = reconstruction.sources();
+            assert_eq!(sources.len(), 4);
+            assert!(sources.windows(2).all(|w| w[0].0 < w[1].0));
+            for (source_fragment, source) in sources {
+                assert_ne!(source.id(), "node0");
+                assert_eq!(&before[*source_fragment].1, source.id());
+            }
+            rebuilt += 1;
+        }
+    }
+    assert!(rebuilt > 0);
+    assert!(plan.cleanup_intervals(&"node0".to_string()).count() > 0);
+    assert!(
+        init_nodes
+            .iter()
+            .all(|node| plan.pull_intervals(node.id()).count() == 0)
+    );
+
+    // Fragments taken over from the nodes that are still around are copied.
+    let plan = ks
+        .add_node(LabelledNode::new("node12", &["zone0", "node12"]))
+        .expect("Failed to add node");
+    let reconstructions = plan
+        .reconstructions(&"node12".to_string())
+        .collect::<Vec<_>>();
+    assert!(!reconstructions.is_empty());
+    for reconstruction in reconstructions {
+        assert!(reconstruction.is_copy());
+        let (fragment, source) = &reconstruction.sources()[0];
+        assert_eq!(*fragment, reconstruction.fragment());
+        assert!(
+            plan.cleanup_intervals(source.id())
+                .any(|key_range| *key_range == reconstruction.key_range())
+        );
+    }
+
+    // Replication factor is fixed to the number of fragments.
+    assert_eq!(
+        ks.set_replication_factor(3).err(),
+        Some(KeyspaceError::InvalidReplicationFactor(3))
+    );
+    assert!(ks.set_replication_factor(6).is_ok());
+
+    // Without parity, a lost fragment cannot be rebuilt.
+    let mut ks = KeyspaceBuilder::new((0..3).map(|i| Node::new(&format!("node{}", i))))
+        .with_erasure_coding(ErasureCoding::new(2, 0).expect("Invalid erasure coding"))
+        .build()
+        .expect("Failed to create keyspace");
+    let plan = ks
+        .remove_node(&"node0".to_string())
+        .expect("Failed to remove node");
+    let ids = ["node1".to_string(), "node2".to_string()];
+    let lost = ids
+        .iter()
+        .flat_map(|id| plan.reconstructions(id))
+        .collect::<Vec<_>>();
+    assert!(!lost.is_empty());
+    assert!(lost.iter().all(|r| !r.is_recoverable()));
+}
+
+#[test]
+fn replica_roles() {
+    let init_nodes = (0..8)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ReplicaRoles::new(0, 1, 1),
+        Err(KeyspaceError::InvalidReplicaRoles(0, 1, 1))
+    );
+
+    // 2 voters, 1 learner, 1 witness.
+    let roles = ReplicaRoles::new(2, 1, 1).expect("Invalid replica roles");
+    assert_eq!(roles.total(), 4);
+    assert!(matches!(
+        KeyspaceBuilder::new(init_nodes.clone())
+            .with_replica_roles(roles)
+            .build(),
+        Err(KeyspaceError::InvalidReplicationFactor(3))
+    ));
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_runtime_replication_factor(4)
+        .with_replica_roles(roles)
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(ks.replica_roles(), Some(roles));
+
+    use ReplicaRole::*;
+    let with_roles = |ks: &keyspace::Keyspace<Node, _, DYNAMIC_RF>, pos: u64| {
+        ks.replicas_with_roles_for_position(pos)
+            .map(|(node, role)| (node.id().clone(), role))
+            .collect::<HashMap<_, _>>()
+    };
+    for key in 0..100u64 {
+        let replicas = ks.replicas_with_roles(&key).collect::<Vec<_>>();
+        assert_eq!(
+            replicas.iter().map(|(_, role)| *role).collect::<Vec<_>>(),
+            vec![Voter, Voter, Learner, Witness]
+        );
+        assert_eq!(
+            replicas
+                .iter()
+                .map(|(node, _)| node.clone())
+                .collect::<Vec<_>>(),
+            ks.replicas(&key).collect::<Vec<_>>()
+        );
+    }
+    assert!(Witness.votes() && !Witness.stores_data());
+    assert!(!Learner.votes() && Learner.stores_data());
+
+    // Witnesses are reported, but do not store the data.
+    let interval = ks
+        .replicas_for_range(KeyRange::new(0, Some(1)))
+        .next()
+        .expect("No interval found");
+    assert_eq!(interval.roles(), &[Voter, Voter, Learner, Witness]);
+    assert_eq!(
+        interval.data_nodes().collect::<Vec<_>>(),
+        interval.nodes()[..3].iter().collect::<Vec<_>>()
+    );
+
+    // Nodes pull the data when they start storing it, from the nodes that
+    // stored it, and drop it when they stop storing it.
+    let positions = (0..256u64).map(|i| i << 56).collect::<Vec<_>>();
+    let before = positions
+        .iter()
+        .map(|&pos| with_roles(&ks, pos))
+        .collect::<Vec<_>>();
+    let plan = ks.add_node(Node::new("node8")).expect("Failed to add node");
+    let stores_data = |role: Option<&ReplicaRole>| role.is_some_and(|role| role.stores_data());
+    let mut changes = 0;
+    for (&pos, before) in positions.iter().zip(&before) {
+        let after = with_roles(&ks, pos);
+        let ids = before.keys().chain(after.keys()).collect::<HashSet<_>>();
+        for id in ids {
+            let (from, to) = (before.get(id), after.get(id));
+            let change = plan
+                .role_changes(id)
+                .find(|change| change.key_range().contains(pos));
+            // Primaries may change among the voters, with no data movement.
+            if from == to {
+                assert!(change.is_none_or(|change| change.was_primary() != change.is_primary()));
+                continue;
+            }
+            changes += 1;
+            let change = change.expect("No role change found");
+            assert_eq!((change.from().as_ref(), change.to().as_ref()), (from, to));
+
+            let pull = plan
+                .pull_intervals(id)
+                .find(|interval| interval.key_range().contains(pos));
+            let cleanup = plan
+                .cleanup_intervals(id)
+                .any(|key_range| key_range.contains(pos));
+            match (stores_data(from), stores_data(to)) {
+                (false, true) => {
+                    let pull = pull.expect("No pull interval found");
+                    assert!(pull.roles().iter().all(|role| role.stores_data()));
+                    assert_eq!(pull.nodes().len(), 3);
+                    for node in pull.nodes() {
+                        assert!(stores_data(before.get(node.id())));
+                    }
+                    assert!(!cleanup);
+                }
+                (true, false) => {
+                    assert!(pull.is_none());
+                    assert!(cleanup);
+                }
+                _ => {
+                    assert!(pull.is_none());
+                    assert!(!cleanup);
+                }
+            }
+        }
+    }
+    assert!(changes > 0);
+
+    // Replication factor is fixed to the number of replicas.
+    assert_eq!(
+        ks.set_replication_factor(3).err(),
+        Some(KeyspaceError::InvalidReplicationFactor(3))
+    );
+
+    // Range partitioned keyspace.
+    let ks = KeyspaceBuilder::new(init_nodes)
+        .with_replication_factor::<4>()
+        .with_replica_roles(roles)
+        .build_range_partitioned(IntegerEncoder)
+        .expect("Failed to create keyspace");
+    assert_eq!(
+        ks.replicas_with_roles_for_position(0)
+            .map(|(_, role)| role)
+            .collect::<Vec<_>>(),
+        vec![Voter, Voter, Learner, Witness]
+    );
+}
+
+#[test]
+fn primary_balancing() {
+    let init_nodes = (0..10)
+        .map(|i| LabelledNode::new(&format!("node{i}"), &[]).with_capacity(1 + i % 4))
+        .collect::<Vec<_>>();
+    let unbalanced = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_primary_balancing()
+        .build()
+        .expect("Failed to create keyspace");
+    assert!(ks.primary_imbalance() < unbalanced.primary_imbalance());
+    assert!(ks.primary_imbalance() < 1.01, "{}", ks.primary_imbalance());
+
+    // Members of replica sets are the same.
+    let positions = (0..1024u64).map(|i| i << 54).collect::<Vec<_>>();
+    for &pos in &positions {
+        assert_eq!(
+            ks.replicas_for_position(pos).collect::<HashSet<_>>(),
+            unbalanced
+                .replicas_for_position(pos)
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    // Reassigned primaries are role changes, not data movement.
+    let plan = ks
+        .add_node(LabelledNode::new("node10", &[]).with_capacity(2))
+        .expect("Failed to add node");
+    assert!(ks.primary_imbalance() < 1.01, "{}", ks.primary_imbalance());
+    let mut reassigned = 0;
+    for node in &init_nodes {
+        for change in plan.role_changes(node.id()) {
+            if change.from() != change.to() {
+                continue;
+            }
+            assert_ne!(change.was_primary(), change.is_primary());
+            assert!(
+                !plan
+                    .pull_intervals(node.id())
+                    .any(|interval| *interval.key_range() == change.key_range())
+            );
+            assert!(
+                !plan
+                    .cleanup_intervals(node.id())
+                    .any(|key_range| *key_range == change.key_range())
+            );
+            reassigned += 1;
+        }
+    }
+    assert!(reassigned > 0);
+
+    // A membership change only reassigns primaries of the replica sets it
+    // changes.
+    let replica_sets = |ks: &keyspace::Keyspace<LabelledNode>| {
+        let mut key_ranges = ks
+            .iter()
+            .map(|(key_range, _)| key_range)
+            .collect::<Vec<_>>();
+        key_ranges.dedup();
+        key_ranges
+            .into_iter()
+            .map(|key_range| {
+                let replicas = ks
+                    .replicas_for_position(key_range.start())
+                    .collect::<Vec<_>>();
+                (key_range, replicas)
+            })
+            .collect::<Vec<_>>()
+    };
+    let check_local = |before: &[(KeyRange, Vec<NodeRef<LabelledNode>>)],
+                       ks: &keyspace::Keyspace<LabelledNode>| {
+        let mut unchanged = 0;
+        for (key_range, replicas) in before {
+            let after = ks
+                .replicas_for_position(key_range.start())
+                .collect::<Vec<_>>();
+            if after.iter().collect::<HashSet<_>>() == replicas.iter().collect::<HashSet<_>>() {
+                assert_eq!(after[0], replicas[0], "{key_range:?}");
+                unchanged += 1;
+            }
+        }
+        assert!(unchanged > 0);
+    };
+    let before = replica_sets(&ks);
+    ks.add_node(LabelledNode::new("node11", &[]).with_capacity(3))
+        .expect("Failed to add node");
+    check_local(&before, &ks);
+    let before = replica_sets(&ks);
+    ks.remove_node(&"node3".to_string())
+        .expect("Failed to remove node");
+    check_local(&before, &ks);
+    assert!(ks.primary_imbalance() < 1.05, "{}", ks.primary_imbalance());
+
+    // Nodes with no capacity never become primaries.
+    let mut ks = KeyspaceBuilder::new(
+        init_nodes
+            .iter()
+            .take(3)
+            .cloned()
+            .chain([LabelledNode::new("idle", &[]).with_capacity(0)]),
+    )
+    .with_primary_balancing()
+    .build()
+    .expect("Failed to create keyspace");
+    ks.remove_node(&"node0".to_string())
+        .expect("Failed to remove node");
+    assert!(ks.iter_node(&"idle".to_string()).count() > 0);
+    assert!(ks.primary_imbalance().is_finite());
+    for (key_range, replicas) in replica_sets(&ks) {
+        assert_ne!(replicas[0].id(), "idle", "{key_range:?}");
+    }
+
+    // With replica roles, only voters become primaries.
+    let roles = ReplicaRoles::new(2, 0, 1).expect("Invalid replica roles");
+    let unbalanced = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replica_roles(roles)
+        .build()
+        .expect("Failed to create keyspace");
+    let ks = KeyspaceBuilder::new(init_nodes)
+        .with_replica_roles(roles)
+        .with_primary_balancing()
+        .build()
+        .expect("Failed to create keyspace");
+    assert!(ks.primary_imbalance() < unbalanced.primary_imbalance());
+    for &pos in &positions {
+        assert_eq!(
+            ks.replicas_for_position(pos).nth(2),
+            unbalanced.replicas_for_position(pos).nth(2)
+        );
+    }
+}
+
+#[test]
+fn hinted_handoff() {
+    let init_nodes = (0..5)
+        .map(|i| Node::new(&format!("node{}", i)))
+        .collect::<Vec<_>>();
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .build()
+        .expect("Failed to create keyspace");
+    let without_node0 = KeyspaceBuilder::new(init_nodes[1..].to_vec())
+        .build()
+        .expect("Failed to create keyspace");
+    let node0 = "node0".to_string();
+    let positions = (0..1024u64).map(|i| i << 54).collect::<Vec<_>>();
+
+    assert!(!ks.mark_down(&"node9".to_string()));
+    assert!(ks.mark_down(&node0));
+    assert!(!ks.mark_down(&node0));
+    assert!(ks.is_down(&node0));
+    assert_eq!(ks.version(), 0);
+
+    // Placement is intact, while available replicas skip the node, in favour
+    // of the next HRW candidates.
+    for &pos in &positions {
+        let replicas = ks.replicas_for_position(pos).collect::<Vec<_>>();
+        let available = ks.available_replicas_for_position(pos).collect::<Vec<_>>();
+        assert_eq!(replicas.len(), 3);
+        assert_eq!(available.len(), 3);
+        for ((node, substituted), replica) in available.iter().zip(&replicas) {
+            if replica.id() == &node0 {
+                assert_eq!(substituted.as_ref(), Some(replica));
+                assert!(!replicas.contains(node));
+            } else {
+                assert_eq!(node, replica);
+                assert!(substituted.is_none());
+            }
+        }
+        assert_eq!(
+            available
+                .into_iter()
+                .map(|(node, _)| node)
+                .collect::<HashSet<_>>(),
+            without_node0
+                .replicas_for_position(pos)
+                .collect::<HashSet<_>>()
+        );
+    }
+
+    // With another node down, neither of them is routed to.
+    let node1 = "node1".to_string();
+    assert!(ks.mark_down(&node1));
+    for &pos in &positions {
+        let available = ks
+            .available_replicas_for_position(pos)
+            .map(|(node, _)| node.id().clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(available.len(), 3);
+        assert!(!available.contains(&node0) && !available.contains(&node1));
+    }
+    assert_eq!(
+        ks.mark_up(&node1).map(|hints| hints.is_empty()),
+        Some(false)
+    );
+
+    // Hints cover the shards of the node, held by their substitutes.
+    let hints = ks.hints(&node0).expect("Node is down");
+    assert_eq!(hints.len(), ks.iter_node(&node0).count());
+    for interval in &hints {
+        let pos = interval.key_range().start();
+        assert!(
+            ks.replicas_for_position(pos)
+                .any(|node| node.id() == &node0)
+        );
+        assert!(!interval.nodes().is_empty());
+        for node in interval.nodes() {
+            assert!(
+                !ks.replicas_for_position(pos)
+                    .any(|replica| replica == *node)
+            );
+        }
+    }
+
+    // Once the node is up, the hints are returned for replay.
+    assert_eq!(ks.mark_up(&node0), Some(hints));
+    assert_eq!(ks.mark_up(&node0), None);
+    assert!(!ks.is_down(&node0));
+    assert_eq!(ks.version(), 0);
+    for &pos in &positions {
+        assert!(
+            ks.available_replicas_for_position(pos)
+                .map(|(node, _)| node)
+                .eq(ks.replicas_for_position(pos))
+        );
+    }
+
+    // Removed nodes are no longer down.
+    assert!(ks.mark_down(&node0));
+    ks.remove_node(&node0).expect("Failed to remove node");
+    assert!(!ks.is_down(&node0));
+    assert_eq!(ks.hints(&node0), None);
+
+    // Registry marks the node down in all the tables, including new ones.
+    let mut registry = KeyspaceRegistry::<Node>::new(init_nodes);
+    registry
+        .create_table(
+            "users",
+            TableConfig::new(DefaultReplicationStrategy::new(), 3),
+        )
+        .expect("Failed to create table");
+    assert!(registry.mark_down(&node0));
+    registry
+        .create_table(
+            "orders",
+            TableConfig::new(DefaultReplicationStrategy::new(), 2).with_salt(1),
+        )
+        .expect("Failed to create table");
+    for (_, table) in registry.tables() {
+        assert!(table.is_down(&node0));
+        assert!(
+            !table
+                .available_replicas(&"key")
+                .any(|(node, _)| node.id() == &node0)
+        );
+    }
+    let hints = registry.mark_up(&node0).expect("Node is down");
+    assert_eq!(hints.keys().collect::<Vec<_>>(), ["orders", "users"]);
+    assert!(hints.values().all(|hints| !hints.is_empty()));
+}
+
+#[test]
+fn locality_aware_reads() {
+    let init_nodes = zoned_nodes(9, 3);
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    let positions = (0..1024u64).map(|i| i << 54).collect::<Vec<_>>();
+
+    // Replica in the caller's zone is read first, the rest are the same.
+    let caller = Locality::new(["zone1", "client0"]);
+    for &pos in &positions {
+        let reads = ks
+            .read_replicas_for_position(pos, &caller)
+            .collect::<Vec<_>>();
+        assert_eq!(reads[0].topology[0], "zone1");
+        assert_eq!(
+            reads.iter().cloned().collect::<HashSet<_>>(),
+            ks.replicas_for_position(pos).collect::<HashSet<_>>()
+        );
+    }
+
+    // Ties are broken deterministically, but differently by the callers.
+    let first = |caller: &Locality, pos| {
+        ks.read_replicas_for_position(pos, caller)
+            .next()
+            .expect("No replicas")
+    };
+    let callers = (0..3)
+        .map(|i| Locality::new(["zone9"]).with_caller(&i))
+        .collect::<Vec<_>>();
+    for &pos in &positions {
+        assert_eq!(first(&callers[0], pos), first(&callers[0], pos));
+    }
+    assert!(
+        positions
+            .iter()
+            .any(|&pos| first(&callers[0], pos) != first(&callers[1], pos))
+    );
+
+    // Reads of equally near replicas are spread over them.
+    let mut ranks = [0; 3];
+    for caller in &callers {
+        for &pos in &positions {
+            let node = first(caller, pos);
+            ranks[ks
+                .replicas_for_position(pos)
+                .position(|n| n == node)
+                .unwrap()] += 1;
+        }
+    }
+    for count in ranks {
+        assert!(count > positions.len() * 3 / 4, "{:?}", ranks);
+    }
+
+    // Distance can be any function, e.g. of measured latencies.
+    let latency = |node: &LabelledNode| if node.id == "node4" { 1 } else { 10 };
+    for &pos in &positions {
+        let reads = ks.read_replicas(&pos, &latency).collect::<Vec<_>>();
+        if ks.replicas(&pos).any(|node| node.id == "node4") {
+            assert_eq!(reads[0].id, "node4");
+        }
+    }
+
+    // Witnesses store no data, and are not read from.
+    let ks = KeyspaceBuilder::new(init_nodes)
+        .with_replica_roles(ReplicaRoles::new(2, 0, 1).expect("Invalid replica roles"))
+        .build()
+        .expect("Failed to create keyspace");
+    for &pos in &positions {
+        let witness = ks.replicas_for_position(pos).nth(2).expect("No witness");
+        let reads = ks
+            .read_replicas_for_position(pos, &caller)
+            .collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        assert!(!reads.contains(&witness));
+    }
+}
+
+#[test]
+fn consistency_levels() {
+    let init_nodes = zoned_nodes(9, 3);
+    let mut ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replication_strategy(FailureDomainReplicationStrategy::new())
+        .build()
+        .expect("Failed to create keyspace");
+    let replicas = ks.replicas(&"key").collect::<Vec<_>>();
+    let ids = replicas
+        .iter()
+        .map(|node| node.id().clone())
+        .collect::<Vec<_>>();
+    let outsider = init_nodes
+        .iter()
+        .find(|node| !ids.contains(node.id()))
+        .expect("No node outside of the replica set")
+        .id()
+        .clone();
+
+    // Quorum contacts the minimal subset of the replicas, in order.
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.required(), 2);
+    assert_eq!(tracker.contact(), &replicas[..2]);
+    assert_eq!(tracker.spares(), &replicas[2..]);
+    assert!(!tracker.record_response(&outsider));
+    assert!(!tracker.record_response(&ids[0]));
+    assert!(!tracker.record_response(&ids[0]));
+    assert!(tracker.record_failure(&ids[1]));
+    assert!(!tracker.record_response(&ids[1]));
+    assert!(tracker.record_response(&ids[2]));
+    assert_eq!(tracker.responses(), 2);
+
+    // Levels requiring all or one of the replicas.
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::All)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.contact().len(), 3);
+    assert!(!tracker.record_failure(&ids[2]));
+    assert!(tracker.is_failed());
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::One)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.contact(), &replicas[..1]);
+    assert!(tracker.record_response(&ids[1]));
+
+    // Local quorum only counts the replicas in the domain.
+    let local = replicas[1].topology.clone();
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::local_quorum(local))
+        .expect("Not enough replicas");
+    assert_eq!(tracker.contact(), &replicas[1..2]);
+    assert!(tracker.spares().is_empty());
+    assert!(!tracker.record_response(&ids[0]));
+    assert!(tracker.record_response(&ids[1]));
+    assert_eq!(
+        ks.consistency(&"key", ConsistencyLevel::local_quorum(["zone9"]))
+            .err(),
+        Some(KeyspaceError::InsufficientReplicas(1, 0))
+    );
+
+    // Writes to replicas that are down go to their substitutes, whose
+    // responses count for them.
+    ks.mark_down(&ids[0]);
+    let substitute = ks
+        .available_replicas(&"key")
+        .find_map(|(node, substituted)| substituted.map(|_| node))
+        .expect("No substitute");
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.contact(), &[
+        substitute.clone(),
+        replicas[1].clone()
+    ]);
+    assert!(!tracker.record_response(&ids[0]));
+    assert!(!tracker.record_response(substitute.id()));
+    assert!(tracker.record_response(&ids[1]));
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::All)
+        .expect("Not enough replicas");
+    assert!(!tracker.record_response(substitute.id()));
+    assert!(!tracker.record_response(&ids[1]));
+    assert!(tracker.record_response(&ids[2]));
+
+    // Substitutes keep no data to read, so replicas that are down are
+    // skipped, but still count towards majority.
+    let tracker = ks
+        .read_consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.contact(), &replicas[1..]);
+    assert!(tracker.spares().is_empty());
+    ks.mark_down(&ids[1]);
+    assert_eq!(
+        ks.read_consistency(&"key", ConsistencyLevel::Quorum).err(),
+        Some(KeyspaceError::InsufficientReplicas(2, 1))
+    );
+    assert!(ks.read_consistency(&"key", ConsistencyLevel::One).is_ok());
+    assert!(ks.consistency(&"key", ConsistencyLevel::Quorum).is_ok());
+
+    // Learners do not vote.
+    let ks = KeyspaceBuilder::new(init_nodes.clone())
+        .with_replica_roles(ReplicaRoles::new(2, 1, 0).expect("Invalid replica roles"))
+        .build()
+        .expect("Failed to create keyspace");
+    let replicas = ks.replicas(&"key").collect::<Vec<_>>();
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.required(), 2);
+    assert_eq!(tracker.contact(), &replicas[..2]);
+    assert!(!tracker.record_response(replicas[2].id()));
+    assert!(!tracker.record_response(replicas[0].id()));
+    assert!(tracker.record_response(replicas[1].id()));
+
+    // Learners serve reads at one, but not at quorum.
+    let mut tracker = ks
+        .read_consistency(&"key", ConsistencyLevel::One)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.spares(), &replicas[1..]);
+    assert!(tracker.record_response(replicas[2].id()));
+    let mut tracker = ks
+        .read_consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.required(), 2);
+    assert!(!tracker.record_response(replicas[2].id()));
+    assert_eq!(tracker.responses(), 0);
+
+    // Witnesses vote on writes, but store no data to read.
+    let ks = KeyspaceBuilder::new(init_nodes)
+        .with_replica_roles(ReplicaRoles::new(2, 0, 1).expect("Invalid replica roles"))
+        .build()
+        .expect("Failed to create keyspace");
+    let replicas = ks.replicas(&"key").collect::<Vec<_>>();
+    let mut tracker = ks
+        .consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.spares(), &replicas[2..]);
+    assert!(!tracker.record_response(replicas[2].id()));
+    assert!(tracker.record_response(replicas[0].id()));
+    for level in [
+        ConsistencyLevel::One,
+        ConsistencyLevel::Quorum,
+        ConsistencyLevel::All,
+    ] {
+        let mut tracker = ks
+            .read_consistency(&"key", level)
+            .expect("Not enough replicas");
+        assert!(
+            tracker
+                .contact()
+                .iter()
+                .chain(tracker.spares())
+                .all(|node| node != &replicas[2])
+        );
+        assert!(!tracker.record_response(replicas[2].id()));
+        assert_eq!(tracker.responses(), 0);
+    }
+    let mut tracker = ks
+        .read_consistency(&"key", ConsistencyLevel::Quorum)
+        .expect("Not enough replicas");
+    assert_eq!(tracker.required(), 2);
+    assert!(!tracker.record_response(replicas[2].id()));
+    assert!(!tracker.record_response(replicas[0].id()));
+    assert!(tracker.record_response(replicas[1].id()));
+}
+
+#[test]
+fn churn_simulation() {
+    let simulate = |seed| {
+        let nodes = (0..9).map(|id| SimNode::new(id, 1).with_zone(id as usize % 3));
+        let keyspace = KeyspaceBuilder::new(nodes)
+            .with_replication_strategy(FailureDomainReplicationStrategy::new())
+            .build()
+            .expect("Failed to create keyspace");
+        let mut simulation = Simulation::new(keyspace)
+            .with_keys(5_000)
+            .with_capacities(1, 3)
+            .with_zones(3)
+            .with_node_limits(6, 12)
+            .with_seed(seed);
+        let reports = simulation.run(6).expect("Failed to simulate");
+        let nodes = simulation.keyspace().iter().map(|(_, node)| *node.id());
+        (reports, nodes.collect::<HashSet<_>>())
+    };
+
+    // Simulations are reproducible.
+    let (reports, nodes) = simulate(1);
+    assert_eq!(simulate(1), (reports.clone(), nodes.clone()));
+    assert_ne!(simulate(2).0, reports);
+
+    let mut expected = (0..9).collect::<HashSet<u64>>();
+    for report in &reports {
+        match report.event() {
+            ChurnEvent::Added(id, _) => assert!(expected.insert(id)),
+            ChurnEvent::Removed(id, _) => assert!(expected.remove(&id)),
+            ChurnEvent::CapacityChanged(id, from, to) => {
+                assert!(expected.contains(&id));
+                assert_ne!(from, to);
+            }
+        }
+        assert!(report.minimal() > 0.0);
+        let ratio = report.movement_ratio().expect("Nothing had to move");
+        assert!(ratio < 2.0, "{:?}", report);
+        assert!(report.fairness() < 1.0, "{:?}", report);
+        assert_eq!(report.degraded_shards(), 0);
+        assert_eq!(report.invariant_violation(), None);
+        assert!(report.plan_size() > 0);
+    }
+    assert_eq!(nodes, expected);
+
+    // Nodes with no capacity (here, in every replica set) do not skew
+    // fairness.
+    let nodes = (0..3).map(|id| SimNode::new(id, usize::from(id > 0)));
+    let keyspace = KeyspaceBuilder::new(nodes)
+        .build()
+        .expect("Failed to create keyspace");
+    let mut simulation = Simulation::new(keyspace)
+        .with_keys(1_000)
+        .with_capacities(1, 2)
+        .with_node_limits(3, 3)
+        .with_seed(1);
+    let report = simulation.step().expect("Failed to simulate");
+    assert!(matches!(
+        report.event(),
+        ChurnEvent::CapacityChanged(1 | 2, _, _)
+    ));
+    assert!(report.fairness() < 1.0, "{:?}", report);
+}
+
+#[test]
+fn failed_node_update_leaves_keyspace_intact() {
+    let mut keyspace = KeyspaceBuilder::new(["node1", "node2", "node3"])
+        .build()
+        .expect("Failed to create keyspace");
+    let intervals = keyspace.iter().collect::<Vec<_>>();
+
+    // Not enough nodes would be left to fill the replica sets.
+    assert_eq!(
+        keyspace.remove_node(&"node3").map(|_| ()),
+        Err(KeyspaceError::NotEnoughNodes(3))
+    );
+    assert_eq!(keyspace.version(), 0);
+    assert!(keyspace.iter().eq(intervals));
+
+    // The node is kept, and takes part in the following updates.
+    keyspace.add_node("node4").expect("Failed to add node");
+    assert!(keyspace.iter().any(|(_, node)| node.id() == &"node3"));
+    keyspace
+        .remove_node(&"node3")
+        .expect("Failed to remove node");
+    assert_eq!(keyspace.version(), 2);
+    assert!(keyspace.iter().all(|(_, node)| node.id() != &"node3"));
+}
+
+/// Operation changing the keyspace.
+#[derive(Debug, Clone)]
+enum Operation {
+    /// Adds a node (or updates an existing one) with the given capacity and
+    /// zone.
+    Add(u64, usize, usize),
+    Remove(u64),
+    Split(u64, u8),
+    Merge(u64),
+    MarkDown(u64),
+    /// Changes the replication factor, fails unless it is set at runtime.
+    SetReplicationFactor(usize),
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+        4 => (0..12u64, 1..4usize, 0..3usize)
+            .prop_map(|(id, capacity, zone)| Operation::Add(id, capacity, zone)),
+        3 => (0..12u64).prop_map(Operation::Remove),
+        1 => (any::<u64>(), 1..4u8).prop_map(|(pos, bits)| Operation::Split(pos, bits)),
+        1 => any::<u64>().prop_map(Operation::Merge),
+        1 => (0..12u64).prop_map(Operation::MarkDown),
+        1 => (1..5usize).prop_map(Operation::SetReplicationFactor),
+    ]
+}
+
+/// Kind of the keyspace the operations are applied to.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Replicated,
+    Roles,
+    RuntimeFactor,
+    ErasureCoded,
+    Balanced,
+    Counting,
+}
+
+fn kind() -> impl Strategy<Value = Kind> {
+    prop_oneof![
+        Just(Kind::Replicated),
+        Just(Kind::Roles),
+        Just(Kind::RuntimeFactor),
+        Just(Kind::ErasureCoded),
+        Just(Kind::Balanced),
+        Just(Kind::Counting),
+    ]
+}
+
+/// Strategy capping the number of replica sets (and primaries) of a node,
+/// relaxed to no cap.
+#[derive(Clone)]
+struct Capped {
+    replicas: usize,
+    primaries: usize,
+}
+
+impl ContextualReplicationStrategy<SimNode> for Capped {
+    fn is_eligible(&mut self, node: &SimNode, ctx: &ReplicaContext<'_, SimNode>) -> bool {
+        ctx.counts().replicas(node.id()) < self.replicas
+            && (ctx.rank() > 0 || ctx.counts().primaries(node.id()) < self.primaries)
+    }
+
+    fn relaxed(&self) -> Option<Self> {
+        (self.replicas < usize::MAX).then_some(Self {
+            replicas: usize::MAX,
+            primaries: usize::MAX,
+        })
+    }
+}
+
+/// Applies the operations, checking the invariants of the keyspace and the
+/// migration plans after each of them.
+fn check_operations<R, const RF: usize>(
+    mut keyspace: keyspace::Keyspace<SimNode, R, RF>,
+    operations: Vec<Operation>,
+    set_replication_factor: impl Fn(
+        &mut keyspace::Keyspace<SimNode, R, RF>,
+        usize,
+    ) -> KeyspaceResult<MigrationPlan<SimNode>>,
+) -> Result<(), TestCaseError>
+where
+    R: ContextualReplicationStrategy<SimNode>,
+{
+    prop_assert_eq!(keyspace.check_invariants(), Ok(()));
+
+    for operation in operations {
+        let previous = keyspace.iter().collect::<Vec<_>>();
+        let version = keyspace.version();
+        let plan = match operation {
+            Operation::Add(id, capacity, zone) => {
+                keyspace.add_node(SimNode::new(id, capacity).with_zone(zone))
+            }
+            Operation::Remove(id) => keyspace.remove_node(&id),
+            Operation::Split(pos, bits) => keyspace.split_shard(pos, bits),
+            Operation::Merge(pos) => keyspace.merge_shard(pos),
+            Operation::MarkDown(id) => {
+                keyspace.mark_down(&id);
+                continue;
+            }
+            Operation::SetReplicationFactor(replication_factor) => {
+                set_replication_factor(&mut keyspace, replication_factor)
+            }
+        };
+
+        // Failed operations leave the keyspace intact.
+        prop_assert_eq!(keyspace.check_invariants(), Ok(()), "{:?}", operation);
+        match plan {
+            Ok(plan) => prop_assert_eq!(keyspace.check_migration(previous, &plan), Ok(())),
+            Err(_) => {
+                prop_assert_eq!(keyspace.version(), version);
+                prop_assert!(keyspace.iter().eq(previous));
+            }
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn invariants_hold(kind in kind(), operations in prop::collection::vec(operation(), 1..12)) {
+        let nodes = (0..4).map(|id| SimNode::new(id, 1).with_zone(id as usize % 3));
+        let builder = KeyspaceBuilder::new(nodes.clone())
+            .with_replication_strategy(FailureDomainReplicationStrategy::new());
+        let fixed = |_: &mut keyspace::Keyspace<SimNode, _, 3>, replication_factor| {
+            Err(KeyspaceError::InvalidReplicationFactor(replication_factor))
+        };
+        let runtime = |keyspace: &mut keyspace::Keyspace<SimNode, _, DYNAMIC_RF>,
+                       replication_factor| {
+            keyspace.set_replication_factor(replication_factor)
+        };
+        match kind {
+            Kind::Replicated => {
+                let keyspace = builder.build().expect("Failed to create keyspace");
+                check_operations(keyspace, operations, fixed)?;
+            }
+            Kind::Roles => {
+                let roles = ReplicaRoles::new(2, 0, 1).expect("Invalid replica roles");
+                let keyspace = builder
+                    .with_replica_roles(roles)
+                    .build()
+                    .expect("Failed to create keyspace");
+                check_operations(keyspace, operations, fixed)?;
+            }
+            Kind::RuntimeFactor => {
+                let keyspace = builder
+                    .with_runtime_replication_factor(3)
+                    .build()
+                    .expect("Failed to create keyspace");
+                check_operations(keyspace, operations, runtime)?;
+            }
+            Kind::ErasureCoded => {
+                let coding = ErasureCoding::new(2, 1).expect("Invalid erasure coding");
+                let keyspace = KeyspaceBuilder::new(nodes)
+                    .with_erasure_coding(coding)
+                    .with_replication_strategy(coding.failure_domains(0))
+                    .build()
+                    .expect("Failed to create keyspace");
+                check_operations(keyspace, operations, runtime)?;
+            }
+            Kind::Balanced => {
+                let keyspace = builder
+                    .with_primary_balancing()
+                    .build()
+                    .expect("Failed to create keyspace");
+                check_operations(keyspace, operations, fixed)?;
+            }
+            Kind::Counting => {
+                // Caps bind before the shards are all placed.
+                let strategy = Capped {
+                    replicas: 40_000,
+                    primaries: 14_000,
+                };
+                let keyspace = KeyspaceBuilder::new(nodes)
+                    .with_replication_strategy(strategy)
+                    .with_primary_balancing()
+                    .build()
+                    .expect("Failed to create keyspace");
+                check_operations(keyspace, operations, |_, replication_factor| {
+                    Err(KeyspaceError::InvalidReplicationFactor(replication_factor))
+                })?;
+            }
+        }
+    }
+}
+
+#[test]
+fn invariant_violations() {
+    let mut keyspace = KeyspaceBuilder::new(["node1", "node2", "node3", "node4"])
+        .build()
+        .expect("Failed to create keyspace");
+    assert_eq!(keyspace.check_invariants(), Ok(()));
+
+    let previous = keyspace.iter().collect::<Vec<_>>();
+    let plan = keyspace.add_node("node5").expect("Failed to add node");
+    assert_eq!(keyspace.check_migration(previous.clone(), &plan), Ok(()));
+
+    // Plan of a different change does not lead to the current layout.
+    let stale = keyspace
+        .remove_node(&"node1")
+        .expect("Failed to remove node");
+    assert!(matches!(
+        keyspace.check_migration(previous.clone(), &stale),
+        Err(KeyspaceError::InvariantViolation(_))
+    ));
+
+    // Plan of an earlier version.
+    assert!(matches!(
+        keyspace.check_migration(previous.clone(), &plan),
+        Err(KeyspaceError::InvariantViolation(_))
+    ));
+
+    // Previous layout with a gap.
+    let previous = keyspace.iter().skip(3).collect::<Vec<_>>();
+    let plan = keyspace.add_node("node6").expect("Failed to add node");
+    assert!(matches!(
+        keyspace.check_migration(previous, &plan),
+        Err(KeyspaceError::InvariantViolation(_))
+    ));
+}